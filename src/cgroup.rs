@@ -0,0 +1,459 @@
+// Support for running each job in its own cgroup (v2 only). Processes can
+// leave the job's process group by calling setsid (QEMU and dev servers love
+// doing this) but they can't leave its cgroup, so this lets us find and kill
+// everything the job started.
+//
+// We don't need any special privileges for this, just a delegated subtree,
+// which is what systemd gives you for your user session anyway. If we can't
+// create cgroups, jobs just fall back to being tracked by process group.
+
+use std::{
+    fs::{self, File},
+    io,
+    os::{fd::AsRawFd as _, unix::process::CommandExt as _},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use log::{debug, info, warn};
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        resource::{getrlimit, setrlimit, Resource},
+        signal::{kill, Signal},
+    },
+    unistd::Pid,
+};
+
+use crate::util::ResultExt as _;
+
+// For naming cgroups, so that multiple roots in the same process (which only
+// really happens in tests) don't collide.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// How long to wait for a killed cgroup to empty out before giving up on
+// removing it.
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Controllers that Limits needs.
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+
+// The kernel's default period for cpu.max.
+const CPU_MAX_PERIOD_US: u64 = 100_000;
+
+// Caps on what a job can use, from the test config. They're enforced via the
+// job's cgroup if possible. Otherwise we use rlimits and niceness where there's
+// something close enough.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    // In bytes.
+    pub memory_max: Option<u64>,
+    // Same scale as cpu.weight, i.e. 1 to 10000 with 100 as the default.
+    pub cpu_weight: Option<u64>,
+    // In CPUs, e.g. 1.5 means 150ms of CPU time every 100ms.
+    pub cpu_max: Option<f64>,
+    pub pids_max: Option<u64>,
+}
+
+// cpu_max is checked for NaN when parsing the config.
+impl Eq for Limits {}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    // Niceness that roughly matches a CPU weight. Each nice level is worth
+    // about 25% CPU time. Without privileges we can only make the job nicer
+    // than us, so weights above the default don't do anything.
+    fn nice(cpu_weight: u64) -> i32 {
+        let nice = -(cpu_weight as f64 / 100.0).ln() / 1.25_f64.ln();
+        nice.round().clamp(0.0, 19.0) as i32
+    }
+
+    // Set up the command so that the process gets rlimits (and niceness)
+    // approximating these limits before it execs. These are per-process,
+    // except that RLIMIT_NPROC counts all processes belonging to the user, so
+    // they're not as good as a cgroup.
+    pub fn add_rlimits(&self, cmd: &mut process::Command) {
+        let memory_max = self.memory_max;
+        let pids_max = self.pids_max;
+        let nice = self.cpu_weight.map(Self::nice);
+        // SAFETY: This runs between fork and exec, where only async-signal-safe
+        // stuff is allowed. The nix wrappers here are just syscalls.
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(max) = memory_max {
+                    lower_rlimit(Resource::RLIMIT_AS, max)?;
+                }
+                if let Some(max) = pids_max {
+                    lower_rlimit(Resource::RLIMIT_NPROC, max)?;
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// Set the soft limit, unless the hard limit is already lower.
+fn lower_rlimit(resource: Resource, value: u64) -> io::Result<()> {
+    let (_, hard) = getrlimit(resource)?;
+    Ok(setrlimit(resource, value.min(hard), hard)?)
+}
+
+// Find the directory for the cgroup this process is in.
+fn own_cgroup_dir() -> anyhow::Result<PathBuf> {
+    let cgroup = fs::read_to_string("/proc/self/cgroup").context("reading /proc/self/cgroup")?;
+    // In the v2 hierarchy the line looks like "0::/user.slice/foo.scope".
+    let path = cgroup
+        .lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("not in a cgroup v2 hierarchy"))?;
+    let mountinfo =
+        fs::read_to_string("/proc/self/mountinfo").context("reading /proc/self/mountinfo")?;
+    for line in mountinfo.lines() {
+        // The filesystem type comes after a separator, because the number of
+        // fields before it varies.
+        let Some((mount, fs_info)) = line.split_once(" - ") else {
+            continue;
+        };
+        if fs_info.split(' ').next() != Some("cgroup2") {
+            continue;
+        }
+        let fields: Vec<&str> = mount.split(' ').collect();
+        let (Some(root), Some(mount_point)) = (fields.get(3), fields.get(4)) else {
+            continue;
+        };
+        if let Ok(relpath) = Path::new(path).strip_prefix(root) {
+            return Ok(Path::new(mount_point).join(relpath));
+        }
+    }
+    bail!("cgroup2 filesystem not mounted")
+}
+
+// A subtree of the cgroup hierarchy that belongs to this Limmat process. Jobs
+// get cgroups underneath it. It's removed when this is dropped.
+#[derive(Debug)]
+pub struct CgroupRoot {
+    dir: PathBuf,
+}
+
+// Remove an empty cgroup and any empty children. Errors are ignored, since
+// that just means something's still running in there.
+fn remove_empty(dir: &Path) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                let _ = fs::remove_dir(entry.path());
+            }
+        }
+    }
+    let _ = fs::remove_dir(dir);
+}
+
+impl CgroupRoot {
+    pub fn create() -> anyhow::Result<Self> {
+        let parent = own_cgroup_dir()?;
+        // If a previous Limmat got killed it couldn't clean up after itself.
+        for entry in fs::read_dir(&parent)
+            .with_context(|| format!("reading {parent:?}"))?
+            .flatten()
+        {
+            let name = entry.file_name();
+            let Some(pid) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix("limmat-"))
+                .and_then(|n| n.split('-').next())
+            else {
+                continue;
+            };
+            if !Path::new("/proc").join(pid).exists() {
+                debug!("Removing stale cgroup {:?}", entry.path());
+                remove_empty(&entry.path());
+            }
+        }
+        let dir = parent.join(format!(
+            "limmat-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&dir).with_context(|| format!("creating cgroup at {dir:?}"))?;
+        debug!("Created cgroup subtree at {dir:?}");
+        // Let the jobs use the controllers for limits, if we're allowed to.
+        // Nothing runs directly in here so this doesn't fall foul of the "no
+        // internal processes" rule.
+        let available = fs::read_to_string(dir.join("cgroup.controllers")).unwrap_or_default();
+        let enable: Vec<String> = CONTROLLERS
+            .iter()
+            .filter(|c| available.split_whitespace().any(|a| a == **c))
+            .map(|c| format!("+{c}"))
+            .collect();
+        if !enable.is_empty() {
+            if let Err(err) = fs::write(dir.join("cgroup.subtree_control"), enable.join(" ")) {
+                debug!("Couldn't enable cgroup controllers {enable:?}: {err}");
+            }
+        }
+        Ok(Self { dir })
+    }
+
+    // Whether job cgroups get the given controller.
+    pub fn has_controller(&self, controller: &str) -> bool {
+        fs::read_to_string(self.dir.join("cgroup.subtree_control"))
+            .is_ok_and(|c| c.split_whitespace().any(|c| c == controller))
+    }
+
+    // Like create, but if cgroups aren't available just say so in the logs.
+    pub fn try_create() -> Option<Arc<Self>> {
+        Self::create()
+            .inspect_err(|e| {
+                info!("Not using cgroups, jobs will be tracked by process group ({e:#})")
+            })
+            .ok()
+            .map(Arc::new)
+    }
+
+    pub fn new_job(&self) -> anyhow::Result<JobCgroup> {
+        let dir = self
+            .dir
+            .join(format!("job-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir(&dir).with_context(|| format!("creating cgroup at {dir:?}"))?;
+        // Open this now, the child can't do it after it's forked.
+        let procs = match File::options().write(true).open(dir.join("cgroup.procs")) {
+            Ok(procs) => procs,
+            Err(err) => {
+                fs::remove_dir(&dir).or_log_error("removing unusable cgroup");
+                return Err(err).context("opening cgroup.procs");
+            }
+        };
+        Ok(JobCgroup { dir, procs })
+    }
+}
+
+impl Drop for CgroupRoot {
+    fn drop(&mut self) {
+        // The job cgroups should have already removed themselves, but if one
+        // of them didn't empty out in time it might be possible by now.
+        remove_empty(&self.dir);
+        if self.dir.exists() {
+            warn!("Couldn't remove cgroup {:?}", self.dir);
+        }
+    }
+}
+
+// The cgroup for a single run of a job. When this is dropped, anything that's
+// still in there gets SIGKILLed and the cgroup is removed. That blocks until
+// they're dead, so don't drop it on an async thread.
+#[derive(Debug)]
+pub struct JobCgroup {
+    dir: PathBuf,
+    // cgroup.procs, open for writing.
+    procs: File,
+}
+
+impl JobCgroup {
+    // Set up the command so that the process gets moved into the cgroup
+    // before it execs, so that there's no window where it can fork without its
+    // children ending up in there too. The cgroup must outlive the spawn.
+    pub fn add_command(&self, cmd: &mut process::Command) {
+        let fd = self.procs.as_raw_fd();
+        // SAFETY: This runs between fork and exec, where only async-signal-safe
+        // stuff is allowed. write is.
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing 0 means "the process doing the writing".
+                if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // Apply whatever limits the cgroup has controllers for. Returns the ones it
+    // doesn't.
+    pub fn set_limits(&self, limits: &Limits) -> anyhow::Result<Limits> {
+        let controllers = fs::read_to_string(self.dir.join("cgroup.controllers"))
+            .context("reading cgroup.controllers")?;
+        let has = |controller| controllers.split_whitespace().any(|c| c == controller);
+        let write = |file, value: String| {
+            fs::write(self.dir.join(file), value).with_context(|| format!("writing {file}"))
+        };
+        let mut unapplied = Limits::default();
+        if let Some(max) = limits.memory_max {
+            if has("memory") {
+                write("memory.max", max.to_string())?;
+                // If one process gets OOM-killed the job isn't gonna produce a
+                // meaningful result anyway, so kill the whole thing.
+                write("memory.oom.group", "1".into())?;
+            } else {
+                unapplied.memory_max = Some(max);
+            }
+        }
+        if has("cpu") {
+            if let Some(weight) = limits.cpu_weight {
+                write("cpu.weight", weight.to_string())?;
+            }
+            if let Some(cpus) = limits.cpu_max {
+                let quota = (cpus * CPU_MAX_PERIOD_US as f64).round() as u64;
+                write("cpu.max", format!("{quota} {CPU_MAX_PERIOD_US}"))?;
+            }
+        } else {
+            unapplied.cpu_weight = limits.cpu_weight;
+            unapplied.cpu_max = limits.cpu_max;
+        }
+        if let Some(max) = limits.pids_max {
+            if has("pids") {
+                write("pids.max", max.to_string())?;
+            } else {
+                unapplied.pids_max = Some(max);
+            }
+        }
+        Ok(unapplied)
+    }
+
+    // Whether the kernel OOM-killed anything in here. This counts OOMs for any
+    // reason, not just from the cgroup's own memory.max.
+    pub fn oom_killed(&self) -> anyhow::Result<bool> {
+        let path = self.dir.join("memory.events");
+        let events = match fs::read_to_string(&path) {
+            Ok(events) => events,
+            // No memory controller.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).with_context(|| format!("reading {path:?}")),
+        };
+        Ok(events.lines().any(|l| {
+            l.strip_prefix("oom_kill ")
+                .is_some_and(|n| n.trim().parse::<u64>().is_ok_and(|n| n > 0))
+        }))
+    }
+
+    fn pids(&self) -> anyhow::Result<Vec<Pid>> {
+        let path = self.dir.join("cgroup.procs");
+        fs::read_to_string(&path)
+            .with_context(|| format!("reading {path:?}"))?
+            .lines()
+            .map(|l| {
+                l.parse()
+                    .map(Pid::from_raw)
+                    .with_context(|| format!("bad PID {l:?} in {path:?}"))
+            })
+            .collect()
+    }
+
+    // Send a signal to every process in the cgroup.
+    pub fn signal(&self, signal: Signal) -> anyhow::Result<()> {
+        for pid in self.pids()? {
+            match kill(pid, signal) {
+                // It already exited, that's fine.
+                Ok(()) | Err(Errno::ESRCH) => (),
+                Err(err) => return Err(err).with_context(|| format!("signalling {pid}")),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_populated(&self) -> anyhow::Result<bool> {
+        let path = self.dir.join("cgroup.events");
+        Ok(fs::read_to_string(&path)
+            .with_context(|| format!("reading {path:?}"))?
+            .lines()
+            .any(|l| l == "populated 1"))
+    }
+
+    // SIGKILL everything in the cgroup and wait for it to be empty.
+    pub fn kill(&self) -> anyhow::Result<()> {
+        // cgroup.kill does this atomically, but it only exists since Linux
+        // 5.14. Before that we just have to keep killing stuff until it stops
+        // forking.
+        let have_kill_file = match fs::write(self.dir.join("cgroup.kill"), "1") {
+            Ok(()) => true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err).context("writing cgroup.kill"),
+        };
+        let start = Instant::now();
+        while self.is_populated()? {
+            if start.elapsed() > KILL_TIMEOUT {
+                bail!("cgroup {:?} still populated after SIGKILL", self.dir);
+            }
+            if !have_kill_file {
+                self.signal(Signal::SIGKILL)?;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JobCgroup {
+    fn drop(&mut self) {
+        // The job is over by now, so anything left in here is a stray daemon
+        // or something.
+        if let Err(err) = self.kill() {
+            warn!("Couldn't kill job processes: {err:#}");
+            return;
+        }
+        fs::remove_dir(&self.dir).or_log_error("removing job cgroup");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use super::*;
+
+    #[test_log::test]
+    fn should_kill_escaped_processes() {
+        let root = match CgroupRoot::create() {
+            Ok(root) => root,
+            Err(err) => {
+                // Not much we can do about this, it depends on the environment.
+                warn!("Skipping test, can't create cgroups: {err:#}");
+                return;
+            }
+        };
+        let cgroup = root.new_job().unwrap();
+        let mut cmd = process::Command::new("bash");
+        // The sleep puts itself in a new session, so killpg wouldn't find it.
+        cmd.args(["-c", "setsid sleep 1000 >/dev/null 2>&1 & echo $!"])
+            .stdout(Stdio::piped());
+        cgroup.add_command(&mut cmd);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let sleep_pid = Pid::from_raw(
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(cgroup.pids().unwrap(), vec![sleep_pid]);
+
+        let dir = cgroup.dir.clone();
+        drop(cgroup);
+        assert!(!dir.exists());
+        drop(root);
+    }
+
+    #[test]
+    fn should_approximate_cpu_weight_with_nice() {
+        assert_eq!(Limits::nice(100), 0);
+        assert_eq!(Limits::nice(10000), 0);
+        assert_eq!(Limits::nice(80), 1);
+        assert_eq!(Limits::nice(10), 10);
+        assert_eq!(Limits::nice(1), 19);
+    }
+}
@@ -1,8 +1,10 @@
 use std::{
     borrow::Borrow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsString,
-    hash::{DefaultHasher, Hash as _, Hasher as _},
+    fmt::{self, Display},
+    path::{Component, Path},
+    slice,
     sync::Arc,
     time::Duration,
 };
@@ -10,16 +12,25 @@ use std::{
 use anyhow::{bail, Context as _};
 #[allow(unused_imports)]
 use log::debug;
+use regex::Regex;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    cgroup::Limits,
     dag::{Dag, GraphNode},
+    database::GcPolicy,
+    fingerprint::{fingerprint, Fingerprints},
+    report::{MetricSpec, ReportFile},
     resource::{self, Pools, ResourceKey},
-    test::{self, CachePolicy, TestDag, TestName},
+    sandbox::Sandbox,
+    test::{
+        self, dep_dir_env_var, CachePolicy, CacheTtl, ConfigHash, ExitCodeOutcomes, RetryPolicy,
+        TestDag, TestName,
+    },
 };
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Resource {
@@ -51,7 +62,7 @@ impl Resource {
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Command {
@@ -75,7 +86,21 @@ impl Command {
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+impl Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Shell(cmd) => write!(f, "{cmd}"),
+            Self::Raw(args) => write!(f, "{}", args.join(" ")),
+        }
+    }
+}
+
+// This gets serialized into the config hash. Optional fields shouldn't be
+// serialized when they're unset, otherwise adding a new one would invalidate
+// every cached result. Fields that don't change what the result of the test
+// would be (the TTLs, retries, failure_pattern and the metric thresholds) are
+// never serialized, so that changing them doesn't throw results away either.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Test {
     name: String,
@@ -83,6 +108,7 @@ pub struct Test {
     #[serde(default = "default_requires_worktree")]
     requires_worktree: bool,
     // TODO: This should only refer to resource names.
+    #[serde(skip_serializing_if = "Option::is_none")]
     resources: Option<Vec<Resource>>,
     #[serde(default = "default_shutdown_grace_period")]
     /// When a job is no longer needed it's SIGTERMed. If it doesn't respond (by
@@ -90,9 +116,116 @@ pub struct Test {
     /// the overall shutdown of limmat so do not set this to longer than you are
     /// willing to wait when you terminate this program.
     shutdown_grace_period_s: u64,
+    /// If the job is still running after this many seconds, it's shut down in
+    /// the same way as when it's no longer needed (so shutdown_grace_period_s
+    /// still applies) and reported as having timed out. Timeouts aren't cached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_s: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Memory limit for the job in bytes. If it goes over, it gets killed and
+    /// reported as an OOM error. Without cgroups this becomes a limit on each
+    /// process's address space instead.
+    memory_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Relative share of CPU time for the job when the CPUs are busy, from 1
+    /// to 10000. The default is 100. Without cgroups the job is niced instead
+    /// (so values above 100 don't do anything).
+    cpu_weight: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Maximum number of CPUs the job can keep busy, e.g. 1.5. Only enforced
+    /// when cgroups are available.
+    cpu_max: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Maximum number of processes (and threads) in the job. Without cgroups
+    /// this becomes RLIMIT_NPROC, which counts all of your user's processes.
+    pids_max: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Run the job in a sandbox made of Linux namespaces, so it can't use the
+    /// network or write outside the directory it runs in (depending on the
+    /// settings). Useful for making sure tests cached by_tree really only
+    /// depend on the tree. Needs unprivileged user namespaces.
+    sandbox: Option<Sandbox>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    /// Run the job with a pseudo-terminal as its stdout and stderr, so that
+    /// tools that check for one give the same (e.g. coloured) output as they
+    /// would for you. Both streams end up in the stdout log, and the terminal
+    /// is the size of the one Limmat is running in, if any.
+    tty: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Exit codes that mean the test couldn't be run properly, as opposed to
+    /// meaning the code under test is broken. These are reported as errors and
+    /// are not cached.
+    error_exit_codes: Vec<i32>,
+    /// Exit code that means the test doesn't apply to this commit. This is
+    /// cached and counts as a success for the purposes of depends_on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skip_exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Exit codes that mean the test passed but with something worth
+    /// highlighting. These are cached and count as successes for the purposes
+    /// of depends_on.
+    warning_exit_codes: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Glob patterns for files (or directories) that the job produces and that
+    /// should be kept in the result database once it finishes. Patterns are
+    /// relative to the directory the job runs in and must not contain '..'.
+    artifacts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// A report in a standard format that the job writes, with results for
+    /// individual test cases. These are stored as subtests of the job, and
+    /// failing ones are listed in the web UI.
+    report: Option<ReportFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Numbers the test produces, like benchmark results. They're stored
+    /// with the result and compared against the parent commit. Metrics can
+    /// also be reported via $LIMMAT_RESULT_FILE without being listed here.
+    metrics: Vec<Metric>,
+    // Results that are already cached keep their old excerpt.
+    #[serde(skip_serializing)]
+    /// Regex (or list of them) for the line of output that best explains a
+    /// failure, like a compiler error. When the job fails, the first matching
+    /// line from stderr, or otherwise stdout, is shown next to the status.
+    failure_pattern: Option<FailurePattern>,
     #[serde(default = "default_cache_policy")]
     cache: CachePolicy,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Paths or glob patterns for files outside the repository that the test
+    /// depends on. If their contents change, cached results are invalidated.
+    /// Relative paths are relative to the repository. Directories are hashed
+    /// recursively, without following links inside them.
+    cache_inputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Commands whose output identifies something else the test depends on,
+    /// like `gcc --version`. If the output changes, cached results are
+    /// invalidated. They are run in the repository, and the output is stored
+    /// in the result database so keep it short.
+    cache_key_commands: Vec<Command>,
+    #[serde(skip_serializing)]
+    /// If set, cached results older than this many seconds aren't reused and
+    /// the test runs again. Expired results stay in the database until they
+    /// are overwritten or garbage-collected.
+    cache_ttl_s: Option<u64>,
+    #[serde(skip_serializing)]
+    /// Like cache_ttl_s, but only for successes (including skips and
+    /// warnings). Takes precedence over cache_ttl_s.
+    cache_ttl_success_s: Option<u64>,
+    #[serde(skip_serializing)]
+    /// Like cache_ttl_s, but only for failures. Takes precedence over
+    /// cache_ttl_s.
+    cache_ttl_failure_s: Option<u64>,
+    // schemars ignores the default of a field that's skipped when
+    // serializing, so without the Option it would say these are required.
+    #[serde(default, skip_serializing)]
+    #[schemars(with = "Option<u32>")]
+    /// If the job doesn't succeed, run it again up to this many times. The
+    /// output of every run is kept. If a later run succeeds, the test is
+    /// reported as flaky, which otherwise counts as a success.
+    retries: u32,
+    #[serde(default = "default_retry_on", skip_serializing)]
+    #[schemars(with = "Option<Vec<RetryOn>>")]
+    /// Which outcomes get retried when retries is set. By default, both.
+    retry_on: Vec<RetryOn>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     depends_on: Vec<String>,
 }
 
@@ -100,6 +233,54 @@ fn default_requires_worktree() -> bool {
     true
 }
 
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The test command exited with a code that means failure.
+    Failure,
+    /// Anything reported as an error, including error_exit_codes, and also
+    /// timeouts.
+    Error,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Metric {
+    name: String,
+    /// If set, the metric is read from the job's stdout: this regex is
+    /// matched against each line, and the first capture group of the last
+    /// match is parsed as a number.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    regex: Option<String>,
+    #[serde(skip_serializing)]
+    /// If the metric goes up by more than this percentage compared with the
+    /// parent commit, the result is shown as a regression.
+    max_increase_percent: Option<f64>,
+    #[serde(skip_serializing)]
+    /// Like max_increase_percent, for metrics where bigger is better.
+    max_decrease_percent: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum FailurePattern {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FailurePattern {
+    fn patterns(&self) -> &[String] {
+        match self {
+            Self::One(pattern) => slice::from_ref(pattern),
+            Self::Many(patterns) => patterns,
+        }
+    }
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::Failure, RetryOn::Error]
+}
+
 // This implementation is only valid for Tests among those registered for a single Manager.
 impl GraphNode<String> for Test {
     fn id(&self) -> impl Borrow<String> {
@@ -115,9 +296,11 @@ impl Test {
     // Convert to the "real" object. other_tests is the set of other tests that
     // have already been parsed, which must include all of these test's
     // transitive dependencies (or this will panic).
+    // fingerprint is this test's entry from Config::fingerprints.
     pub fn parse(
         &self,
         other_tests: &Dag<TestName, Arc<test::Test>>,
+        fingerprint: Option<&serde_json::Value>,
     ) -> anyhow::Result<test::Test> {
         let mut seen_resources = HashSet::new();
         for resource in self.resources.as_ref().unwrap_or(&vec![]) {
@@ -138,18 +321,129 @@ impl Test {
             needs_resources.insert(ResourceKey::Worktree, 1);
         }
 
-        // Hash the config, also taking into account the hashes of the
-        // dependency test configs.
-        let mut hasher = DefaultHasher::new();
-        self.hash(&mut hasher);
-        for dep_name in &self.depends_on {
-            other_tests
-                .node(&TestName::new(dep_name))
-                .unwrap()
-                .config_hash
-                .hash(&mut hasher);
+        for pattern in &self.artifacts {
+            glob::Pattern::new(pattern)
+                .with_context(|| format!("invalid artifact pattern {pattern:?}"))?;
+            let path = Path::new(pattern);
+            if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                bail!("artifact pattern {pattern:?} must be relative and must not contain '..'");
+            }
+        }
+
+        if let Some(report) = &self.report {
+            let path = Path::new(&report.path);
+            if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                bail!(
+                    "report path {:?} must be relative and must not contain '..'",
+                    report.path
+                );
+            }
+        }
+
+        let mut seen_metrics = HashSet::new();
+        for metric in &self.metrics {
+            if !seen_metrics.insert(&metric.name) {
+                bail!("duplicate metric {:?}", metric.name);
+            }
+            if let Some(regex) = &metric.regex {
+                Regex::new(regex)
+                    .with_context(|| format!("invalid regex for metric {:?}", metric.name))?;
+            }
+            for threshold in [metric.max_increase_percent, metric.max_decrease_percent]
+                .into_iter()
+                .flatten()
+            {
+                if threshold.is_nan() || threshold < 0.0 {
+                    bail!(
+                        "metric {:?} threshold must be a non-negative number of percent",
+                        metric.name
+                    );
+                }
+            }
+        }
+
+        let failure_patterns = self
+            .failure_pattern
+            .as_ref()
+            .map(|p| p.patterns().to_vec())
+            .unwrap_or_default();
+        for pattern in &failure_patterns {
+            Regex::new(pattern).with_context(|| format!("invalid failure pattern {pattern:?}"))?;
+        }
+
+        if let CachePolicy::ByPaths(paths) = &self.cache {
+            if paths.is_empty() {
+                bail!("by_paths cache policy needs at least one path");
+            }
+            for path in paths {
+                let p = Path::new(path);
+                if path.is_empty()
+                    || p.is_absolute()
+                    || p.components().any(|c| c == Component::ParentDir)
+                {
+                    bail!("cache path {path:?} must be relative to the repository root and must not contain '..'");
+                }
+            }
+        }
+
+        if self.memory_max == Some(0) || self.pids_max == Some(0) {
+            bail!("memory_max and pids_max must be more than 0");
+        }
+        if self.cpu_weight.is_some_and(|w| !(1..=10000).contains(&w)) {
+            bail!("cpu_weight must be between 1 and 10000");
+        }
+        if self
+            .cpu_max
+            .is_some_and(|c| c.is_nan() || c <= 0.0 || c.is_infinite())
+        {
+            bail!("cpu_max must be a positive number of CPUs");
+        }
+        if let Some(sandbox) = &self.sandbox {
+            sandbox.validate()?;
+        }
+        if let Some(report) = &self.report {
+            report.validate()?;
+        }
+
+        if self.retries != 0 && self.retry_on.is_empty() {
+            bail!("retries is set but retry_on is empty");
+        }
+
+        let mut seen_exit_codes = HashSet::new();
+        for &code in self
+            .error_exit_codes
+            .iter()
+            .chain(&self.skip_exit_code)
+            .chain(&self.warning_exit_codes)
+        {
+            if code == 0 {
+                bail!("exit code 0 always means success, it can't be remapped");
+            }
+            if !seen_exit_codes.insert(code) {
+                bail!("exit code {} given more than one meaning", code);
+            }
         }
-        let config_hash = hasher.finish();
+
+        // Describe the effective config, also taking into account the hashes
+        // of the dependency test configs, so that changing a dependency
+        // invalidates its dependents' results too. serde_json sorts object
+        // keys so this doesn't depend on how the TOML was written.
+        let dependency_hashes: BTreeMap<&str, &ConfigHash> = self
+            .depends_on
+            .iter()
+            .map(|dep_name| {
+                let dep = other_tests.node(&TestName::new(dep_name)).unwrap();
+                (dep_name.as_str(), &dep.config_hash)
+            })
+            .collect();
+        let mut config_json = serde_json::json!({
+            "test": self,
+            "dependency_hashes": dependency_hashes,
+        });
+        if let Some(fingerprint) = fingerprint {
+            config_json["fingerprint"] = fingerprint.clone();
+        }
+        let config_hash = ConfigHash::of(&config_json);
 
         Ok(test::Test {
             name: TestName::new(self.name.clone()),
@@ -157,7 +451,50 @@ impl Test {
             args: self.command.args(),
             needs_resources,
             shutdown_grace_period: Duration::from_secs(self.shutdown_grace_period_s),
-            cache_policy: self.cache,
+            timeout: self.timeout_s.map(Duration::from_secs),
+            limits: Limits {
+                memory_max: self.memory_max,
+                cpu_weight: self.cpu_weight,
+                cpu_max: self.cpu_max,
+                pids_max: self.pids_max,
+            },
+            sandbox: self.sandbox.clone(),
+            tty: self.tty,
+            exit_codes: ExitCodeOutcomes {
+                error: self.error_exit_codes.clone(),
+                skip: self.skip_exit_code,
+                warning: self.warning_exit_codes.clone(),
+            },
+            artifacts: self.artifacts.clone(),
+            report_file: self.report.clone(),
+            metrics: self
+                .metrics
+                .iter()
+                .map(|m| MetricSpec {
+                    name: m.name.clone(),
+                    regex: m.regex.clone(),
+                    max_increase: m.max_increase_percent.map(|p| p / 100.0),
+                    max_decrease: m.max_decrease_percent.map(|p| p / 100.0),
+                })
+                .collect(),
+            failure_patterns,
+            cache_policy: self.cache.clone(),
+            cache_ttl: CacheTtl {
+                success: self
+                    .cache_ttl_success_s
+                    .or(self.cache_ttl_s)
+                    .map(Duration::from_secs),
+                failure: self
+                    .cache_ttl_failure_s
+                    .or(self.cache_ttl_s)
+                    .map(Duration::from_secs),
+            },
+            retry: RetryPolicy {
+                max_retries: self.retries,
+                on_failure: self.retry_on.contains(&RetryOn::Failure),
+                on_error: self.retry_on.contains(&RetryOn::Error),
+            },
+            config_json,
             config_hash,
             depends_on: self.depends_on.iter().map(TestName::new).collect(),
         })
@@ -176,12 +513,36 @@ fn default_shutdown_grace_period() -> u64 {
     60
 }
 
-#[derive(Deserialize, JsonSchema, Debug)]
+#[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default = "default_num_worktrees")]
     pub num_worktrees: usize,
     resources: Option<Vec<Resource>>,
+    /// Name used to keep this project's results separate from other
+    /// repositories' in the result database. By default the repository is
+    /// identified by the location of its git directory. Set this if you want
+    /// several clones to share results, or if the repository moves around.
+    project: Option<String>,
+    /// Size limit in bytes for the result database. When garbage collection
+    /// runs (see gc_interval_s, or run `limmat gc`) it deletes the least
+    /// recently used results until the database fits.
+    max_db_size: Option<u64>,
+    /// When garbage collection runs, results that haven't been used for this
+    /// many days get deleted.
+    max_age_days: Option<u64>,
+    /// If set, `limmat watch` runs garbage collection in the background with
+    /// this period. Requires max_db_size or max_age_days.
+    gc_interval_s: Option<u64>,
+    /// The cache_inputs and cache_key_commands of the tests are checked when
+    /// Limmat starts. If this is set, `limmat watch` also checks them again
+    /// with this period, and restarts the tests if they changed.
+    fingerprint_interval_s: Option<u64>,
+    /// If set, Limmat runs a GNU make jobserver with this many job slots and
+    /// passes it to every job via MAKEFLAGS, so that parallel make (and
+    /// cargo) invocations in different jobs share them. Each job can always
+    /// run one thing on top of the shared slots.
+    jobserver_slots: Option<usize>,
     // Default is just here to make testing snippets from the documentation easier.
     #[serde(default)]
     tests: Vec<Test>,
@@ -213,10 +574,29 @@ impl Config {
             .collect()
     }
 
-    fn parse_tests(
+    // Hash the cache_inputs and run the cache_key_commands for each test that
+    // has them. dir is the repository.
+    pub fn fingerprints(&self, dir: &Path) -> anyhow::Result<Fingerprints> {
+        self.tests
+            .iter()
+            .filter(|t| !t.cache_inputs.is_empty() || !t.cache_key_commands.is_empty())
+            .map(|t| {
+                let fp = fingerprint(&t.cache_inputs, &t.cache_key_commands, dir)
+                    .with_context(|| format!("fingerprinting test {:?}", t.name))?;
+                Ok((t.name.clone(), fp))
+            })
+            .collect()
+    }
+
+    pub fn parse_tests(&self, fingerprints: &Fingerprints) -> anyhow::Result<TestDag> {
+        self.parse_tests_with(fingerprints, &self.parse_resource_tokens())
+    }
+
+    fn parse_tests_with(
         &self,
+        fingerprints: &Fingerprints,
         resource_tokens: &ResourceTokens,
-    ) -> anyhow::Result<Dag<TestName, Arc<test::Test>>> {
+    ) -> anyhow::Result<TestDag> {
         let tests = Dag::new(self.tests.clone()).context("parsing test dependency graph")?;
         // This is beginning to be kinda cool, we can map between DAGs of
         // different types of objects.  It's still kinda awkward that users of
@@ -230,7 +610,8 @@ impl Config {
             .try_fold(
                 Dag::empty(),
                 |parsed_dag, test_conf| -> anyhow::Result<Dag<TestName, Arc<test::Test>>> {
-                    let new_node = Arc::new(test_conf.parse(&parsed_dag)?);
+                    let new_node =
+                        Arc::new(test_conf.parse(&parsed_dag, fingerprints.get(&test_conf.name))?);
                     Ok(parsed_dag.with_node(new_node).unwrap())
                 },
             )
@@ -251,6 +632,20 @@ impl Config {
             }
         }
 
+        // Names that only differ in characters that get replaced would end up
+        // sharing a dependency directory variable.
+        let mut env_vars = HashMap::new();
+        for test in tests.nodes() {
+            if let Some(other) = env_vars.insert(dep_dir_env_var(&test.name), &test.name) {
+                bail!(
+                    "tests {:?} and {:?} would both get their dependency directory in ${}",
+                    other,
+                    test.name,
+                    dep_dir_env_var(&test.name)
+                );
+            }
+        }
+
         Ok(tests)
     }
 }
@@ -268,12 +663,20 @@ pub struct ParsedConfig {
     pub num_worktrees: usize,
     pub resource_pools: Arc<Pools>,
     pub tests: TestDag,
+    pub gc_policy: GcPolicy,
+    pub gc_interval: Option<Duration>,
+    pub project: Option<String>,
+    pub fingerprint_interval: Option<Duration>,
+    pub jobserver_slots: Option<usize>,
+    // The original config, so the tests can be re-parsed when their
+    // fingerprints change.
+    pub raw: Arc<Config>,
 }
 
 impl ParsedConfig {
-    pub fn from(config: Config) -> anyhow::Result<Self> {
+    pub fn from(config: Config, fingerprints: &Fingerprints) -> anyhow::Result<Self> {
         let resource_tokens = config.parse_resource_tokens();
-        let tests = config.parse_tests(&resource_tokens)?;
+        let tests = config.parse_tests_with(fingerprints, &resource_tokens)?;
         let resources: HashMap<ResourceKey, Vec<resource::Resource>> = resource_tokens
             .into_iter()
             .map(|(key, tokens)| {
@@ -286,10 +689,47 @@ impl ParsedConfig {
                 )
             })
             .collect();
+        let gc_policy = GcPolicy {
+            max_size: config.max_db_size,
+            max_age: config
+                .max_age_days
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        };
+        if let Some(ref project) = config.project {
+            // It gets used as a directory name.
+            if project.is_empty()
+                || project.starts_with('.')
+                || !project
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            {
+                bail!(
+                    "invalid project name {project:?}, use letters, digits, '-', '_' and '.' \
+                     (and don't start with '.')"
+                );
+            }
+        }
+        if config.gc_interval_s.is_some() && gc_policy.is_unlimited() {
+            bail!("gc_interval_s is set, but neither max_db_size nor max_age_days is");
+        }
+        if config.fingerprint_interval_s.is_some()
+            && config
+                .tests
+                .iter()
+                .all(|t| t.cache_inputs.is_empty() && t.cache_key_commands.is_empty())
+        {
+            bail!("fingerprint_interval_s is set, but no tests have cache_inputs or cache_key_commands");
+        }
         Ok(Self {
             num_worktrees: config.num_worktrees,
             resource_pools: Arc::new(Pools::new(resources)),
             tests,
+            gc_policy,
+            gc_interval: config.gc_interval_s.map(Duration::from_secs),
+            project: config.project.clone(),
+            fingerprint_interval: config.fingerprint_interval_s.map(Duration::from_secs),
+            jobserver_slots: config.jobserver_slots,
+            raw: Arc::new(config),
         })
     }
 }
@@ -342,7 +782,179 @@ mod tests {
             "No TOML found in README - test bug?"
         );
         for toml in toml_blocks {
-            expect_that!(toml::from_str(toml).map(ParsedConfig::from), ok(anything()));
+            expect_that!(
+                toml::from_str(toml).map(|c| ParsedConfig::from(c, &Fingerprints::new())),
+                ok(anything())
+            );
         }
     }
+
+    #[test]
+    fn fingerprint_should_change_config_hash() {
+        let config: Config = toml::from_str(
+            r#"
+                [[tests]]
+                name = "dep"
+                command = "true"
+                cache_key_commands = ["gcc --version"]
+                [[tests]]
+                name = "foo"
+                command = "true"
+                depends_on = ["dep"]
+            "#,
+        )
+        .unwrap();
+        let hashes = |fp: &str| {
+            let fingerprints: Fingerprints = [("dep".to_owned(), serde_json::json!(fp))].into();
+            let tests = config.parse_tests(&fingerprints).unwrap();
+            ["dep", "foo"].map(|name| {
+                tests
+                    .node(&TestName::new(name))
+                    .unwrap()
+                    .config_hash
+                    .clone()
+            })
+        };
+        let [dep1, foo1] = hashes("gcc 12");
+        let [dep2, foo2] = hashes("gcc 13");
+        assert_ne!(dep1, dep2);
+        // The dependent should be invalidated too.
+        assert_ne!(foo1, foo2);
+        assert_eq!(hashes("gcc 12"), [dep1, foo1]);
+    }
+
+    #[test]
+    fn unset_fields_should_not_be_hashed() {
+        let config: Config = toml::from_str(
+            r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+            "#,
+        )
+        .unwrap();
+        let tests = config.parse_tests(&Fingerprints::new()).unwrap();
+        let test = tests.node(&TestName::new("foo")).unwrap();
+        assert_eq!(
+            test.config_json["test"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            [
+                "cache",
+                "command",
+                "name",
+                "requires_worktree",
+                "shutdown_grace_period_s"
+            ]
+        );
+    }
+
+    #[test]
+    fn cache_ttl_should_not_change_config_hash() {
+        let parse = |ttl: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    {ttl}
+                "#
+            ))
+            .unwrap();
+            let tests = config.parse_tests(&Fingerprints::new()).unwrap();
+            tests.node(&TestName::new("foo")).unwrap().clone()
+        };
+        let without = parse("");
+        let with = parse("cache_ttl_s = 60\ncache_ttl_failure_s = 10");
+        assert_eq!(without.config_hash, with.config_hash);
+        assert_eq!(
+            with.cache_ttl,
+            CacheTtl {
+                success: Some(Duration::from_secs(60)),
+                failure: Some(Duration::from_secs(10)),
+            }
+        );
+    }
+
+    #[test]
+    fn should_parse_failure_pattern() {
+        let parse = |pattern: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    {pattern}
+                "#
+            ))
+            .unwrap();
+            config
+                .parse_tests(&Fingerprints::new())
+                .map(|tests| tests.node(&TestName::new("foo")).unwrap().clone())
+        };
+        let without = parse("").unwrap();
+        let one = parse(r#"failure_pattern = "^error""#).unwrap();
+        let many = parse(r#"failure_pattern = ["^error", "FAIL"]"#).unwrap();
+        assert_eq!(without.config_hash, one.config_hash);
+        assert_eq!(one.failure_patterns, vec!["^error".to_owned()]);
+        assert_eq!(
+            many.failure_patterns,
+            vec!["^error".to_owned(), "FAIL".to_owned()]
+        );
+        assert!(parse(r#"failure_pattern = "(""#).is_err());
+    }
+
+    #[test]
+    fn should_parse_sandbox() {
+        let parse = |sandbox: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    {sandbox}
+                "#
+            ))
+            .unwrap();
+            config
+                .parse_tests(&Fingerprints::new())
+                .map(|tests| tests.node(&TestName::new("foo")).unwrap().clone())
+        };
+        let without = parse("").unwrap();
+        let with = parse("sandbox = {}").unwrap();
+        assert_ne!(without.config_hash, with.config_hash);
+        assert_eq!(without.sandbox, None);
+        assert_eq!(
+            with.sandbox,
+            Some(Sandbox {
+                network: false,
+                readonly_paths: Vec::new(),
+                private_tmp: true,
+            })
+        );
+        assert!(parse(r#"sandbox = { readonly_paths = ["usr"] }"#).is_err());
+    }
+
+    #[test]
+    fn should_reject_colliding_dep_dirs() {
+        let config: Config = toml::from_str(
+            r#"
+                [[tests]]
+                name = "a-b"
+                command = "true"
+
+                [[tests]]
+                name = "a_b"
+                command = "true"
+            "#,
+        )
+        .unwrap();
+        let err = config.parse_tests(&Fingerprints::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("LIMMAT_DEP_a_b_DIR"),
+            "unexpected error: {err}"
+        );
+    }
 }
@@ -207,7 +207,7 @@ impl<I: Hash + Eq + Clone + Debug, G: GraphNode<I>> Dag<I, G> {
 
     // Iterate all the descendants of the relevant node, visiting parents before
     // their children.
-    pub fn top_down_from(&self, id: &I) -> Option<TopDown<I, G>> {
+    pub fn top_down_from(&self, id: &I) -> Option<TopDown<'_, I, G>> {
         Some(TopDown {
             dag: self,
             visit_stack: Vec::new(),
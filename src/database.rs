@@ -1,49 +1,367 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
     fs::{self, create_dir_all, remove_dir_all, File},
-    path::{Path, PathBuf},
+    os::unix::{ffi::OsStrExt as _, fs::symlink},
+    path::{Component, Path, PathBuf},
     process::Stdio,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 #[allow(unused_imports)]
-use log::debug;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 
 use crate::{
     git::Hash,
-    test::{ConfigHash, TestCase, TestJobOutput, TestName, TestResult},
+    pty::TerminalSize,
+    report::Report,
+    test::{
+        CacheTtl, ConfigHash, JobDetails, JobUsage, TestCase, TestJobOutput, TestName, TestResult,
+        TestStatus,
+    },
+    util::{hex_string, human_bytes},
 };
 
 // Result database similar to the design described in
 // https://github.com/bjackman/git-brisect?tab=readme-ov-file#the-result-directory
-// TODO: Actually we should probably separate it by the repo lol. But how?
+// Results are separated by repository (see RepoId), each repo gets a directory
+// under repos/ with the entries in <hash>/<test_name>. Old versions didn't have
+// the repos/ level, Database::migrate_legacy moves those entries into place.
+//
+// Each entry keeps the last MAX_ATTEMPTS runs of the test case as numbered
+// attempts, in attempts/<n>, and a "latest" symlink to the most recent one
+// that produced a reusable result (see TestStatus::is_cacheable). That's the
+// one lookups look at. Entries from old versions have the files of
+// a single attempt directly in the entry directory, they get moved into
+// attempts/1 when the test case next runs.
+//
+// Multiple processes can use the database at once, they coordinate via flock:
+// - Each entry has a lock file next to it. Writers hold it exclusively from
+//   before they start clobbering the old entry until the new one is complete.
+//   Readers hold it shared for as long as they have a DatabaseEntry.
+// - There's also a database-wide lock. Writers hold it shared while they claim
+//   an entry, maintenance operations (i.e. gc) hold it exclusively to stop new
+//   entries from being created.
+// Apart from maintenance, all locking is non-blocking, when something is busy
+// the caller decides whether to wait.
 pub struct Database {
     pub base_dir: PathBuf,
+    // Where the results for the current repo live.
+    pub repo_dir: PathBuf,
+}
+
+// Identifies the repository that results belong to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepoId {
+    // Configured by the user.
+    Project(String),
+    // Canonical path of the git common dir, i.e. the thing that's shared by
+    // all worktrees of the repo.
+    GitDir(PathBuf),
+}
+
+impl RepoId {
+    // Name of the directory where this repo's results are stored.
+    fn dir_name(&self) -> String {
+        match self {
+            Self::Project(name) => format!("project-{name}"),
+            Self::GitDir(path) => {
+                let digest = Sha256::digest(path.as_os_str().as_bytes());
+                // Stick the repo's name in there too so humans can tell what's what.
+                let repo_name = path
+                    .parent()
+                    .and_then(|p| p.file_name())
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                format!("git-{repo_name}-{}", hex_string(&digest[..8]))
+            }
+        }
+    }
+}
+
+impl Display for RepoId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Project(name) => write!(f, "project {name:?}"),
+            Self::GitDir(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// Result of Database::explain_lookup.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LookupExplanation {
+    // The test has cache = "no_caching".
+    NoCaching,
+    NoEntry,
+    // Someone is writing the entry right now.
+    Busy,
+    // The test ran but never produced anything that can be reused, e.g. it
+    // timed out. This is the status of the last run that finished.
+    NotCacheable(TestStatus),
+    // There's an entry but it's older than the cache TTL allows. The age is
+    // None if the entry doesn't record when it was stored.
+    Expired {
+        status: TestStatus,
+        age: Option<Duration>,
+        ttl: Duration,
+    },
+    // There's an entry from a different config. These are descriptions of the
+    // differences, empty if the entry doesn't record its config.
+    ConfigChanged(Vec<String>),
+    // The result would be reused.
+    Hit(TestStatus),
+}
+
+impl Display for LookupExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoCaching => write!(f, "Not cached: test has cache = \"no_caching\""),
+            Self::NoEntry => write!(f, "No result stored"),
+            Self::Busy => write!(f, "Result is currently being written by another job"),
+            Self::NotCacheable(status) => {
+                write!(
+                    f,
+                    "Stored result can't be reused, test will re-run: {status}"
+                )
+            }
+            Self::Expired { status, age, ttl } => {
+                write!(f, "Stored result has expired, test will re-run: {status} (")?;
+                match age {
+                    Some(age) => write!(f, "stored {}s ago", age.as_secs())?,
+                    None => write!(f, "it's too old to say when it was stored")?,
+                }
+                write!(f, ", cache TTL is {}s)", ttl.as_secs())
+            }
+            Self::ConfigChanged(diffs) if diffs.is_empty() => write!(
+                f,
+                "Config changed since the result was stored (it's too old to say how)"
+            ),
+            Self::ConfigChanged(diffs) => {
+                write!(f, "Config changed since the result was stored:")?;
+                for diff in diffs {
+                    write!(f, "\n  {diff}")?;
+                }
+                Ok(())
+            }
+            Self::Hit(status) => write!(f, "Stored result will be reused: {status}"),
+        }
+    }
+}
+
+// Describe how new differs from old, with the path of each difference given
+// like "test.command". Arrays are compared as a whole.
+fn json_diff(
+    path: &str,
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    diffs: &mut Vec<String>,
+) {
+    use serde_json::Value;
+
+    if let (Value::Object(old), Value::Object(new)) = (old, new) {
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}.{key}")
+            };
+            match (old.get(key), new.get(key)) {
+                (Some(old), Some(new)) => json_diff(&path, old, new, diffs),
+                (None, Some(new)) => diffs.push(format!("{path}: added ({new})")),
+                (Some(old), None) => diffs.push(format!("{path}: removed (was {old})")),
+                (None, None) => unreachable!(),
+            }
+        }
+    } else if old != new {
+        diffs.push(format!("{path}: {old} -> {new}"));
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+// A repository's section of the database, as reported by Database::list_repos.
+pub struct RepoInfo {
+    pub dir: PathBuf,
+    // None if the repo.json couldn't be read.
+    pub id: Option<RepoId>,
+    pub num_entries: usize,
+    pub size: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 struct TestResultEntry {
     config_hash: ConfigHash,
-    result: TestResult,
+    // Only final statuses get stored here, but not all of them are results
+    // that can be reused, see TestStatus::is_cacheable. Older versions called
+    // this "result" and could only store a TestResult.
+    #[serde(alias = "result", deserialize_with = "deserialize_status")]
+    status: TestStatus,
+    // When the job finished, for checking the cache TTL. None for entries
+    // from versions that didn't record it.
+    #[serde(default)]
+    finished_at: Option<SystemTime>,
+    // None if the command never ran, or for entries from versions that
+    // didn't record it.
+    #[serde(default)]
+    usage: Option<JobUsage>,
+    // Whatever the job wrote to $LIMMAT_RESULT_FILE.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    report: Option<Report>,
+    // See JobDetails::failure_excerpt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failure_excerpt: Option<String>,
+    // See JobDetails::parent_metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_metrics: Option<BTreeMap<String, f64>>,
+    // Set if the job ran under a terminal, in which case stdout.txt has
+    // everything it wrote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    terminal_size: Option<TerminalSize>,
+}
+
+fn deserialize_status<'de, D: serde::Deserializer<'de>>(d: D) -> Result<TestStatus, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredStatus {
+        Status(TestStatus),
+        Legacy(TestResult),
+    }
+    Ok(match StoredStatus::deserialize(d)? {
+        StoredStatus::Status(status) => status,
+        StoredStatus::Legacy(result) => TestStatus::Completed(result),
+    })
 }
 
 impl Database {
-    pub fn create_or_open(base_dir: &Path) -> anyhow::Result<Self> {
-        create_dir_all(base_dir).context(format!(
+    pub fn create_or_open(base_dir: &Path, repo_id: &RepoId) -> anyhow::Result<Self> {
+        let repo_dir = base_dir.join(REPOS_DIR).join(repo_id.dir_name());
+        create_dir_all(&repo_dir).context(format!(
             "creating result database dir at {}",
-            base_dir.display()
+            repo_dir.display()
         ))?;
+        // Record who this is, for list_repos.
+        let repo_json = repo_dir.join("repo.json");
+        if !repo_json.exists() {
+            fs::write(
+                &repo_json,
+                serde_json::to_vec(repo_id).expect("failed to serialize RepoId"),
+            )
+            .with_context(|| format!("writing {repo_json:?}"))?;
+        }
         Ok(Self {
             base_dir: base_dir.to_owned(),
+            repo_dir,
         })
     }
 
+    // Entries from before the database was separated by repository, returns
+    // the hashes they're stored under.
+    pub fn legacy_hashes(&self) -> Result<Vec<Hash>> {
+        let mut hashes = Vec::new();
+        for dir in fs::read_dir(&self.base_dir).context("reading result database")? {
+            let dir = dir?;
+            if dir.file_type()?.is_dir() && dir.file_name() != REPOS_DIR {
+                hashes.push(Hash::new(dir.file_name().to_string_lossy()));
+            }
+        }
+        Ok(hashes)
+    }
+
+    // Move legacy entries stored under these hashes into the current repo's
+    // section of the database. It's up to the caller to figure out which
+    // hashes belong to the current repo. Entries that are in use get left
+    // behind. Returns the number of entries moved.
+    pub fn migrate_legacy(&self, hashes: &[Hash]) -> Result<usize> {
+        let _db_lock = lock_file(&self.db_lock_path(), FlockArg::LockExclusive)?;
+        let mut num_moved = 0;
+        for hash in hashes {
+            let old_hash_dir = self.base_dir.join::<&str>(hash.as_ref());
+            let new_hash_dir = self.repo_dir.join::<&str>(hash.as_ref());
+            let mut num_left = 0;
+            for test_dir in fs::read_dir(&old_hash_dir)? {
+                let test_dir = test_dir?;
+                if !test_dir.file_type()?.is_dir() {
+                    continue;
+                }
+                let new_dir = new_hash_dir.join(test_dir.file_name());
+                let old_lock = lock_file(
+                    &entry_lock_path(&test_dir.path()),
+                    FlockArg::LockExclusiveNonblock,
+                )?;
+                // If there's already a new-style entry, don't clobber it.
+                if old_lock.is_none() || new_dir.exists() {
+                    num_left += 1;
+                    continue;
+                }
+                create_dir_all(&new_hash_dir)?;
+                fs::rename(test_dir.path(), &new_dir)
+                    .with_context(|| format!("moving {:?} to {new_dir:?}", test_dir.path()))?;
+                num_moved += 1;
+            }
+            // Now all that's left is lock files. Since we hold the DB lock,
+            // nobody can be trying to claim these entries, and nobody will
+            // look for them in future. So it's safe to delete the lock files.
+            if num_left == 0 {
+                remove_dir_all(&old_hash_dir)
+                    .with_context(|| format!("deleting {old_hash_dir:?}"))?;
+            }
+        }
+        Ok(num_moved)
+    }
+
+    // Summarise the repos that have results in the database.
+    pub fn list_repos(&self) -> Result<Vec<RepoInfo>> {
+        let repos_dir = self.base_dir.join(REPOS_DIR);
+        let mut repos = Vec::new();
+        for dir in fs::read_dir(&repos_dir).context("reading result database")? {
+            let dir = dir?.path();
+            let id = fs::read_to_string(dir.join("repo.json"))
+                .map_err(anyhow::Error::from)
+                .and_then(|s| Ok(serde_json::from_str(&s)?))
+                .inspect_err(|e| warn!("Couldn't read repo.json in {dir:?}: {e:#}"))
+                .ok();
+            let mut entries = Vec::new();
+            list_entries_in(&dir, &mut entries)?;
+            repos.push(RepoInfo {
+                id,
+                num_entries: entries.len(),
+                size: entries.iter().map(|e| e.size).sum(),
+                dir,
+            });
+        }
+        repos.sort_by(|a, b| a.dir.cmp(&b.dir));
+        Ok(repos)
+    }
+
+    fn db_lock_path(&self) -> PathBuf {
+        self.base_dir.join("lock")
+    }
+
     pub fn result_relpath(test_case: &TestCase) -> PathBuf {
         Path::new(test_case.storage_hash()).join(&test_case.test.name)
     }
 
+    // Where a particular attempt is, relative to the same place as
+    // result_relpath. If we don't know the attempt, go with whatever is
+    // latest.
+    pub fn attempt_relpath(test_case: &TestCase, attempt: Option<u64>) -> PathBuf {
+        let relpath = Self::result_relpath(test_case);
+        match attempt {
+            Some(number) => relpath.join(ATTEMPTS_DIR).join(number.to_string()),
+            None => relpath.join(LATEST_LINK),
+        }
+    }
+
     fn result_path(&self, hash: &Hash, test_name: &TestName) -> PathBuf {
-        self.base_dir.join::<&str>(hash.as_ref()).join(test_name)
+        self.repo_dir.join::<&str>(hash.as_ref()).join(test_name)
     }
 
     pub fn lookup_result(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
@@ -52,41 +370,570 @@ impl Database {
             Some(ref hash) => hash,
         };
 
-        let base_dir = self.result_path(hash, &test_case.test.name);
-        if !base_dir.exists() {
+        let entry_dir = self.result_path(hash, &test_case.test.name);
+        if !entry_dir.exists() {
             return Ok(None);
         }
-        let entry = DatabaseEntry::open(&base_dir)?;
+        let lock = match lock_file(&entry_lock_path(&entry_dir), FlockArg::LockSharedNonblock)? {
+            None => {
+                debug!("{test_case:?}: entry is being written, treating as absent");
+                return Ok(None);
+            }
+            Some(lock) => lock,
+        };
+        let Some(attempt_dir) = latest_attempt_dir(&entry_dir) else {
+            // Nothing ever finished here.
+            return Ok(None);
+        };
+        let entry = DatabaseEntry::open(&attempt_dir, EntryLock { _lock: lock })?;
 
         if entry.result.config_hash != test_case.test.config_hash {
             // Configuration changed, need to re-run.
             return Ok(None);
         }
+        if !entry.status().is_cacheable() {
+            // E.g. the job timed out last time, try again.
+            return Ok(None);
+        }
+        if entry.is_expired(&test_case.test.cache_ttl) {
+            // Leave the entry alone, it gets replaced when the test re-runs.
+            debug!("{test_case:?}: result has expired, treating as absent");
+            return Ok(None);
+        }
+
+        // Record that the entry was used, gc evicts the least recently used
+        // ones first.
+        entry
+            .touch()
+            .unwrap_or_else(|e| warn!("Couldn't update last use time of {attempt_dir:?}: {e:#}"));
 
         Ok(Some(entry))
     }
 
+    // Like lookup_result, but instead of the entry returns why it would or
+    // wouldn't be used.
+    pub fn explain_lookup(&self, test_case: &TestCase) -> Result<LookupExplanation> {
+        let hash = match test_case.cache_hash {
+            None => return Ok(LookupExplanation::NoCaching),
+            Some(ref hash) => hash,
+        };
+        let entry_dir = self.result_path(hash, &test_case.test.name);
+        if !entry_dir.exists() {
+            return Ok(LookupExplanation::NoEntry);
+        }
+        let Some(lock) = self.read_lock(&entry_dir)? else {
+            return Ok(LookupExplanation::Busy);
+        };
+        let Some(attempt_dir) = latest_attempt_dir(&entry_dir) else {
+            // Nothing reusable was ever stored, but if the test has finished
+            // before we can say how.
+            let last_status = list_attempts(&entry_dir)?
+                .into_iter()
+                .rev()
+                .find_map(|(number, dir)| Attempt::read(number, dir, false).ok()?.status);
+            return Ok(match last_status {
+                Some(status) => LookupExplanation::NotCacheable(status),
+                None => LookupExplanation::NoEntry,
+            });
+        };
+        let entry = DatabaseEntry::open(&attempt_dir, lock)?;
+
+        if entry.result.config_hash != test_case.test.config_hash {
+            let config_path = attempt_dir.join(CONFIG_FILE);
+            if !config_path.exists() {
+                // Written by a version that didn't store the config.
+                return Ok(LookupExplanation::ConfigChanged(vec![]));
+            }
+            let stored_config: serde_json::Value = serde_json::from_str(
+                &fs::read_to_string(&config_path)
+                    .with_context(|| format!("reading {config_path:?}"))?,
+            )
+            .context("parsing stored config")?;
+            let mut diffs = Vec::new();
+            json_diff("", &stored_config, &test_case.test.config_json, &mut diffs);
+            return Ok(LookupExplanation::ConfigChanged(diffs));
+        }
+        // Only possible for entries in the old layout, which didn't have a
+        // separate latest link.
+        if !entry.status().is_cacheable() {
+            return Ok(LookupExplanation::NotCacheable(entry.status().clone()));
+        }
+        if entry.is_expired(&test_case.test.cache_ttl) {
+            return Ok(LookupExplanation::Expired {
+                status: entry.status().clone(),
+                age: entry.age(),
+                ttl: test_case
+                    .test
+                    .cache_ttl
+                    .for_status(entry.status())
+                    .expect("expired entry with no TTL"),
+            });
+        }
+        Ok(LookupExplanation::Hit(entry.status().clone()))
+    }
+
+    // Stop the entry at this path from getting deleted or overwritten until the
+    // returned lock is dropped. Returns None if someone is currently writing it.
+    // The path can also be one of the entry's attempts.
+    pub fn read_lock(&self, dir: &Path) -> Result<Option<EntryLock>> {
+        Ok(lock_file(
+            &entry_lock_path(entry_dir_of(dir)),
+            FlockArg::LockSharedNonblock,
+        )?
+        .map(|lock| EntryLock { _lock: lock }))
+    }
+
+    // The latest run of a test case that produced a result. Unlike
+    // lookup_result this doesn't care whether it's still valid to reuse: the
+    // config hash, cacheability and TTL are all ignored, it's up to the caller
+    // to know the result is fresh enough. None if somebody is currently
+    // writing to it, an error if there's nothing there.
+    pub fn latest_entry(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
+        let entry_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        // Don't let the lock create the entry directory.
+        if !entry_dir.exists() {
+            bail!("no result in {entry_dir:?}");
+        }
+        let Some(lock) = self.read_lock(&entry_dir)? else {
+            return Ok(None);
+        };
+        let attempt_dir = latest_attempt_dir(&entry_dir)
+            .ok_or_else(|| anyhow!("no finished result in {entry_dir:?}"))?;
+        Ok(Some(DatabaseEntry::open(&attempt_dir, lock)?))
+    }
+
+    // All the runs of the test that are stored under this hash, oldest
+    // first. Includes ones that are still in progress or never finished.
+    pub fn attempts(&self, hash: &Hash, test_name: &TestName) -> Result<Vec<Attempt>> {
+        let entry_dir = self.result_path(hash, test_name);
+        let latest = latest_attempt_dir(&entry_dir);
+        let mut attempts = Vec::new();
+        if entry_dir.join("result.json").exists() {
+            // Old layout with a single attempt, it'll become number 1.
+            let is_latest = latest.as_ref() == Some(&entry_dir);
+            attempts.push(Attempt::read(1, entry_dir.clone(), is_latest)?);
+        }
+        for (number, dir) in list_attempts(&entry_dir)? {
+            let is_latest = latest.as_ref() == Some(&dir);
+            attempts.push(Attempt::read(number, dir, is_latest)?);
+        }
+        Ok(attempts)
+    }
+
+    // Usage of the latest run of every test case in this repo that produced
+    // a result. Entries that can't be read are skipped.
+    pub fn past_usage(&self) -> Result<Vec<(TestName, JobUsage)>> {
+        let mut usages = Vec::new();
+        for hash_dir in fs::read_dir(&self.repo_dir).context("reading result database")? {
+            let hash_dir = hash_dir?;
+            if !hash_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for test_dir in fs::read_dir(hash_dir.path())? {
+                let test_dir = test_dir?;
+                let Some(test_name) = test_dir.file_name().to_str().map(TestName::new) else {
+                    continue;
+                };
+                let Some(attempt_dir) = latest_attempt_dir(&test_dir.path()) else {
+                    continue;
+                };
+                let result: Option<TestResultEntry> =
+                    fs::read_to_string(attempt_dir.join("result.json"))
+                        .ok()
+                        .and_then(|json| serde_json::from_str(&json).ok());
+                if let Some(TestResultEntry {
+                    status,
+                    usage: Some(usage),
+                    ..
+                }) = result
+                {
+                    if status.is_cacheable() {
+                        usages.push((test_name, usage));
+                    }
+                }
+            }
+        }
+        Ok(usages)
+    }
+
+    // Delete entries until the database satisfies the policy. First goes the
+    // stuff that's past max_age, then the least recently used entries until
+    // it fits into max_size. Entries that are locked (i.e. being written, or
+    // used by a running job) are left alone, so the result can still be over
+    // budget. This blocks while other processes are claiming entries, so call
+    // it from a blocking context.
+    pub fn gc(&self, policy: &GcPolicy) -> Result<GcReport> {
+        let _db_lock = lock_file(&self.db_lock_path(), FlockArg::LockExclusive)?;
+        let mut entries = self.list_entries()?;
+        entries.sort_by_key(|e| e.last_used);
+        let now = SystemTime::now();
+        let mut report = GcReport {
+            bytes_remaining: entries.iter().map(|e| e.size).sum(),
+            ..Default::default()
+        };
+        for entry in entries {
+            let too_old = policy.max_age.is_some_and(|max_age| {
+                now.duration_since(entry.last_used).unwrap_or_default() > max_age
+            });
+            let too_big = policy
+                .max_size
+                .is_some_and(|max_size| report.bytes_remaining > max_size);
+            // Entries are sorted from least recently used, so if we don't
+            // need to delete this one we don't need to delete the rest.
+            if !too_old && !too_big {
+                break;
+            }
+            // Note we never delete the lock files. Otherwise someone could have
+            // the old one open and think they've locked the entry while someone
+            // else uses a new one.
+            let Some(_lock) = lock_file(
+                &entry_lock_path(&entry.dir),
+                FlockArg::LockExclusiveNonblock,
+            )?
+            else {
+                debug!("Not deleting {:?}, it's in use", entry.dir);
+                report.entries_busy += 1;
+                continue;
+            };
+            debug!("Deleting {:?} ({} bytes)", entry.dir, entry.size);
+            remove_dir_all(&entry.dir).with_context(|| format!("deleting {:?}", entry.dir))?;
+            report.entries_deleted += 1;
+            report.bytes_freed += entry.size;
+            report.bytes_remaining -= entry.size;
+        }
+        Ok(report)
+    }
+
+    // All entries in the database, including other repos' and legacy ones.
+    fn list_entries(&self) -> Result<Vec<GcCandidate>> {
+        let mut entries = Vec::new();
+        list_entries_in(&self.base_dir, &mut entries)?;
+        let repos_dir = self.base_dir.join(REPOS_DIR);
+        if repos_dir.exists() {
+            for repo_dir in fs::read_dir(&repos_dir).context("reading result database")? {
+                list_entries_in(&repo_dir?.path(), &mut entries)?;
+            }
+        }
+        Ok(entries)
+    }
+
     // Prepare to create the output directory for a job output, but don't actually create it yet.
     // It's created once you use one of the methods of CommitOutput for writing data.
     pub fn create_output(&self, test_case: &TestCase) -> anyhow::Result<DatabaseOutput> {
         DatabaseOutput::new(
             self.result_path(test_case.storage_hash(), &test_case.test.name),
-            test_case.test.config_hash,
+            self.db_lock_path(),
+            test_case.test.config_json.clone(),
+            test_case.test.config_hash.clone(),
         )
     }
 }
 
-// Existing entry in the database.
+const ARTIFACTS_DIR: &str = "artifacts";
+const ATTEMPTS_DIR: &str = "attempts";
+// Older attempts than this get deleted when a new one starts. The one that
+// latest points to is always kept. The README promises this number.
+const MAX_ATTEMPTS: usize = 10;
+const LATEST_LINK: &str = "latest";
+const CONFIG_FILE: &str = "config.json";
+const REPOS_DIR: &str = "repos";
+
+// Find the entries in the <hash>/<test_name> hierarchy under dir.
+fn list_entries_in(dir: &Path, entries: &mut Vec<GcCandidate>) -> Result<()> {
+    for hash_dir in fs::read_dir(dir).with_context(|| format!("reading {dir:?}"))? {
+        let hash_dir = hash_dir?;
+        // Skip lock files, repo.json, and the repos dir when listing legacy entries.
+        if !hash_dir.file_type()?.is_dir() || hash_dir.file_name() == REPOS_DIR {
+            continue;
+        }
+        for test_dir in fs::read_dir(hash_dir.path())? {
+            let test_dir = test_dir?;
+            // Skip entry lock files.
+            if !test_dir.file_type()?.is_dir() {
+                continue;
+            }
+            let dir = test_dir.path();
+            // Entries that never got a result (e.g. because they were
+            // canceled) don't have a result.json, but they still take up
+            // space.
+            let last_used = fs::metadata(dir.join(LATEST_LINK).join("result.json"))
+                .or_else(|_| fs::metadata(dir.join("result.json")))
+                .or_else(|_| fs::metadata(&dir))?
+                .modified()?;
+            entries.push(GcCandidate {
+                size: disk_usage(&dir)?,
+                dir,
+                last_used,
+            });
+        }
+    }
+    Ok(())
+}
+
+// The directory of the most recent finished attempt in this entry, if any.
+fn latest_attempt_dir(entry_dir: &Path) -> Option<PathBuf> {
+    if let Ok(target) = fs::read_link(entry_dir.join(LATEST_LINK)) {
+        return Some(entry_dir.join(target));
+    }
+    // Old layout.
+    entry_dir
+        .join("result.json")
+        .exists()
+        .then(|| entry_dir.to_owned())
+}
+
+// The numbered attempt directories in this entry, sorted by number. Other junk
+// is ignored.
+fn list_attempts(entry_dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let attempts_dir = entry_dir.join(ATTEMPTS_DIR);
+    if !attempts_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut attempts = Vec::new();
+    for dir in fs::read_dir(&attempts_dir).with_context(|| format!("reading {attempts_dir:?}"))? {
+        let dir = dir?;
+        if let Some(number) = dir.file_name().to_str().and_then(|n| n.parse().ok()) {
+            attempts.push((number, dir.path()));
+        }
+    }
+    attempts.sort();
+    Ok(attempts)
+}
+
+// Paths handed out to users of the database can point to an attempt rather
+// than the entry itself, this finds the entry.
+fn entry_dir_of(dir: &Path) -> &Path {
+    match dir.parent() {
+        Some(parent) if parent.file_name() == Some(ATTEMPTS_DIR.as_ref()) => {
+            parent.parent().expect("attempts dir with no parent")
+        }
+        _ => dir,
+    }
+}
+
+// One run of a test case, as reported by Database::attempts.
+#[derive(Debug)]
+pub struct Attempt {
+    pub number: u64,
+    pub dir: PathBuf,
+    // None if it's still running, it never finished (e.g. Limmat crashed), or
+    // the result is unreadable.
+    pub status: Option<TestStatus>,
+    pub finished_at: Option<SystemTime>,
+    pub usage: Option<JobUsage>,
+    pub report: Option<Report>,
+    pub failure_excerpt: Option<String>,
+    pub parent_metrics: Option<BTreeMap<String, f64>>,
+    pub terminal_size: Option<TerminalSize>,
+    // Whether this is the attempt that lookups use.
+    pub is_latest: bool,
+}
+
+impl Attempt {
+    fn read(number: u64, dir: PathBuf, is_latest: bool) -> Result<Self> {
+        let json_path = dir.join("result.json");
+        let result: Option<TestResultEntry> = if json_path.exists() {
+            // Don't let one broken attempt hide the rest of the history.
+            serde_json::from_str(
+                &fs::read_to_string(&json_path)
+                    .with_context(|| format!("reading {json_path:?}"))?,
+            )
+            .inspect_err(|e| warn!("Couldn't parse {json_path:?}: {e}"))
+            .ok()
+        } else {
+            None
+        };
+        Ok(Self {
+            number,
+            dir,
+            status: result.as_ref().map(|r| r.status.clone()),
+            finished_at: result.as_ref().and_then(|r| r.finished_at),
+            usage: result.as_ref().and_then(|r| r.usage.clone()),
+            report: result.as_ref().and_then(|r| r.report.clone()),
+            terminal_size: result.as_ref().and_then(|r| r.terminal_size),
+            failure_excerpt: result.as_ref().and_then(|r| r.failure_excerpt.clone()),
+            parent_metrics: result.and_then(|r| r.parent_metrics),
+            is_latest,
+        })
+    }
+}
+
+// Limits on the size of the database, see Database::gc.
+#[derive(Debug, Clone, Default)]
+pub struct GcPolicy {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl GcPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_size.is_none() && self.max_age.is_none()
+    }
+}
+
+// What Database::gc got up to.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    pub entries_deleted: usize,
+    pub bytes_freed: u64,
+    // Entries that would have been deleted if they weren't in use.
+    pub entries_busy: usize,
+    pub bytes_remaining: u64,
+}
+
+impl Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Freed {} from {} entries, {} remaining",
+            human_bytes(self.bytes_freed),
+            self.entries_deleted,
+            human_bytes(self.bytes_remaining)
+        )?;
+        if self.entries_busy != 0 {
+            write!(f, " ({} entries skipped as in use)", self.entries_busy)?;
+        }
+        Ok(())
+    }
+}
+
+struct GcCandidate {
+    dir: PathBuf,
+    last_used: SystemTime,
+    size: u64,
+}
+
+// Total size of the files under path. Symlinks aren't followed.
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path).with_context(|| format!("statting {path:?}"))?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path).with_context(|| format!("reading directory {path:?}"))? {
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+// The lock for an entry lives outside of it, so that it doesn't get deleted
+// when the entry gets cleaned up.
+fn entry_lock_path(entry_dir: &Path) -> PathBuf {
+    let mut filename = entry_dir
+        .file_name()
+        .expect("database entry path has no filename")
+        .to_owned();
+    filename.push(".lock");
+    entry_dir.with_file_name(filename)
+}
+
+// Take a lock, creating the lock file if necessary. Returns None if the lock
+// is non-blocking and someone else holds a conflicting lock.
+fn lock_file(path: &Path, arg: FlockArg) -> Result<Option<Flock<File>>> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+    }
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("opening lock file {path:?}"))?;
+    match Flock::lock(file, arg) {
+        Ok(lock) => Ok(Some(lock)),
+        Err((_, Errno::EWOULDBLOCK)) => Ok(None),
+        Err((_, errno)) => Err(errno).with_context(|| format!("locking {path:?}")),
+    }
+}
+
+// Artifact paths get joined onto database paths, make sure they can't escape.
+fn check_relpath(relpath: &Path) -> Result<()> {
+    if !relpath
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("artifact path {relpath:?} must be relative and must not contain '..'");
+    }
+    Ok(())
+}
+
+// Recursively copy an artifact out of the job directory. Symlinks that point
+// somewhere else inside the artifact are copied as links, not followed, so a
+// link pointing back up the tree can't send us round in circles. Links to
+// files elsewhere in the job directory are replaced with a copy of the file,
+// anything else is skipped: the database gets served over HTTP, so it mustn't
+// end up linking to whatever the job felt like.
+pub fn copy_recursive(src: &Path, dest: &Path, job_dir: &Path) -> Result<()> {
+    let job_dir = job_dir
+        .canonicalize()
+        .with_context(|| format!("resolving {job_dir:?}"))?;
+    let root = if fs::symlink_metadata(src)
+        .with_context(|| format!("reading metadata for {src:?}"))?
+        .is_symlink()
+    {
+        None
+    } else {
+        Some(
+            src.canonicalize()
+                .with_context(|| format!("resolving {src:?}"))?,
+        )
+    };
+    copy_tree(src, dest, root.as_deref(), &job_dir)
+}
+
+fn copy_tree(src: &Path, dest: &Path, root: Option<&Path>, job_dir: &Path) -> Result<()> {
+    let file_type = fs::symlink_metadata(src)
+        .with_context(|| format!("reading metadata for {src:?}"))?
+        .file_type();
+    if file_type.is_symlink() {
+        let target = fs::read_link(src).with_context(|| format!("reading link {src:?}"))?;
+        let resolved = match src.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                warn!("skipping artifact link {src:?} -> {target:?}: {err}");
+                return Ok(());
+            }
+        };
+        if target.is_relative() && root.is_some_and(|root| resolved.starts_with(root)) {
+            symlink(&target, dest).with_context(|| format!("creating link {dest:?}"))?;
+        } else if resolved.starts_with(job_dir) && resolved.is_file() {
+            fs::copy(&resolved, dest)
+                .with_context(|| format!("copying {resolved:?} to {dest:?}"))?;
+        } else {
+            warn!("skipping artifact link {src:?} -> {target:?}, it points outside the artifact");
+        }
+    } else if file_type.is_dir() {
+        create_dir_all(dest).with_context(|| format!("creating {dest:?}"))?;
+        for entry in fs::read_dir(src).with_context(|| format!("reading directory {src:?}"))? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()), root, job_dir)?;
+        }
+    } else {
+        fs::copy(src, dest).with_context(|| format!("copying {src:?} to {dest:?}"))?;
+    }
+    Ok(())
+}
+
+// Shared lock on an entry, while this exists nobody else will modify or
+// delete it.
+pub struct EntryLock {
+    _lock: Flock<File>,
+}
+
+// Existing entry in the database. Nobody else will modify it while this
+// object exists.
 pub struct DatabaseEntry {
     base_path: PathBuf,
     result: TestResultEntry,
+    _lock: EntryLock,
 }
 
 impl DatabaseEntry {
-    fn open(base_dir: &Path) -> anyhow::Result<Self> {
+    fn open(base_dir: &Path, lock: EntryLock) -> anyhow::Result<Self> {
         let json_path = base_dir.join("result.json");
         Ok(Self {
             base_path: base_dir.to_owned(),
+            _lock: lock,
             result: serde_json::from_str(
                 &fs::read_to_string(base_dir.join("result.json"))
                     .with_context(|| format!("reading result JSON from {:?}", json_path))?,
@@ -95,8 +942,57 @@ impl DatabaseEntry {
         })
     }
 
-    pub fn result(&self) -> &TestResult {
-        &self.result.result
+    pub fn path(&self) -> &Path {
+        &self.base_path
+    }
+
+    // None for entries from versions that didn't keep history.
+    pub fn attempt(&self) -> Option<u64> {
+        if entry_dir_of(&self.base_path) == self.base_path {
+            return None;
+        }
+        self.base_path.file_name()?.to_str()?.parse().ok()
+    }
+
+    fn touch(&self) -> Result<()> {
+        File::options()
+            .append(true)
+            .open(self.base_path.join("result.json"))?
+            .set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    pub fn status(&self) -> &TestStatus {
+        &self.result.status
+    }
+
+    pub fn details(&self) -> JobDetails {
+        JobDetails {
+            usage: self.result.usage.clone(),
+            report: self.result.report.clone(),
+            failure_excerpt: self.result.failure_excerpt.clone(),
+            parent_metrics: self.result.parent_metrics.clone(),
+        }
+    }
+
+    // How long ago the job finished, None if the entry doesn't say.
+    fn age(&self) -> Option<Duration> {
+        let finished_at = self.result.finished_at?;
+        // If the clock went backwards, call it brand new.
+        Some(
+            SystemTime::now()
+                .duration_since(finished_at)
+                .unwrap_or_default(),
+        )
+    }
+
+    // Whether the entry is too old to reuse. If we don't know how old it is,
+    // assume it's too old.
+    fn is_expired(&self, ttl: &CacheTtl) -> bool {
+        match ttl.for_status(self.status()) {
+            None => false,
+            Some(ttl) => !self.age().is_some_and(|age| age <= ttl),
+        }
     }
 
     pub fn stdout_path(&self) -> PathBuf {
@@ -106,50 +1002,167 @@ impl DatabaseEntry {
     pub fn stderr_path(&self) -> PathBuf {
         self.base_path.join("stderr.txt")
     }
+
+    // If this is set, stderr_path doesn't exist, it all went to stdout_path.
+    pub fn terminal_size(&self) -> Option<TerminalSize> {
+        self.result.terminal_size
+    }
+
+    pub fn artifacts_dir(&self) -> PathBuf {
+        self.base_path.join(ARTIFACTS_DIR)
+    }
+
+    // Path where the artifact that the job produced at relpath would be
+    // stored. Doesn't check whether it actually exists.
+    pub fn artifact_path(&self, relpath: &Path) -> Result<PathBuf> {
+        check_relpath(relpath)?;
+        Ok(self.artifacts_dir().join(relpath))
+    }
 }
 
-// Output for an individual test job, stored into the database
+// Output for an individual test job, stored into the database as a new
+// attempt in the entry.
 pub struct DatabaseOutput {
-    base_dir: PathBuf,
-    base_dir_created: bool,
+    entry_dir: PathBuf,
+    db_lock_path: PathBuf,
+    // Exclusive lock on the entry, set once we've claimed it.
+    lock: Option<Flock<File>>,
+    // Set once it's been created.
+    attempt_dir: Option<PathBuf>,
+    attempt: Option<u64>,
     stdout_opened: bool,
     stderr_opened: bool,
     status_written: bool,
+    usage: Option<JobUsage>,
+    report: Option<Report>,
+    failure_excerpt: Option<String>,
+    parent_metrics: Option<BTreeMap<String, f64>>,
+    terminal_size: Option<TerminalSize>,
+    config_json: serde_json::Value,
     config_hash: ConfigHash,
 }
 
 impl DatabaseOutput {
-    pub fn new(base_dir: PathBuf, config_hash: ConfigHash) -> anyhow::Result<Self> {
-        debug!("Creating database entry at {base_dir:?}");
+    pub fn new(
+        entry_dir: PathBuf,
+        db_lock_path: PathBuf,
+        config_json: serde_json::Value,
+        config_hash: ConfigHash,
+    ) -> anyhow::Result<Self> {
+        debug!("Creating database entry at {entry_dir:?}");
         Ok(Self {
-            base_dir,
-            base_dir_created: false,
+            entry_dir,
+            db_lock_path,
+            lock: None,
+            attempt_dir: None,
+            attempt: None,
             stdout_opened: false,
             stderr_opened: false,
             status_written: false,
+            usage: None,
+            report: None,
+            failure_excerpt: None,
+            parent_metrics: None,
+            terminal_size: None,
+            config_json,
             config_hash,
         })
     }
 
-    // Create and return base directory
+    // Create and return the directory for this attempt.
     fn get_base_dir(&mut self) -> Result<&Path> {
-        if !self.base_dir_created {
-            // To avoid confusion from partially-overwritten entries, delete the old one if it exists.
-            if self.base_dir.exists() {
-                remove_dir_all(&self.base_dir).context("cleaning up old result DB entry")?;
-            }
+        assert!(
+            self.lock.is_some(),
+            "writing to database entry without claiming it"
+        );
+        if self.attempt_dir.is_none() {
+            self.migrate_old_layout()?;
+            let attempts = list_attempts(&self.entry_dir)?;
+            let number = attempts.last().map_or(1, |(number, _)| number + 1);
+            self.prune_attempts(attempts)?;
+            let dir = self.entry_dir.join(ATTEMPTS_DIR).join(number.to_string());
+            create_dir_all(&dir)
+                .context(format!("creating commit result dir at {}", dir.display()))?;
+            self.attempt_dir = Some(dir);
+            self.attempt = Some(number);
+        }
+        Ok(self.attempt_dir.as_ref().unwrap())
+    }
 
-            create_dir_all(&self.base_dir).context(format!(
-                "creating commit result dir at {}",
-                self.base_dir.display()
-            ))?;
-            self.base_dir_created = true;
+    // Make room for a new attempt, given the existing ones.
+    fn prune_attempts(&self, attempts: Vec<(u64, PathBuf)>) -> Result<()> {
+        let latest = latest_attempt_dir(&self.entry_dir);
+        let num_to_delete = (attempts.len() + 1).saturating_sub(MAX_ATTEMPTS);
+        for (_, dir) in attempts
+            .into_iter()
+            .filter(|(_, dir)| Some(dir) != latest.as_ref())
+            .take(num_to_delete)
+        {
+            debug!("Deleting old attempt {dir:?}");
+            remove_dir_all(&dir).with_context(|| format!("deleting {dir:?}"))?;
         }
-        Ok(&self.base_dir)
+        Ok(())
+    }
+
+    // If the entry was written by a version that didn't keep history, move
+    // its contents into the first attempt.
+    fn migrate_old_layout(&self) -> Result<()> {
+        if !self.entry_dir.exists() || self.entry_dir.join(ATTEMPTS_DIR).exists() {
+            return Ok(());
+        }
+        let children = fs::read_dir(&self.entry_dir)?.collect::<Result<Vec<_>, _>>()?;
+        if children.is_empty() {
+            return Ok(());
+        }
+        let first = self.entry_dir.join(ATTEMPTS_DIR).join("1");
+        create_dir_all(&first).with_context(|| format!("creating {first:?}"))?;
+        for child in children {
+            fs::rename(child.path(), first.join(child.file_name()))
+                .with_context(|| format!("moving {:?} into {first:?}", child.path()))?;
+        }
+        if first.join("result.json").exists() {
+            self.set_latest(&first)?;
+        }
+        Ok(())
+    }
+
+    // Point the latest link at this attempt.
+    fn set_latest(&self, attempt_dir: &Path) -> Result<()> {
+        let target = attempt_dir
+            .strip_prefix(&self.entry_dir)
+            .expect("attempt outside of entry");
+        // Replace it atomically, so the link always points somewhere valid.
+        let tmp_path = self.entry_dir.join(format!("{LATEST_LINK}.tmp"));
+        if fs::symlink_metadata(&tmp_path).is_ok() {
+            fs::remove_file(&tmp_path).with_context(|| format!("deleting {tmp_path:?}"))?;
+        }
+        symlink(target, &tmp_path).with_context(|| format!("creating {tmp_path:?}"))?;
+        fs::rename(&tmp_path, self.entry_dir.join(LATEST_LINK))
+            .with_context(|| format!("updating latest link in {:?}", self.entry_dir))
     }
 }
 
 impl TestJobOutput for DatabaseOutput {
+    fn claim(&mut self) -> Result<bool> {
+        if self.lock.is_some() {
+            return Ok(true);
+        }
+        // Only need this while taking the entry lock, it just stops us
+        // claiming new entries in the middle of maintenance.
+        let Some(_db_lock) = lock_file(&self.db_lock_path, FlockArg::LockSharedNonblock)? else {
+            debug!(
+                "Database locked for maintenance, can't claim {:?}",
+                self.entry_dir
+            );
+            return Ok(false);
+        };
+        self.lock = lock_file(
+            &entry_lock_path(&self.entry_dir),
+            FlockArg::LockExclusiveNonblock,
+        )?;
+        Ok(self.lock.is_some())
+    }
+
     fn stdout(&mut self) -> Result<Stdio> {
         assert!(!self.stdout_opened);
         self.stdout_opened = true;
@@ -166,20 +1179,437 @@ impl TestJobOutput for DatabaseOutput {
         )?))
     }
 
-    // TODO: Figure out how to record errors in the more general case, probably with a JSON object.
-    fn set_result(&mut self, result: &TestResult) -> anyhow::Result<()> {
+    fn terminal(&mut self, size: TerminalSize) -> Result<File> {
+        assert!(!self.stdout_opened && !self.stderr_opened);
+        self.stdout_opened = true;
+        self.terminal_size = Some(size);
+        Ok(File::create(self.get_base_dir()?.join("stdout.txt"))?)
+    }
+
+    fn set_status(&mut self, status: &TestStatus) -> anyhow::Result<()> {
         assert!(!self.status_written);
         self.status_written = true;
+        // Keep the whole config around so that explain_lookup can say what
+        // changed if the hash stops matching.
+        fs::write(
+            self.get_base_dir()?.join(CONFIG_FILE),
+            serde_json::to_vec_pretty(&self.config_json).expect("failed to serialize config"),
+        )?;
         let entry = TestResultEntry {
-            config_hash: self.config_hash,
-            result: result.clone(),
+            config_hash: self.config_hash.clone(),
+            status: status.clone(),
+            finished_at: Some(SystemTime::now()),
+            usage: self.usage.clone(),
+            report: self.report.clone(),
+            failure_excerpt: self.failure_excerpt.clone(),
+            parent_metrics: self.parent_metrics.clone(),
+            terminal_size: self.terminal_size,
         };
-        Ok(fs::write(
-            self.get_base_dir()?.join("result.json"),
+        let attempt_dir = self.get_base_dir()?.to_owned();
+        fs::write(
+            attempt_dir.join("result.json"),
             serde_json::to_vec(&entry).expect("failed to serialize TestStatus"),
-        )?)
+        )?;
+        // Otherwise e.g. a job that got cancelled would stop us reusing the
+        // result from before it.
+        if status.is_cacheable() {
+            self.set_latest(&attempt_dir)?;
+        }
+        Ok(())
+    }
+
+    fn new_attempt(&mut self) {
+        self.attempt_dir = None;
+        self.attempt = None;
+        self.stdout_opened = false;
+        self.stderr_opened = false;
+        self.status_written = false;
+        self.usage = None;
+        self.report = None;
+        self.failure_excerpt = None;
+        self.parent_metrics = None;
+        self.terminal_size = None;
+    }
+
+    fn set_usage(&mut self, usage: &JobUsage) {
+        self.usage = Some(usage.clone());
+    }
+
+    fn set_result(&mut self, report: &Report) {
+        self.report = Some(report.clone());
+    }
+
+    fn set_failure_excerpt(&mut self, excerpt: &str) {
+        self.failure_excerpt = Some(excerpt.to_owned());
+    }
+
+    fn set_parent_metrics(&mut self, metrics: &BTreeMap<String, f64>) {
+        self.parent_metrics = Some(metrics.clone());
+    }
+
+    fn attempt(&mut self) -> anyhow::Result<Option<u64>> {
+        self.get_base_dir()?;
+        Ok(self.attempt)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.attempt_dir.as_deref()
+    }
+
+    fn stdout_path(&self) -> Option<PathBuf> {
+        self.stdout_opened
+            .then(|| self.attempt_dir.as_ref().unwrap().join("stdout.txt"))
+    }
+
+    fn stderr_path(&self) -> Option<PathBuf> {
+        self.stderr_opened
+            .then(|| self.attempt_dir.as_ref().unwrap().join("stderr.txt"))
+    }
+
+    fn artifact_dest(&mut self, relpath: &Path) -> anyhow::Result<Option<PathBuf>> {
+        check_relpath(relpath)?;
+        let dest = self.get_base_dir()?.join(ARTIFACTS_DIR).join(relpath);
+        if let Some(parent) = dest.parent() {
+            create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+        }
+        Ok(Some(dest))
     }
 }
 
 // TODO:
 // - Test behaviour on already-existing directories
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use test_case::test_case;
+
+    use super::*;
+
+    fn repo_id() -> RepoId {
+        RepoId::Project("my_project".into())
+    }
+
+    // Create a fake entry of the given size, last used the given number of days ago.
+    fn fake_entry(db: &Database, hash: &str, size: usize, age_days: u64) -> PathBuf {
+        let dir = db.repo_dir.join(hash).join("my_test");
+        create_dir_all(&dir).unwrap();
+        fs::write(dir.join("stdout.txt"), vec![b'x'; size]).unwrap();
+        fs::write(dir.join("result.json"), "{}").unwrap();
+        File::options()
+            .append(true)
+            .open(dir.join("result.json"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age_days * 24 * 60 * 60))
+            .unwrap();
+        dir
+    }
+
+    // Each entry is 1000 bytes of stdout plus 2 bytes of result.json.
+    #[test_case(GcPolicy { max_size: None, max_age: None }, [true, true, true] ; "unlimited")]
+    #[test_case(GcPolicy { max_size: Some(2004), max_age: None }, [false, true, true] ; "max_size")]
+    #[test_case(GcPolicy { max_size: Some(1), max_age: None }, [false, false, false] ; "tiny max_size")]
+    #[test_case(
+        GcPolicy { max_size: None, max_age: Some(Duration::from_secs(2 * 24 * 60 * 60)) },
+        [false, true, true] ; "max_age")]
+    fn should_gc(policy: GcPolicy, want_kept: [bool; 3]) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        let entries = [
+            fake_entry(&db, "aaaa", 1000, 3),
+            fake_entry(&db, "bbbb", 1000, 1),
+            fake_entry(&db, "cccc", 1000, 0),
+        ];
+
+        let report = db.gc(&policy).unwrap();
+
+        let num_deleted = want_kept.iter().filter(|k| !**k).count();
+        assert_eq!(
+            report,
+            GcReport {
+                entries_deleted: num_deleted,
+                bytes_freed: num_deleted as u64 * 1002,
+                entries_busy: 0,
+                bytes_remaining: (3 - num_deleted) as u64 * 1002,
+            }
+        );
+        for (entry, want_kept) in entries.iter().zip(want_kept) {
+            assert_eq!(entry.exists(), want_kept, "{entry:?}");
+        }
+    }
+
+    #[test]
+    fn should_separate_repos() {
+        let db_dir = TempDir::new().unwrap();
+        let db1 = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        let db2 = Database::create_or_open(
+            db_dir.path(),
+            &RepoId::GitDir("/home/me/src/linux/.git".into()),
+        )
+        .unwrap();
+        assert_ne!(db1.repo_dir, db2.repo_dir);
+        fake_entry(&db1, "aaaa", 10, 0);
+        fake_entry(&db1, "bbbb", 10, 0);
+        fake_entry(&db2, "aaaa", 10, 0);
+
+        let repos = db1.list_repos().unwrap();
+        let summary: Vec<_> = repos
+            .iter()
+            .map(|r| (r.id.clone(), r.dir.clone(), r.num_entries))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    Some(RepoId::GitDir("/home/me/src/linux/.git".into())),
+                    db2.repo_dir.clone(),
+                    1
+                ),
+                (Some(repo_id()), db1.repo_dir.clone(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_migrate_legacy() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        // Entries from before the database was split up by repo.
+        for (hash, test) in [
+            ("aaaa", "t1"),
+            ("aaaa", "t2"),
+            ("bbbb", "t1"),
+            ("cccc", "t1"),
+        ] {
+            let dir = db_dir.path().join(hash).join(test);
+            create_dir_all(&dir).unwrap();
+            fs::write(dir.join("result.json"), "{}").unwrap();
+        }
+        // Entries that are in use get left behind.
+        let _lock = db.read_lock(&db_dir.path().join("bbbb/t1")).unwrap();
+
+        let mut legacy = db.legacy_hashes().unwrap();
+        legacy.sort_by(|a, b| AsRef::<str>::as_ref(a).cmp(b.as_ref()));
+        assert_eq!(
+            legacy,
+            vec![Hash::new("aaaa"), Hash::new("bbbb"), Hash::new("cccc")]
+        );
+
+        // Pretend cccc is from some other repo.
+        let num_moved = db
+            .migrate_legacy(&[Hash::new("aaaa"), Hash::new("bbbb")])
+            .unwrap();
+
+        assert_eq!(num_moved, 2);
+        assert!(db.repo_dir.join("aaaa/t1/result.json").exists());
+        assert!(db.repo_dir.join("aaaa/t2/result.json").exists());
+        assert!(!db_dir.path().join("aaaa").exists());
+        assert!(db_dir.path().join("bbbb/t1/result.json").exists());
+        assert!(!db.repo_dir.join("bbbb").exists());
+        assert!(db_dir.path().join("cccc/t1/result.json").exists());
+    }
+
+    #[test]
+    fn gc_should_skip_locked() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        let old_entry = fake_entry(&db, "aaaa", 1000, 3);
+        let new_entry = fake_entry(&db, "bbbb", 1000, 0);
+        let _lock = db.read_lock(&old_entry).unwrap().unwrap();
+
+        let report = db
+            .gc(&GcPolicy {
+                max_size: Some(0),
+                max_age: None,
+            })
+            .unwrap();
+
+        assert_eq!(report.entries_deleted, 1);
+        assert_eq!(report.entries_busy, 1);
+        assert!(old_entry.exists());
+        assert!(!new_entry.exists());
+    }
+
+    #[test]
+    fn should_diff_json() {
+        let old = serde_json::json!({
+            "test": {"command": "make", "timeout_s": 10, "depends_on": ["a"]},
+            "dependency_hashes": {"a": "1234"},
+        });
+        let new = serde_json::json!({
+            "test": {"command": "make -j", "depends_on": ["a"], "artifacts": ["out"]},
+            "dependency_hashes": {"a": "5678"},
+        });
+        let mut diffs = Vec::new();
+        json_diff("", &old, &new, &mut diffs);
+        assert_eq!(
+            diffs,
+            vec![
+                r#"dependency_hashes.a: "1234" -> "5678""#,
+                r#"test.artifacts: added (["out"])"#,
+                r#"test.command: "make" -> "make -j""#,
+                "test.timeout_s: removed (was 10)",
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_config_hash_should_not_match() {
+        let entry: TestResultEntry = serde_json::from_str(
+            r#"{"config_hash": 1234, "status": {"completed": {"exit_code": 0}}}"#,
+        )
+        .unwrap();
+        assert_ne!(entry.config_hash, ConfigHash::of(&serde_json::Value::Null));
+    }
+
+    #[test]
+    fn should_copy_symlinks_as_links() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/file"), "hello").unwrap();
+        // This would recurse forever if we followed it.
+        symlink("..", src.join("sub/loop")).unwrap();
+
+        let dest = dir.path().join("dest");
+        copy_recursive(&src, &dest, dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("sub/file")).unwrap(), "hello");
+        assert_eq!(
+            fs::read_link(dest.join("sub/loop")).unwrap(),
+            PathBuf::from("..")
+        );
+    }
+
+    #[test]
+    fn should_not_copy_links_out_of_artifact() {
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret"), "hunter2").unwrap();
+        let job_dir = TempDir::new().unwrap();
+        fs::write(job_dir.path().join("other"), "hello").unwrap();
+        let src = job_dir.path().join("out");
+        create_dir_all(&src).unwrap();
+        symlink(outside.path().join("secret"), src.join("absolute")).unwrap();
+        symlink("../../../../../../../../../etc/passwd", src.join("dotdot")).unwrap();
+        symlink("../other", src.join("sibling")).unwrap();
+        symlink("nonexistent", src.join("dangling")).unwrap();
+
+        let dest = TempDir::new().unwrap();
+        let dest = dest.path().join("out");
+        copy_recursive(&src, &dest, job_dir.path()).unwrap();
+        // Links out of the job directory get dropped.
+        assert!(fs::symlink_metadata(dest.join("absolute")).is_err());
+        assert!(fs::symlink_metadata(dest.join("dotdot")).is_err());
+        assert!(fs::symlink_metadata(dest.join("dangling")).is_err());
+        // Ones to elsewhere in the job directory would dangle, so they get
+        // replaced by what they point to.
+        assert!(!fs::symlink_metadata(dest.join("sibling"))
+            .unwrap()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(dest.join("sibling")).unwrap(), "hello");
+    }
+
+    #[test]
+    fn should_read_old_result_field() {
+        let entry: TestResultEntry =
+            serde_json::from_str(r#"{"config_hash": 1234, "result": {"exit_code": 0}}"#).unwrap();
+        assert_eq!(
+            entry.status,
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+    }
+
+    #[test]
+    fn should_keep_attempts() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        // Entry from before history was kept.
+        let entry_dir = fake_entry(&db, "aaaa", 10, 0);
+        let hash = Hash::new("aaaa");
+        let test_name = TestName::new("my_test");
+        let write_attempt = |status: &TestStatus| {
+            let mut output = DatabaseOutput::new(
+                entry_dir.clone(),
+                db.db_lock_path(),
+                serde_json::Value::Null,
+                ConfigHash::default(),
+            )
+            .unwrap();
+            assert!(output.claim().unwrap());
+            output.stdout().unwrap();
+            output.set_status(status).unwrap();
+            output.path().unwrap().to_owned()
+        };
+
+        let second = write_attempt(&TestStatus::Error("oh no".into()));
+        let third = write_attempt(&TestStatus::Canceled);
+
+        let attempts = db.attempts(&hash, &test_name).unwrap();
+        let summary: Vec<_> = attempts
+            .iter()
+            .map(|a| (a.number, a.dir.clone(), a.is_latest))
+            .collect();
+        // Runs that didn't produce a result don't replace the one from before.
+        assert_eq!(
+            summary,
+            vec![
+                (1, entry_dir.join("attempts/1"), true),
+                (2, second.clone(), false),
+                (3, third.clone(), false),
+            ]
+        );
+        // The old entry's files moved into the first attempt.
+        assert!(entry_dir.join("attempts/1/stdout.txt").exists());
+        assert_eq!(attempts[1].status, Some(TestStatus::Error("oh no".into())));
+        assert_eq!(attempts[2].status, Some(TestStatus::Canceled));
+
+        let fourth = write_attempt(&TestStatus::Completed(TestResult { exit_code: 1 }));
+        assert_eq!(latest_attempt_dir(&entry_dir), Some(fourth.clone()));
+        // Attempt paths are locked via their entry.
+        let _lock = db.read_lock(&third).unwrap().unwrap();
+        assert_eq!(
+            db.gc(&GcPolicy {
+                max_size: Some(0),
+                max_age: None
+            })
+            .unwrap()
+            .entries_busy,
+            1
+        );
+    }
+
+    #[test]
+    fn should_prune_attempts() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path(), &repo_id()).unwrap();
+        let entry_dir = db.repo_dir.join("aaaa/my_test");
+        let write_attempt = |status: &TestStatus| {
+            let mut output = DatabaseOutput::new(
+                entry_dir.clone(),
+                db.db_lock_path(),
+                serde_json::Value::Null,
+                ConfigHash::default(),
+            )
+            .unwrap();
+            assert!(output.claim().unwrap());
+            output.set_status(status).unwrap();
+            output.attempt().unwrap().unwrap()
+        };
+
+        let result = write_attempt(&TestStatus::Completed(TestResult { exit_code: 0 }));
+        for _ in 0..MAX_ATTEMPTS * 2 {
+            write_attempt(&TestStatus::Canceled);
+        }
+
+        let attempts = db
+            .attempts(&Hash::new("aaaa"), &TestName::new("my_test"))
+            .unwrap();
+        assert_eq!(attempts.len(), MAX_ATTEMPTS);
+        // The result is still there for lookups, alongside the newest runs.
+        assert_eq!(attempts[0].number, result);
+        assert!(attempts[0].is_latest);
+        assert_eq!(
+            attempts.last().unwrap().number,
+            (MAX_ATTEMPTS * 2 + 1) as u64
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    os::unix::ffi::OsStrExt as _,
+    path::Path,
+    process::Command as SyncCommand,
+};
+
+use anyhow::{anyhow, Context as _};
+#[allow(unused_imports)]
+use log::debug;
+use serde_json::json;
+use sha2::{Digest as _, Sha256};
+
+use crate::{config::Command, process::OutputExt as _, util::hex_string};
+
+// Fingerprints of the stuff outside the repository that tests depend on, keyed
+// by test name. Tests that don't declare any cache_inputs or
+// cache_key_commands don't have one.
+pub type Fingerprints = HashMap<String, serde_json::Value>;
+
+// Describe the files matching the cache_inputs patterns and the output of the
+// cache_key_commands. This gets mixed into the test's config so that it
+// invalidates the cache when it changes. It's JSON rather than just a hash so
+// that explain-cache can say which input changed.
+//
+// Relative patterns, and the commands, are relative to dir.
+pub fn fingerprint(
+    inputs: &[String],
+    commands: &[Command],
+    dir: &Path,
+) -> anyhow::Result<serde_json::Value> {
+    let escaped_dir = glob::Pattern::escape(
+        dir.to_str()
+            .ok_or_else(|| anyhow!("non-UTF-8 directory {dir:?}"))?,
+    );
+    // If a pattern doesn't match anything then it just doesn't contribute, so
+    // that creating a matching file later still changes the fingerprint.
+    let mut input_hashes = BTreeMap::new();
+    for pattern in inputs {
+        let full_pattern = if Path::new(pattern).is_absolute() {
+            pattern.clone()
+        } else {
+            format!("{escaped_dir}/{pattern}")
+        };
+        let paths = glob::glob(&full_pattern)
+            .with_context(|| format!("bad cache_inputs pattern {pattern:?}"))?;
+        for path in paths {
+            let path = path.context("expanding cache_inputs pattern")?;
+            let mut hasher = Sha256::new();
+            hash_path(&path, &mut hasher, true)?;
+            // Key relative inputs relative to dir, so that the fingerprint
+            // doesn't depend on where the repo is or how its path was spelt.
+            let key = path.strip_prefix(dir).unwrap_or(&path);
+            input_hashes.insert(
+                key.to_string_lossy().into_owned(),
+                hex_string(&hasher.finalize()),
+            );
+        }
+    }
+
+    let mut command_outputs = BTreeMap::new();
+    for command in commands {
+        debug!("Running cache key command {command}");
+        let output = SyncCommand::new(command.program())
+            .args(command.args())
+            .current_dir(dir)
+            .output()
+            .with_context(|| format!("running cache key command {command}"))?;
+        output
+            .ok()
+            .with_context(|| format!("cache key command {command}"))?;
+        command_outputs.insert(
+            command.to_string(),
+            String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+        );
+    }
+
+    Ok(json!({
+        "cache_inputs": input_hashes,
+        "cache_key_commands": command_outputs,
+    }))
+}
+
+// Feed the content of the file into the hasher. For directories, that's the
+// names and contents of everything inside. Links inside directories are hashed
+// as the path they point to rather than followed, so one pointing back up the
+// tree can't send us round in circles. If follow_links is set, the path itself
+// is followed, since that's presumably what a pattern naming a link meant.
+fn hash_path(path: &Path, hasher: &mut Sha256, follow_links: bool) -> anyhow::Result<()> {
+    let metadata = if follow_links {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    }
+    .with_context(|| format!("reading metadata for {path:?}"))?;
+    // Tag each kind of thing so that, say, a link can't collide with a file
+    // containing its target.
+    if metadata.is_symlink() {
+        let target = fs::read_link(path).with_context(|| format!("reading link {path:?}"))?;
+        let target = target.as_os_str().as_bytes();
+        hasher.update(b"l");
+        hasher.update((target.len() as u64).to_le_bytes());
+        hasher.update(target);
+    } else if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)
+            .with_context(|| format!("reading {path:?}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        hasher.update(b"d");
+        for entry in entries {
+            let name = entry.file_name();
+            // Length-prefix the names so different layouts can't collide.
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            hash_path(&entry.path(), hasher, false)?;
+        }
+    } else {
+        let content = fs::read(path).with_context(|| format!("reading {path:?}"))?;
+        hasher.update(b"f");
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn should_change_with_inputs() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "a").unwrap();
+        let fp = || {
+            fingerprint(
+                &["sub".into(), "*.cfg".into()],
+                &[Command::Shell("cat sub/a.txt; echo ' version'".into())],
+                dir.path(),
+            )
+            .unwrap()
+        };
+
+        let orig = fp();
+        assert_eq!(orig["cache_key_commands"].as_object().unwrap().len(), 1);
+        assert_eq!(
+            orig["cache_key_commands"]["cat sub/a.txt; echo ' version'"],
+            "a version"
+        );
+        assert_eq!(fp(), orig);
+
+        // Nothing matched *.cfg at first.
+        fs::write(dir.path().join("my.cfg"), "").unwrap();
+        let with_cfg = fp();
+        assert_ne!(with_cfg, orig);
+
+        fs::write(dir.path().join("sub/a.txt"), "b").unwrap();
+        let changed = fp();
+        assert_ne!(changed["cache_inputs"], with_cfg["cache_inputs"]);
+        assert_eq!(
+            changed["cache_key_commands"]["cat sub/a.txt; echo ' version'"],
+            "b version"
+        );
+    }
+
+    #[test]
+    fn should_not_depend_on_dir_spelling() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let fp = |dir: &Path| fingerprint(&["a.txt".into()], &[], dir).unwrap();
+
+        let orig = fp(dir.path());
+        assert_eq!(
+            orig["cache_inputs"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["a.txt"]
+        );
+        assert_eq!(fp(&dir.path().join(".")), orig);
+        // Same content somewhere else.
+        let other = TempDir::new().unwrap();
+        fs::write(other.path().join("a.txt"), "a").unwrap();
+        assert_eq!(fp(other.path()), orig);
+    }
+
+    #[test]
+    fn should_not_follow_links_in_dirs() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "a").unwrap();
+        // This would recurse forever if we followed it.
+        symlink("..", dir.path().join("sub/loop")).unwrap();
+        symlink("sub", dir.path().join("link")).unwrap();
+        let fp = |pattern: &str| fingerprint(&[pattern.into()], &[], dir.path()).unwrap();
+
+        let orig = fp("sub");
+        // Links to the input are followed.
+        assert_eq!(
+            fp("link")["cache_inputs"]["link"],
+            orig["cache_inputs"]["sub"]
+        );
+        // Changing where a link points changes the fingerprint.
+        fs::remove_file(dir.path().join("sub/loop")).unwrap();
+        symlink(".", dir.path().join("sub/loop")).unwrap();
+        assert_ne!(fp("sub"), orig);
+    }
+
+    #[test]
+    fn should_fail_on_failing_command() {
+        let dir = TempDir::new().unwrap();
+        assert!(fingerprint(&[], &[Command::Raw(vec!["false".into()])], dir.path()).is_err());
+    }
+}
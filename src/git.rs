@@ -1,11 +1,12 @@
 use core::fmt;
 use core::fmt::{Debug, Display};
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::ops::Deref;
 use std::os::unix::ffi::{OsStrExt as _, OsStringExt as _};
 use std::path::{Path, PathBuf};
 use std::pin::pin;
-use std::process::Command as SyncCommand;
+use std::process::{Command as SyncCommand, Output, Stdio};
 use std::str;
 use std::time::Duration;
 
@@ -18,13 +19,16 @@ use futures_core::{stream::Stream, FusedFuture};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use sha2::{Digest as _, Sha256};
 use tempfile::TempDir;
+use tokio::io::AsyncWriteExt as _;
 use tokio::process::Command;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
 
 use crate::process::OutputExt;
 use crate::process::{CommandExt, SyncCommandExt as _};
+use crate::util::hex_string;
 
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
 pub struct Hash(String);
@@ -164,6 +168,27 @@ impl Worktree for PersistentWorktree {
 pub struct Commit {
     pub hash: CommitHash,
     pub tree: TreeHash,
+    // First parent, None for a root commit.
+    pub parent: Option<CommitHash>,
+    // The rest is only set for what was asked for in
+    // Worktree::resolve_commits, it's for the cache policies that need it.
+    //
+    // Objects at paths within the commit, None means there's nothing at that
+    // path.
+    pub path_objects: HashMap<String, Option<Hash>>,
+    // From git patch-id --stable. Commits that don't have one (merges and
+    // empty commits) get their commit hash here instead.
+    pub patch_id: Option<Hash>,
+    // SHA-256 of the commit message.
+    pub message_hash: Option<Hash>,
+}
+
+// What Worktree::resolve_commits should find out.
+#[derive(Debug, Default, Clone)]
+pub struct CommitLookups {
+    pub paths: Vec<String>,
+    pub patch_id: bool,
+    pub message: bool,
 }
 
 impl From<Commit> for CommitHash {
@@ -251,6 +276,126 @@ pub trait Worktree: Debug {
         Ok(out_str.lines().map(CommitHash::new).collect())
     }
 
+    // Filter down to the hashes that refer to objects in this repo.
+    async fn existing_objects(&self, hashes: &[Hash]) -> anyhow::Result<Vec<Hash>> {
+        Ok(self
+            .batch_check(hashes.iter().map(|h| h.to_string()).collect())
+            .await?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    // Fill in the stuff asked for by lookups for each of the commits. This
+    // only runs a fixed number of git commands however many commits there are.
+    async fn resolve_commits(
+        &self,
+        mut commits: Vec<Commit>,
+        lookups: &CommitLookups,
+    ) -> anyhow::Result<Vec<Commit>> {
+        if commits.is_empty() {
+            return Ok(commits);
+        }
+
+        if !lookups.paths.is_empty() {
+            let names = commits
+                .iter()
+                .flat_map(|c| lookups.paths.iter().map(move |p| format!("{}:{p}", c.hash)))
+                .collect();
+            let mut objects = self.batch_check(names).await?.into_iter();
+            for commit in &mut commits {
+                for path in &lookups.paths {
+                    let object = objects.next().context("too little 'git cat-file' output")?;
+                    commit.path_objects.insert(path.clone(), object);
+                }
+            }
+        }
+
+        if lookups.patch_id {
+            let show = self
+                .git([
+                    "show",
+                    "--no-color",
+                    "--no-ext-diff",
+                    "--no-merges",
+                    "--format=commit %H",
+                    "--patch",
+                ])
+                .args(commits.iter().map(|c| &c.hash))
+                .execute()
+                .await
+                .context("'git show' failed")?;
+            let output = execute_with_input(&mut self.git(["patch-id", "--stable"]), show.stdout)
+                .await
+                .context("'git patch-id' failed")?;
+            // Output lines are "<patch ID> <commit hash>"
+            let patch_ids: HashMap<&str, &str> = str::from_utf8(&output.stdout)
+                .context("non utf-8 patch-id output")?
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(patch_id, commit)| (commit, patch_id))
+                .collect();
+            for commit in &mut commits {
+                commit.patch_id = Some(match patch_ids.get(commit.hash.as_ref() as &str) {
+                    Some(patch_id) => Hash::new(*patch_id),
+                    None => commit.hash.clone().into(),
+                });
+            }
+        }
+
+        if lookups.message {
+            let output = self
+                .git(["show", "--no-patch", "--format=%H%x00%B%x00"])
+                .args(commits.iter().map(|c| &c.hash))
+                .execute()
+                .await
+                .context("'git show' failed")?;
+            // Each commit comes out as "<hash>\0<message>\0\n".
+            let fields: Vec<&[u8]> = output.stdout.split(|b| *b == 0).collect();
+            let message_hashes: HashMap<String, Hash> = fields
+                .chunks_exact(2)
+                .map(|chunk| {
+                    (
+                        String::from_utf8_lossy(chunk[0]).trim().to_owned(),
+                        Hash::new(hex_string(&Sha256::digest(chunk[1]))),
+                    )
+                })
+                .collect();
+            for commit in &mut commits {
+                commit.message_hash = Some(
+                    message_hashes
+                        .get(commit.hash.as_ref() as &str)
+                        .with_context(|| format!("no message found for {}", commit.hash))?
+                        .clone(),
+                );
+            }
+        }
+
+        Ok(commits)
+    }
+
+    // Look up each object name (anything git rev-parse understands) with 'git
+    // cat-file --batch-check', None means it doesn't exist.
+    async fn batch_check(&self, object_names: Vec<String>) -> anyhow::Result<Vec<Option<Hash>>> {
+        let mut input = String::new();
+        for name in object_names {
+            input.push_str(&name);
+            input.push('\n');
+        }
+        let output = execute_with_input(
+            &mut self.git(["cat-file", "--batch-check=%(objectname)"]),
+            input.into_bytes(),
+        )
+        .await
+        .context("'git cat-file' failed")?;
+        // Missing objects are reported as "<name> missing".
+        Ok(str::from_utf8(&output.stdout)
+            .context("non utf-8 cat-file output")?
+            .lines()
+            .map(|line| (!line.ends_with(" missing")).then(|| Hash::new(line)))
+            .collect())
+    }
+
     async fn checkout(&self, commit: &CommitHash) -> anyhow::Result<()> {
         self.git(["checkout"])
             .arg(commit)
@@ -397,7 +542,7 @@ pub trait Worktree: Debug {
     {
         // We don't use log_n1 here because we want to check the exit code,
         // that API is designed for users who assume the revision exists.
-        let mut cmd = self.git(["log", "-n1", "--format=%H %T"]);
+        let mut cmd = self.git(["log", "-n1", "--format=%H %T %P"]);
         let cmd = cmd.arg(rev_spec);
         let output = cmd.output().await.context("failed to run 'git log -n1'")?;
         // Hack: empirically, git returns 128 when the range is invalid, it's not documented
@@ -412,17 +557,39 @@ pub trait Worktree: Debug {
         }
         let out_string =
             String::from_utf8(output.stdout).context("reading git rev-parse output")?;
-        let parts: Vec<&str> = out_string.trim().splitn(2, " ").collect();
-        if parts.len() != 2 {
+        let parts: Vec<&str> = out_string.split_whitespace().collect();
+        if parts.len() < 2 {
             bail!("Failed to parse result of {cmd:?} - {out_string:?}",);
         }
         Ok(Some(Commit {
             hash: CommitHash::new(parts[0]),
             tree: TreeHash::new(parts[1]),
+            parent: parts.get(2).map(|p| CommitHash::new(*p)),
+            path_objects: HashMap::new(),
+            patch_id: None,
+            message_hash: None,
         }))
     }
 }
 
+// Run the command with input fed to its stdin, fail unless it succeeds.
+async fn execute_with_input(cmd: &mut Command, input: Vec<u8>) -> anyhow::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("spawning command")?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Write from a separate task so the child can't block on us reading its
+    // output while we block on it reading its input.
+    let writer = tokio::spawn(async move { stdin.write_all(&input).await });
+    let output = child.wait_with_output().await?;
+    writer.await?.context("writing to stdin")?;
+    output.ok()?;
+    Ok(output)
+}
+
 // A worktree that is deleted when dropped. This is kind of a dumb API that just happens to fit this
 // project's exact needs. Instead probably Repo::new and this method should return a common trait or
 // something.
@@ -588,6 +755,29 @@ pub mod test_utils {
                 .ok_or(anyhow!("no HEAD after committing"))
         }
 
+        // Like commit, but first writes content to the file at relpath.
+        async fn commit_file<S>(
+            &self,
+            relpath: impl AsRef<Path>,
+            content: impl AsRef<[u8]>,
+            message: S,
+        ) -> anyhow::Result<Commit>
+        where
+            S: AsRef<OsStr>,
+        {
+            let path = self.path().join(relpath.as_ref());
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, content)?;
+            self.git(["add"])
+                .arg(&path)
+                .execute()
+                .await
+                .context("'git add' failed")?;
+            self.commit(message).await
+        }
+
         async fn merge(&self, parents: &[CommitHash]) -> anyhow::Result<Commit> {
             self.git(["merge", "-m", "merge commit"])
                 .args(parents)
@@ -1,10 +1,14 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{self, Path as FsPath},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use anyhow::Context as _;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Path, State, WebSocketUpgrade,
     },
     handler::HandlerWithoutStateExt as _,
     http::StatusCode,
@@ -13,11 +17,52 @@ use axum::{
     Router,
 };
 use indoc::indoc;
-use tokio::{net::TcpListener, select, sync::watch};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use tokio::{net::TcpListener, select, sync::watch, task::spawn_blocking};
 use tokio_util::sync::CancellationToken;
 use tower_http::services::ServeDir;
 
-use crate::text::RenderHtmlPre;
+use crate::{
+    database::{Attempt, Database},
+    git::Hash,
+    report::SubtestStatus,
+    test::TestName,
+    text::RenderHtmlPre,
+    util::human_duration,
+};
+
+// Characters that can't go in a URL path segment as they are. '&' and '\''
+// aren't a problem for the URL but would be in an href.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// Percent-encode a string for use as one segment of a URL path.
+pub fn url_segment(s: &str) -> String {
+    utf8_percent_encode(s, PATH_SEGMENT).to_string()
+}
+
+// Percent-encode a relative filesystem path for use in a URL.
+pub fn url_path(path: &FsPath) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            path::Component::Normal(name) => Some(url_segment(&name.to_string_lossy())),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
 
 async fn handle_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "File not found")
@@ -26,19 +71,24 @@ async fn handle_404() -> impl IntoResponse {
 pub struct Ui {
     hostname: String,
     listener: TcpListener,
-    // Path where we'll serve results from the result database. The results are just
-    // served as a directory.
-    result_db: PathBuf,
+    // The current repo's results are just served as a directory, this is also
+    // used to list the history of test cases.
+    database: Arc<Database>,
     state: Arc<UiState>,
 }
 
 impl Ui {
-    pub fn new(hostname: String, listener: TcpListener, result_db: PathBuf, title: String) -> Self {
+    pub fn new(
+        hostname: String,
+        listener: TcpListener,
+        database: Arc<Database>,
+        title: String,
+    ) -> Self {
         Self {
             hostname,
             listener,
-            result_db,
-            state: Arc::new(UiState::new(title)),
+            state: Arc::new(UiState::new(title, database.clone())),
+            database,
         }
     }
 
@@ -57,6 +107,10 @@ impl Ui {
         Ok(self.home_url()? + "/results")
     }
 
+    pub fn history_url_base(&self) -> anyhow::Result<String> {
+        Ok(self.home_url()? + "/history")
+    }
+
     pub fn state(&self) -> Arc<UiState> {
         self.state.clone()
     }
@@ -65,10 +119,12 @@ impl Ui {
         let app = Router::new()
             .route("/", get(home))
             .route("/updates", get(updates))
+            .route("/history/:hash/:test", get(history))
+            .route("/subtests/:hash/:test/:attempt", get(subtests))
             .route("/favicon.ico", get(include_bytes!("../assets/favicon.ico")))
             .nest_service(
                 "/results",
-                ServeDir::new(self.result_db).not_found_service(handle_404.into_service()),
+                ServeDir::new(&self.database.repo_dir).not_found_service(handle_404.into_service()),
             )
             .with_state(self.state);
         select! {
@@ -82,13 +138,15 @@ pub struct UiState {
     // This holds the pre-rendered log & test result buffer with links etc.
     log_html_pre: watch::Sender<String>,
     title: String,
+    database: Arc<Database>,
 }
 
 impl UiState {
-    fn new(title: String) -> Self {
+    fn new(title: String, database: Arc<Database>) -> Self {
         Self {
             log_html_pre: watch::Sender::new("[starting up...]".into()),
             title,
+            database,
         }
     }
 
@@ -156,3 +214,196 @@ async fn home(State(state): State<Arc<UiState>>) -> Html<String> {
     )
     .into()
 }
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Stored runs of a test case, or the response to give if there aren't any.
+async fn read_attempts(state: &UiState, hash: &str, test: &str) -> Result<Vec<Attempt>, Response> {
+    // These get joined onto database paths.
+    if [hash, test]
+        .iter()
+        .any(|s| s.contains('/') || *s == "." || *s == "..")
+    {
+        return Err((StatusCode::BAD_REQUEST, "Bad test case").into_response());
+    }
+    // This reads a bunch of files, keep it off the async threads.
+    let database = state.database.clone();
+    let (hash, test) = (Hash::new(hash), TestName::new(test));
+    let attempts = spawn_blocking(move || database.attempts(&hash, &test))
+        .await
+        .context("joining attempt listing task")
+        .and_then(|result| result);
+    match attempts {
+        Ok(attempts) if attempts.is_empty() => Err(handle_404().await.into_response()),
+        Ok(attempts) => Ok(attempts),
+        Err(err) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Reading result database: {err:#}"),
+        )
+            .into_response()),
+    }
+}
+
+// Lists the stored runs of a test case with links to their outputs.
+async fn history(
+    State(state): State<Arc<UiState>>,
+    Path((hash, test)): Path<(String, String)>,
+) -> Response {
+    let attempts = match read_attempts(&state, &hash, &test).await {
+        Ok(attempts) => attempts,
+        Err(response) => return response,
+    };
+    let now = SystemTime::now();
+    let mut items = String::new();
+    for attempt in attempts.iter().rev() {
+        let Ok(relpath) = attempt.dir.strip_prefix(&state.database.repo_dir) else {
+            continue;
+        };
+        let url = format!("/results/{}", url_path(relpath));
+        let status = attempt
+            .status
+            .as_ref()
+            .map(|s| s.to_string())
+            .unwrap_or("Didn't finish".into());
+        let age = attempt
+            .finished_at
+            .map(|t| {
+                format!(
+                    ", {} ago",
+                    human_duration(now.duration_since(t).unwrap_or_default())
+                )
+            })
+            .unwrap_or_default();
+        let usage = attempt
+            .usage
+            .as_ref()
+            .map(|u| format!(", {u}"))
+            .unwrap_or_default();
+        let subtests = match &attempt.report {
+            Some(report) if !report.subtests.is_empty() => format!(
+                r#" <a href="/subtests/{hash}/{test}/{number}">subtests</a>"#,
+                hash = url_segment(&hash),
+                test = url_segment(&test),
+                number = attempt.number,
+            ),
+            _ => String::new(),
+        };
+        // Under a terminal, everything went to stdout.txt.
+        let stderr = if attempt.terminal_size.is_none() {
+            format!(r#" <a href="{url}/stderr.txt">stderr</a>"#)
+        } else {
+            String::new()
+        };
+        let excerpt = attempt
+            .failure_excerpt
+            .as_ref()
+            .map(|e| format!("<br><code>{}</code>", escape_html(e)))
+            .unwrap_or_default();
+        items += &format!(
+            r#"<li>#{number}{latest}: {status}{age}{usage} - <a href="{url}/stdout.txt">stdout</a>{stderr}{subtests}{excerpt}</li>"#,
+            number = attempt.number,
+            latest = if attempt.is_latest { " (latest)" } else { "" },
+            status = escape_html(&status),
+        );
+        items += "\n";
+    }
+    Html(format!(
+        indoc! {r#"
+        <!DOCTYPE html>
+            <html lang="en">
+            <head>
+                <meta charset="utf-8">
+                <title>{title}</title>
+            </head>
+            <body>
+                <h1>{title}</h1>
+                <ul>
+                {items}
+                </ul>
+            </body>
+        </html>
+    "#},
+        title = escape_html(&format!("{test} at {hash}")),
+        items = items,
+    ))
+    .into_response()
+}
+
+// Lists the subtests of a run that didn't pass.
+async fn subtests(
+    State(state): State<Arc<UiState>>,
+    Path((hash, test, number)): Path<(String, String, u64)>,
+) -> Response {
+    let attempts = match read_attempts(&state, &hash, &test).await {
+        Ok(attempts) => attempts,
+        Err(response) => return response,
+    };
+    let Some(report) = attempts
+        .into_iter()
+        .find(|a| a.number == number)
+        .and_then(|a| a.report)
+    else {
+        return handle_404().await.into_response();
+    };
+    let mut items = String::new();
+    let mut num_failed = 0;
+    for subtest in &report.subtests {
+        let status = match subtest.status {
+            SubtestStatus::Fail => "failed",
+            SubtestStatus::Error => "error",
+            SubtestStatus::Pass | SubtestStatus::Skip => continue,
+        };
+        num_failed += 1;
+        let message = subtest
+            .message
+            .as_ref()
+            .map(|m| format!("<pre>{}</pre>", escape_html(m)))
+            .unwrap_or_default();
+        items += &format!(
+            "<li>{name}: {status}{message}</li>\n",
+            name = escape_html(&subtest.name)
+        );
+    }
+    Html(format!(
+        indoc! {r#"
+        <!DOCTYPE html>
+            <html lang="en">
+            <head>
+                <meta charset="utf-8">
+                <title>{title}</title>
+            </head>
+            <body>
+                <h1>{title}</h1>
+                <p>{num_failed} of {total} subtests failed.</p>
+                <ul>
+                {items}
+                </ul>
+            </body>
+        </html>
+    "#},
+        title = escape_html(&format!("{test} at {hash}, run #{number}")),
+        num_failed = num_failed,
+        total = report.subtests.len(),
+        items = items,
+    ))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_url_path() {
+        assert_eq!(
+            url_path(FsPath::new("abc123/my test#1/attempts/2")),
+            "abc123/my%20test%231/attempts/2"
+        );
+        assert_eq!(url_segment(r#"a/b"&<c>"#), "a%2Fb%22%26%3Cc%3E");
+    }
+}
@@ -0,0 +1,202 @@
+// A GNU make jobserver shared by all the jobs, so that parallel builds in
+// different worktrees don't each assume they've got the whole machine to
+// themselves. See
+// https://www.gnu.org/software/make/manual/html_node/Job-Slots.html
+//
+// This is the anonymous pipe flavour, rather than the named FIFO that newer
+// versions of make use, because older versions of make only understand the
+// pipe.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read as _, Write as _},
+    os::{
+        fd::{AsRawFd as _, RawFd},
+        unix::{fs::OpenOptionsExt as _, process::CommandExt as _},
+    },
+    process,
+};
+
+use anyhow::Context as _;
+use log::{debug, info};
+use nix::{fcntl::OFlag, libc, unistd::pipe2};
+
+// The token is arbitrary, this is what make uses.
+const TOKEN: u8 = b'+';
+
+#[derive(Debug)]
+pub struct Jobserver {
+    read: File,
+    write: File,
+    // The read end again, but non-blocking, for taking back tokens without
+    // getting stuck if someone beats us to them. This is a separate open of
+    // the pipe rather than a dup, because O_NONBLOCK is shared between dups
+    // and the jobs need to block.
+    drain: File,
+    // Each job also gets one implicit slot, this many are shared on top of
+    // that.
+    slots: usize,
+}
+
+impl Jobserver {
+    pub fn new(slots: usize) -> anyhow::Result<Self> {
+        // CLOEXEC so that processes other than the jobs don't get these.
+        let (read, write) = pipe2(OFlag::O_CLOEXEC).context("creating jobserver pipe")?;
+        let drain = File::options()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(format!("/proc/self/fd/{}", read.as_raw_fd()))
+            .context("reopening jobserver pipe")?;
+        let jobserver = Self {
+            read: read.into(),
+            write: write.into(),
+            drain,
+            slots,
+        };
+        jobserver.add_tokens(slots)?;
+        Ok(jobserver)
+    }
+
+    fn add_tokens(&self, n: usize) -> anyhow::Result<()> {
+        (&self.write)
+            .write_all(&vec![TOKEN; n])
+            .context("writing jobserver tokens")
+    }
+
+    // How many tokens are sitting in the pipe, i.e. not held by anyone.
+    pub fn available(&self) -> anyhow::Result<usize> {
+        let mut n: libc::c_int = 0;
+        // SAFETY: FIONREAD writes an int through the pointer.
+        if unsafe { libc::ioctl(self.read.as_raw_fd(), libc::FIONREAD, &mut n) } < 0 {
+            return Err(io::Error::last_os_error()).context("checking jobserver tokens");
+        }
+        Ok(n as usize)
+    }
+
+    // Put the slot count back to where it started. This is only correct when
+    // no jobs are running, in which case any tokens that are missing were
+    // leaked by jobs that crashed or were killed.
+    pub fn reset(&self) -> anyhow::Result<()> {
+        if self.available()? == self.slots {
+            return Ok(());
+        }
+        // Take whatever's there, without waiting for anything that isn't.
+        let mut available = 0;
+        let mut buf = [0; 256];
+        loop {
+            match (&self.drain).read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => available += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err).context("draining jobserver tokens"),
+            }
+        }
+        self.add_tokens(self.slots)?;
+        if available < self.slots {
+            info!(
+                "Recovered {} leaked jobserver slots",
+                self.slots - available
+            );
+        } else {
+            // Someone put back tokens they didn't take. Weird but whatever.
+            debug!(
+                "Removed {} surplus jobserver tokens",
+                available - self.slots
+            );
+        }
+        Ok(())
+    }
+
+    // Whatever flags we were given ourselves still apply, make goes with the
+    // last -j and --jobserver-auth. Total parallelism is informational for
+    // make, it's the tokens that actually control it.
+    fn makeflags(&self, inherited: Option<OsString>) -> OsString {
+        let mut makeflags = inherited.unwrap_or_default();
+        if !makeflags.is_empty() {
+            makeflags.push(" ");
+        }
+        makeflags.push(format!(
+            "-j{} --jobserver-auth={},{}",
+            self.slots + 1,
+            self.read.as_raw_fd(),
+            self.write.as_raw_fd()
+        ));
+        makeflags
+    }
+
+    // Set up the command to be a client of the jobserver.
+    pub fn add_command(&self, cmd: &mut process::Command) {
+        let fds: [RawFd; 2] = [self.read.as_raw_fd(), self.write.as_raw_fd()];
+        cmd.env("MAKEFLAGS", self.makeflags(env::var_os("MAKEFLAGS")));
+        // SAFETY: This runs between fork and exec, where only async-signal-safe
+        // stuff is allowed. fcntl is.
+        unsafe {
+            cmd.pre_exec(move || {
+                // Only the job should inherit the pipe, so we only clear
+                // CLOEXEC in its process.
+                for fd in fds {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_reset_slots() {
+        let jobserver = Jobserver::new(3).unwrap();
+        assert_eq!(jobserver.available().unwrap(), 3);
+
+        // Someone takes a token and never gives it back.
+        (&jobserver.read).read_exact(&mut [0]).unwrap();
+        assert_eq!(jobserver.available().unwrap(), 2);
+        jobserver.reset().unwrap();
+        assert_eq!(jobserver.available().unwrap(), 3);
+
+        // Someone gives back too many.
+        jobserver.add_tokens(2).unwrap();
+        jobserver.reset().unwrap();
+        assert_eq!(jobserver.available().unwrap(), 3);
+    }
+
+    #[test]
+    fn should_keep_inherited_makeflags() {
+        let jobserver = Jobserver::new(2).unwrap();
+        let auth = format!(
+            "-j3 --jobserver-auth={},{}",
+            jobserver.read.as_raw_fd(),
+            jobserver.write.as_raw_fd()
+        );
+        assert_eq!(jobserver.makeflags(None), OsString::from(&auth));
+        assert_eq!(
+            jobserver.makeflags(Some("-k --no-print-directory".into())),
+            OsString::from(format!("-k --no-print-directory {auth}"))
+        );
+    }
+
+    #[test]
+    fn should_share_slots_with_make() {
+        let jobserver = Jobserver::new(2).unwrap();
+        let mut cmd = process::Command::new("bash");
+        // Take a token via the fds advertised in MAKEFLAGS.
+        cmd.args([
+            "-c",
+            r#"fds=${MAKEFLAGS#*--jobserver-auth=}; dd bs=1 count=1 status=none <&${fds%,*}"#,
+        ]);
+        jobserver.add_command(&mut cmd);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(output.stdout, b"+");
+        assert_eq!(jobserver.available().unwrap(), 1);
+    }
+}
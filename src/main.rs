@@ -1,47 +1,63 @@
 use anyhow::{anyhow, bail, Context};
-use clap::{arg, Parser as _, Subcommand, ValueEnum};
+use cgroup::CgroupRoot;
+use clap::{Parser as _, Subcommand, ValueEnum};
 use config::{Config, ParsedConfig};
 use dag::{Dag, GraphNode as _};
-use database::{Database, DatabaseOutput};
+use database::{Database, DatabaseOutput, GcPolicy, RepoId};
+use fingerprint::Fingerprints;
 use futures::future::join_all;
 use futures::StreamExt;
 use git::{Commit, PersistentWorktree, TempWorktree};
 use http::Ui;
-use log::{debug, info};
+use jobserver::Jobserver;
+use log::{debug, error, info};
 use nix::sys::utsname::uname;
+use report::{compare_metrics, Report};
 use resource::Pools;
 use resource::{Resource, ResourceKey};
 use std::borrow::Borrow as _;
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::fmt::Display;
 use std::io::{stdout, Stdout};
-use std::path::{absolute, PathBuf};
+use std::os::fd::AsFd as _;
+use std::path::{absolute, Path, PathBuf};
 use std::pin::pin;
 use std::process::Stdio;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{env, fmt, fs, str};
 use tempfile::TempDir;
 use test::{
-    base_job_env, Manager, TestCase, TestCaseId, TestJob, TestJobBuilder, TestJobOutput, TestName,
+    base_job_env, commit_lookups, Manager, TestCase, TestCaseId, TestJob, TestJobBuilder,
+    TestJobOutput, TestName,
 };
-use test::{Test, TestResult};
+use test::{JobUsage, Test, TestStatus};
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::spawn_blocking;
+use tokio::time::MissedTickBehavior;
 use tokio_util::sync::CancellationToken;
-use util::{DisplayablePathBuf, ErrGroup};
+use util::{human_bytes, human_duration, poll_lock, DisplayablePathBuf, ErrGroup};
 
 use crate::git::Worktree;
+use crate::pty::TerminalSize;
 use crate::terminal::TerminalSizeWatcher;
 
+mod cgroup;
 mod config;
 mod dag;
 mod database;
+mod fingerprint;
 mod git;
 mod http;
+mod jobserver;
 mod process;
+mod pty;
+mod report;
 mod resource;
+mod sandbox;
 mod terminal;
 mod test;
 mod text;
@@ -148,12 +164,17 @@ struct GetArgs {
     /// Which output from the job do we want?
     #[arg(default_value_t = GetOutput::Stdout)]
     output: GetOutput,
+    /// For artifacts, the path of the artifact relative to the directory the
+    /// test ran in. If omitted, you get the directory containing all the
+    /// test's artifacts.
+    name: Option<PathBuf>,
 }
 
 #[derive(Clone, ValueEnum, Debug)]
 enum GetOutput {
     Stdout,
     Stderr,
+    Artifact,
 }
 
 impl Display for GetOutput {
@@ -164,6 +185,7 @@ impl Display for GetOutput {
             match self {
                 Self::Stdout => "stdout",
                 Self::Stderr => "stderr",
+                Self::Artifact => "artifact",
             }
         )
     }
@@ -178,6 +200,71 @@ enum Command {
     Test(TestArgs),
     /// EXPERIMENTAL: Get the path of a test's output in the result database.
     Get(GetArgs),
+    /// Delete old results from the database, according to max_db_size and
+    /// max_age_days in the config.
+    Gc,
+    /// Inspect the result database.
+    Db(DbArgs),
+    /// Explain whether the result database has a result for a test at a
+    /// revision that will be reused, and if not, why not.
+    ExplainCache(ExplainCacheArgs),
+    /// List the stored runs of a test at a revision, including ones that
+    /// errored or were cancelled. The one that will be reused (if it's a valid
+    /// result) is marked with a "*".
+    History(HistoryArgs),
+}
+
+impl Command {
+    // Whether the command needs to know the tests' cache hashes. Fingerprinting
+    // can be slow so it's skipped for the others.
+    fn needs_fingerprints(&self) -> bool {
+        !matches!(self, Command::Gc | Command::Db(_))
+    }
+
+    // Whether the command runs test jobs.
+    fn runs_jobs(&self) -> bool {
+        matches!(self, Command::Watch(_) | Command::Test(_) | Command::Get(_))
+    }
+
+    // Whether the command looks at the results for the current repo, as opposed
+    // to just the database as a whole.
+    fn uses_results(&self) -> bool {
+        !matches!(
+            self,
+            Command::Db(DbArgs {
+                command: DbCommand::ListRepos
+            })
+        )
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revision to look up. Any git revspec is fine.
+    rev: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExplainCacheArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revision to look up. Any git revspec is fine.
+    rev: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct DbArgs {
+    #[command(subcommand)]
+    command: DbCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// List the repositories that have results in the database. The current
+    /// one is marked with a "*".
+    ListRepos,
 }
 
 // Kitchen-sink object for global shit.
@@ -186,6 +273,8 @@ struct Env {
     repo: Arc<git::PersistentWorktree>,
     database: Arc<Database>,
     worktree_builder: WorktreeBuilder,
+    // Only set up for commands that run jobs.
+    jobserver: Option<Arc<Jobserver>>,
 }
 
 // Fallback instead of https://github.com/Stebalien/tempfile/pull/308
@@ -218,6 +307,9 @@ async fn watch_loop(
 
     let size_watcher = TerminalSizeWatcher::new()?;
     let mut resizes = pin!(size_watcher.resizes());
+    // Keeps the time estimates ticking down while jobs are running.
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
     loop {
         select! {
@@ -243,6 +335,9 @@ async fn watch_loop(
             _ = resizes.next() => {
                 status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
+            _ = ticks.tick(), if status_tracker.is_busy() => {
+                status_tracker.repaint(&size_watcher.size()).context("error painting status to stdout")?;
+            },
             _ =  cancellation_token.cancelled() => {
                 info!("Got shutdown signal, terminating jobs and waiting");
                 test_manager.cancel_running().await.context("cancelling tests")?;
@@ -270,7 +365,7 @@ async fn watch(
     let ui = Ui::new(
         watch_args.hostname.clone(),
         listener,
-        env.database.base_dir.clone(),
+        env.database.clone(),
         format!(
             "Limmat | {}",
             absolute(env.repo.path())
@@ -281,18 +376,54 @@ async fn watch(
         ),
     );
     let result_url_base = ui.result_url_base()?;
+    let history_url_base = ui.history_url_base()?;
     let home_url = ui.home_url()?;
     let ui_state = ui.state();
     eg.spawn(ui.serve(cancellation_token.child_token()));
 
+    if let Some(interval) = env.config.gc_interval {
+        eg.spawn(gc_loop(
+            cancellation_token.child_token(),
+            env.database.clone(),
+            env.config.gc_policy.clone(),
+            interval,
+        ));
+    }
+
+    // Past runs are used to guess how long the tests will take.
+    let mut durations = ui::DurationStats::default();
+    let database = env.database.clone();
+    match spawn_blocking(move || database.past_usage()).await {
+        Ok(Ok(usages)) => {
+            for (test_name, usage) in usages {
+                durations.record(&test_name, &usage);
+            }
+        }
+        Ok(Err(err)) => error!("Reading past durations failed: {err:#}"),
+        Err(err) => error!("Reading past durations task failed: {err}"),
+    }
+
     // Set up the test manager, which is the weirdly-scoped god-object that
     // orchestrates test jobs.
-    let test_manager = Arc::new(Manager::new(
-        env.repo.clone(),
-        env.database,
-        env.config.resource_pools.clone(),
-        env.config.tests,
-    ));
+    let test_manager = Arc::new(
+        Manager::new(
+            env.repo.clone(),
+            env.database,
+            env.config.resource_pools.clone(),
+            env.config.tests,
+        )
+        .with_jobserver(env.jobserver.clone()),
+    );
+
+    if let Some(interval) = env.config.fingerprint_interval {
+        eg.spawn(fingerprint_loop(
+            cancellation_token.child_token(),
+            test_manager.clone(),
+            env.config.raw.clone(),
+            env.repo.path().to_owned(),
+            interval,
+        ));
+    }
 
     // Set up the status tracker, which shows the user what's going on in the terminal.
     let status_tracker = ui::StatusTracker::new(
@@ -300,7 +431,9 @@ async fn watch(
         stdout(),
         ui_state,
         result_url_base,
+        history_url_base,
         home_url,
+        durations,
     );
 
     // Kick off creation of the worktrees that the test manager will run jobs in.
@@ -349,6 +482,65 @@ async fn watch(
     end_result
 }
 
+// Periodically prune the database in the background. Failures are logged
+// rather than bringing down the whole program.
+async fn gc_loop(
+    cancellation_token: CancellationToken,
+    database: Arc<Database>,
+    policy: GcPolicy,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            _ = ticks.tick() => (),
+        }
+        let database = database.clone();
+        let policy = policy.clone();
+        match spawn_blocking(move || database.gc(&policy)).await {
+            Ok(Ok(report)) => info!("Database GC: {report}"),
+            Ok(Err(err)) => error!("Database GC failed: {err:#}"),
+            Err(err) => error!("Database GC task failed: {err}"),
+        }
+    }
+}
+
+// Periodically re-check the cache_inputs and cache_key_commands, restarting
+// the tests if they changed. Like gc_loop, failures are just logged.
+async fn fingerprint_loop(
+    cancellation_token: CancellationToken,
+    test_manager: Arc<Manager<PersistentWorktree>>,
+    config: Arc<Config>,
+    repo_dir: PathBuf,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate, but we already did this at startup.
+    ticks.tick().await;
+    loop {
+        select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            _ = ticks.tick() => (),
+        }
+        let config = config.clone();
+        let repo_dir = repo_dir.clone();
+        let tests =
+            spawn_blocking(move || config.parse_tests(&config.fingerprints(&repo_dir)?)).await;
+        match tests {
+            Ok(Ok(tests)) => match test_manager.set_tests(tests) {
+                Ok(true) => info!("Cache inputs changed, restarted tests"),
+                Ok(false) => (),
+                Err(err) => error!("Updating tests for new cache inputs failed: {err:#}"),
+            },
+            Ok(Err(err)) => error!("Checking cache inputs failed: {err:#}"),
+            Err(err) => error!("Cache input checking task failed: {err}"),
+        }
+    }
+}
+
 async fn ensure_job_success(
     database: Arc<Database>,
     resource_pools: Arc<Pools>,
@@ -366,16 +558,64 @@ async fn ensure_job_success(
 struct OneshotOutput {}
 
 impl TestJobOutput for OneshotOutput {
+    fn claim(&mut self) -> anyhow::Result<bool> {
+        Ok(true)
+    }
     fn stdout(&mut self) -> anyhow::Result<Stdio> {
         Ok(Stdio::inherit())
     }
     fn stderr(&mut self) -> anyhow::Result<Stdio> {
         Ok(Stdio::inherit())
     }
-    fn set_result(&mut self, result: &TestResult) -> anyhow::Result<()> {
-        eprintln!("Job result: {result:?}");
+    fn terminal(&mut self, _size: TerminalSize) -> anyhow::Result<fs::File> {
+        Ok(stdout()
+            .as_fd()
+            .try_clone_to_owned()
+            .context("duplicating stdout")?
+            .into())
+    }
+    fn set_status(&mut self, status: &TestStatus) -> anyhow::Result<()> {
+        eprintln!("Job result: {status:?}");
         Ok(())
     }
+    fn set_usage(&mut self, _usage: &JobUsage) {}
+    fn set_result(&mut self, report: &Report) {
+        if let Some(summary) = &report.summary {
+            eprintln!("Job summary: {summary}");
+        }
+    }
+    fn set_failure_excerpt(&mut self, _excerpt: &str) {
+        // The output went straight to the terminal, the user has already seen it.
+    }
+    fn set_parent_metrics(&mut self, _metrics: &BTreeMap<String, f64>) {}
+    fn new_attempt(&mut self) {}
+    fn attempt(&mut self) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+    fn artifact_dest(&mut self, _relpath: &Path) -> anyhow::Result<Option<PathBuf>> {
+        // The job ran in the main tree, the artifacts are already right there.
+        Ok(None)
+    }
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+    fn stdout_path(&self) -> Option<PathBuf> {
+        None
+    }
+    fn stderr_path(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+// Look up what the tests' cache policies need to know about the commit before
+// we can create TestCases for it.
+async fn resolve_commit(env: &Env, commit: Commit) -> anyhow::Result<Commit> {
+    let mut commits = env
+        .repo
+        .resolve_commits(vec![commit], &commit_lookups(env.config.tests.nodes()))
+        .await
+        .context("looking up commit details for cache keys")?;
+    Ok(commits.pop().unwrap())
 }
 
 // Run a set of tests at a given version, in worktrees, in parallel, unless
@@ -393,6 +633,7 @@ async fn ensure_tests_run(
     );
 
     let job_env = Arc::new(base_job_env(env.repo.path()));
+    let cgroups = CgroupRoot::try_create();
 
     // Get the graph of tests we need to run as dependencies.
     // This is kinda inefficient: we're building a new Dag based on a subset of
@@ -405,7 +646,9 @@ async fn ensure_tests_run(
     //    new graph and instead just logicall remove the nodes we don't need.
     let jobs = Dag::new(
         // TODO: Would be nice to have an _into thing so we can avoid this clone.
-        tests.map(|t| TestCase::new(rev.clone(), t.clone())),
+        tests
+            .map(|t| TestCase::new(rev.clone(), t.clone()))
+            .collect::<anyhow::Result<Vec<_>>>()?,
     )
     .context("setting up dependency test graph")?
     .bottom_up()
@@ -416,9 +659,10 @@ async fn ensure_tests_run(
                 .child_ids() // This gives the TestCaseIds of dependency jobs.
                 .iter()
                 .map(|tc_id| {
+                    let dep_job: &TestJob<_> = &jobs[tc_id.borrow()];
                     (
-                        test_case.test.name.clone(),
-                        jobs[tc_id.borrow()].subscribe_completion(),
+                        dep_job.test_case().test.name.clone(),
+                        dep_job.subscribe_completion(),
                     )
                 })
                 .collect();
@@ -431,6 +675,8 @@ async fn ensure_tests_run(
                 job_env.clone(),
                 wait_for,
             )
+            .with_cgroups(cgroups.clone())
+            .with_jobserver(env.jobserver.clone())
             .build();
             jobs.insert(test_case.id().borrow().to_owned(), job);
             Ok(jobs)
@@ -489,6 +735,7 @@ async fn test(
         .await
         .context("failed to look up HEAD commit")?
         .ok_or(anyhow!("no HEAD commit - repo empty?"))?;
+    let head = resolve_commit(&env, head).await?;
 
     // Only need worktrees for the tests that needs worktrees.
     let dep_tests: Vec<&Arc<Test>> = env
@@ -507,8 +754,29 @@ async fn test(
     }
 
     let test = env.config.tests.node(&test_name).unwrap();
-    let test_case = TestCase::new(head.clone(), test.clone());
+    let test_case = TestCase::new(head.clone(), test.clone())?;
     let mut needs_resources = test_case.test.needs_resources.clone();
+    // The dependencies were run separately, so we have to tell the job where
+    // their outputs went.
+    // Holding on to the entries stops anyone deleting the dependency outputs
+    // while we're running. If someone's busy with one, wait for them.
+    let mut dep_entries = Vec::new();
+    for dep_name in &test.depends_on {
+        let dep = env.config.tests.node(dep_name).unwrap();
+        let dep_case = TestCase::new(head.clone(), dep.clone())?;
+        let entry = poll_lock(&cancellation_token, || env.database.latest_entry(&dep_case))
+            .await?
+            .ok_or_else(|| anyhow!("canceled waiting for output of dependency {dep_name}"))?;
+        // Somebody else might have re-run it since ensure_tests_run.
+        if !entry.status().satisfies_dependents() {
+            bail!("dependency {dep_name} unsuccessful: {}", entry.status());
+        }
+        dep_entries.push((dep_name.clone(), entry));
+    }
+    let dep_dirs = dep_entries
+        .iter()
+        .map(|(dep_name, entry)| (dep_name.clone(), entry.path().to_owned()))
+        .collect::<Vec<_>>();
     let job = TestJobBuilder::new(
         cancellation_token.clone(),
         test_case,
@@ -516,6 +784,9 @@ async fn test(
         Arc::new(base_job_env(env.repo.path())),
         Vec::new(), // wait_for
     )
+    .with_dep_dirs(dep_dirs.clone())
+    .with_cgroups(CgroupRoot::try_create())
+    .with_jobserver(env.jobserver.clone())
     .build();
     // Doesn't need a worktree, it's gonna do it live and direct in the main tree.
     needs_resources.remove(&ResourceKey::Worktree);
@@ -537,6 +808,7 @@ async fn get(
         .await
         .context("error looking up commit")?
         .ok_or_else(|| anyhow!("revision {:?} not found", get_args.test))?;
+    let rev = resolve_commit(&env, rev).await?;
 
     if get_args.run {
         let tests: Vec<&Arc<Test>> = env
@@ -559,7 +831,7 @@ async fn get(
         .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
     let db_entry = env
         .database
-        .lookup_result(&TestCase::new(rev.clone(), test.clone()))
+        .lookup_result(&TestCase::new(rev.clone(), test.clone())?)
         .context("looking up result in database")?
         .ok_or_else(|| {
             anyhow!(
@@ -571,7 +843,176 @@ async fn get(
         })?;
     match get_args.output {
         GetOutput::Stdout => println!("{}", db_entry.stdout_path().display()),
-        GetOutput::Stderr => println!("{}", db_entry.stderr_path().display()),
+        GetOutput::Stderr => {
+            if let Some(size) = db_entry.terminal_size() {
+                bail!("job ran under a {size} terminal, its stderr is in the stdout output");
+            }
+            println!("{}", db_entry.stderr_path().display())
+        }
+        GetOutput::Artifact => {
+            let path = match get_args.name {
+                Some(name) => db_entry.artifact_path(&name)?,
+                None => db_entry.artifacts_dir(),
+            };
+            if !path.exists() {
+                bail!("no such artifact ({})", path.display());
+            }
+            println!("{}", path.display())
+        }
+    }
+    Ok(())
+}
+
+async fn explain_cache(env: Env, args: ExplainCacheArgs) -> anyhow::Result<()> {
+    let rev = env
+        .repo
+        .rev_parse(&args.rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {:?} not found", args.rev))?;
+    let rev = resolve_commit(&env, rev).await?;
+    let test_name = TestName::new(args.test);
+    let test = env
+        .config
+        .tests
+        .node(&test_name)
+        .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
+    let explanation = env
+        .database
+        .explain_lookup(&TestCase::new(rev, test.clone())?)
+        .context("looking up result in database")?;
+    println!("{explanation}");
+    Ok(())
+}
+
+async fn history(env: Env, args: HistoryArgs) -> anyhow::Result<()> {
+    let rev = env
+        .repo
+        .rev_parse(&args.rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {:?} not found", args.rev))?;
+    let rev = resolve_commit(&env, rev).await?;
+    let test_name = TestName::new(args.test);
+    let test = env
+        .config
+        .tests
+        .node(&test_name)
+        .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
+    let test_case = TestCase::new(rev, test.clone())?;
+    let attempts = env
+        .database
+        .attempts(test_case.storage_hash(), &test_name)
+        .context("reading result database")?;
+    if attempts.is_empty() {
+        println!("No runs stored");
+    }
+    let now = SystemTime::now();
+    for attempt in attempts {
+        let latest = if attempt.is_latest { "* " } else { "  " };
+        let status = attempt
+            .status
+            .map(|s| s.to_string())
+            .unwrap_or("Didn't finish".into());
+        let age = attempt
+            .finished_at
+            .map(|t| {
+                format!(
+                    ", {} ago",
+                    human_duration(now.duration_since(t).unwrap_or_default())
+                )
+            })
+            .unwrap_or_default();
+        let usage = attempt.usage.map(|u| format!(", {u}")).unwrap_or_default();
+        let terminal = attempt
+            .terminal_size
+            .map(|s| format!(", {s} terminal"))
+            .unwrap_or_default();
+        println!(
+            "{latest}#{}: {status} ({}{age}{usage}{terminal})",
+            attempt.number,
+            attempt.dir.display()
+        );
+        if let Some(excerpt) = attempt.failure_excerpt {
+            println!("      {excerpt}");
+        }
+        if let (Some(report), Some(parent_metrics)) = (&attempt.report, &attempt.parent_metrics) {
+            let deltas = compare_metrics(&test.metrics, &report.metrics, parent_metrics);
+            if !deltas.is_empty() {
+                let regressed = if deltas.iter().any(|d| d.regressed) {
+                    "regressed: "
+                } else {
+                    ""
+                };
+                let deltas: Vec<String> = deltas.iter().map(|d| d.to_string()).collect();
+                println!("      {regressed}{}", deltas.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn gc(env: Env) -> anyhow::Result<()> {
+    let policy = env.config.gc_policy;
+    if policy.is_unlimited() {
+        bail!("nothing to do: neither max_db_size nor max_age_days is configured");
+    }
+    let database = env.database;
+    let report = spawn_blocking(move || database.gc(&policy))
+        .await
+        .context("joining GC task")??;
+    println!("{report}");
+    Ok(())
+}
+
+// Move any results from before the database was split up by repository into
+// the current repo's section of the database, if they belong to it.
+async fn migrate_legacy_db(
+    database: &Arc<Database>,
+    repo: &PersistentWorktree,
+) -> anyhow::Result<()> {
+    let db = database.clone();
+    let hashes = spawn_blocking(move || db.legacy_hashes())
+        .await
+        .context("joining legacy database scan")??;
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    let ours = repo
+        .existing_objects(&hashes)
+        .await
+        .context("checking for legacy database entries")?;
+    if ours.is_empty() {
+        return Ok(());
+    }
+    let db = database.clone();
+    let num_moved = spawn_blocking(move || db.migrate_legacy(&ours))
+        .await
+        .context("joining legacy database migration")?
+        .context("migrating legacy database entries")?;
+    if num_moved != 0 {
+        info!("Migrated {num_moved} legacy database entries");
+    }
+    Ok(())
+}
+
+fn list_repos(env: Env) -> anyhow::Result<()> {
+    for repo in env.database.list_repos()? {
+        let current = if repo.dir == env.database.repo_dir {
+            "* "
+        } else {
+            "  "
+        };
+        let id = repo
+            .id
+            .map(|id| id.to_string())
+            .unwrap_or("<unknown>".into());
+        println!(
+            "{current}{id}: {} entries, {} ({})",
+            repo.num_entries,
+            human_bytes(repo.size),
+            repo.dir.display()
+        );
     }
     Ok(())
 }
@@ -600,29 +1041,62 @@ async fn main() -> anyhow::Result<()> {
         fs::read_to_string(&find_config(&args.config)?).context("couldn't read config")?;
     debug!("config:\n{}", &config_content);
     let config: Config = toml::from_str(&config_content).context("couldn't parse config")?;
-    let config = ParsedConfig::from(config)?;
+    let fingerprints = if args.command.needs_fingerprints() {
+        config.fingerprints(Path::new(&args.repo))?
+    } else {
+        Fingerprints::new()
+    };
+    let config = ParsedConfig::from(config, &fingerprints)?;
 
     let repo = git::PersistentWorktree {
         path: args.repo.to_owned().into(),
     };
     // Check repo is valid.
-    repo.git_common_dir()
+    let git_common_dir = repo
+        .git_common_dir()
         .await
         .context(format!("opening repo {}", args.repo))?;
+    let repo_id = match config.project {
+        Some(ref project) => RepoId::Project(project.clone()),
+        // This can be relative to the repo.
+        None => RepoId::GitDir(
+            fs::canonicalize(repo.path.join(git_common_dir))
+                .context("getting canonical path of git dir")?,
+        ),
+    };
+    debug!("repo ID: {repo_id:?}");
+    let database = Arc::new(Database::create_or_open(&args.result_db, &repo_id)?);
+    if args.command.uses_results() {
+        migrate_legacy_db(&database, &repo).await?;
+    }
+
+    let jobserver = match config.jobserver_slots {
+        Some(slots) if args.command.runs_jobs() => Some(Arc::new(
+            Jobserver::new(slots).context("setting up jobserver")?,
+        )),
+        _ => None,
+    };
 
     let env = Env {
         config,
         repo: Arc::new(repo),
-        database: Arc::new(Database::create_or_open(&args.result_db)?),
+        database,
         worktree_builder: WorktreeBuilder {
             prefix: args.worktree_prefix.into(),
             parent_dir: args.worktree_dir.into(),
         },
+        jobserver,
     };
 
     match args.command {
         Command::Watch(watch_args) => watch(env, cancellation_token, watch_args).await,
         Command::Test(ref test_args) => test(env, cancellation_token, test_args).await,
         Command::Get(get_args) => get(env, cancellation_token, get_args).await,
+        Command::Gc => gc(env).await,
+        Command::Db(db_args) => match db_args.command {
+            DbCommand::ListRepos => list_repos(env),
+        },
+        Command::ExplainCache(args) => explain_cache(env, args).await,
+        Command::History(args) => history(env, args).await,
     }
 }
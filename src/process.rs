@@ -1,8 +1,12 @@
 use anyhow::{anyhow, Context};
+use nix::libc;
+use nix::unistd::Pid;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::process::ExitStatusExt as _;
 use std::process::{Command as SyncCommand, ExitStatus, Output};
+use std::time::Duration;
+use std::{io, mem};
 use tokio::process::Command;
 
 pub trait ExitStatusExt {
@@ -76,3 +80,46 @@ impl SyncCommandExt for SyncCommand {
         self.output().context("couldn't run command")?.ok()
     }
 }
+
+// What a process used, as reported by wait4.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rusage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    // Peak resident set size in bytes. This is the largest of the process and
+    // any descendants it waited for, not the total.
+    pub max_rss: u64,
+}
+
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+// Block until the child process exits, reap it and return its resource usage.
+// Nothing else must be trying to reap it (so don't use this on children
+// spawned by tokio).
+pub fn wait4(pid: Pid) -> io::Result<(ExitStatus, Rusage)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, all-zeroes is a valid value.
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+    loop {
+        // SAFETY: The pointers are to live locals of the right types.
+        let ret = unsafe { libc::wait4(pid.as_raw(), &mut status, 0, &mut rusage) };
+        if ret != -1 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok((
+        ExitStatus::from_raw(status),
+        Rusage {
+            user_time: timeval_duration(rusage.ru_utime),
+            system_time: timeval_duration(rusage.ru_stime),
+            // Linux reports this in KiB.
+            max_rss: rusage.ru_maxrss as u64 * 1024,
+        },
+    ))
+}
@@ -0,0 +1,153 @@
+// Running jobs under a pseudo-terminal, for tools that only produce their
+// interactive-style output (colours, progress bars, etc) when they think
+// someone is watching.
+
+use std::{
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, stdout, Read as _, Write as _},
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _, IntoRawFd as _},
+        unix::fs::OpenOptionsExt as _,
+    },
+};
+
+use anyhow::Context as _;
+use crossterm::{terminal, tty::IsTty as _};
+use nix::{
+    fcntl::{fcntl, FcntlArg, OFlag},
+    libc,
+    pty::{grantpt, posix_openpt, ptsname_r, unlockpt},
+    sys::termios::{tcgetattr, tcsetattr, OutputFlags, SetArg},
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::unix::AsyncFd;
+
+// What we tell the job if Limmat isn't running in a terminal itself.
+const DEFAULT_SIZE: TerminalSize = TerminalSize { cols: 80, rows: 24 };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl TerminalSize {
+    // The size of the terminal Limmat is running in, so that the job's output
+    // looks like it would if you ran it yourself.
+    pub fn current() -> Self {
+        if !stdout().is_tty() {
+            return DEFAULT_SIZE;
+        }
+        terminal::size()
+            .map(|(cols, rows)| Self { cols, rows })
+            .unwrap_or(DEFAULT_SIZE)
+    }
+}
+
+impl Display for TerminalSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.cols, self.rows)
+    }
+}
+
+pub struct Pty {
+    master: AsyncFd<File>,
+}
+
+impl Pty {
+    // Returns the pty, and the job's end of it. That's CLOEXEC like the
+    // master, otherwise other jobs started in the meantime would inherit it and
+    // we wouldn't find out when this one is finished with it.
+    pub fn open(size: TerminalSize) -> anyhow::Result<(Self, File)> {
+        let master = posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY | OFlag::O_CLOEXEC)
+            .context("opening pty")?;
+        grantpt(&master).context("granting pty")?;
+        unlockpt(&master).context("unlocking pty")?;
+        let slave_path = ptsname_r(&master).context("getting pty name")?;
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+            .open(&slave_path)
+            .with_context(|| format!("opening {slave_path:?}"))?;
+        let winsize = libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // SAFETY: TIOCSWINSZ reads a winsize through the pointer.
+        if unsafe { libc::ioctl(slave.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } < 0 {
+            return Err(io::Error::last_os_error()).context("setting pty size");
+        }
+        // By default the terminal turns "\n" into "\r\n", which is what you
+        // want on a screen but not in a log file.
+        let mut termios = tcgetattr(&slave).context("getting pty attributes")?;
+        termios.output_flags.remove(OutputFlags::ONLCR);
+        tcsetattr(&slave, SetArg::TCSANOW, &termios).context("setting pty attributes")?;
+        // SAFETY: We own the fd, it came from the PtyMaster.
+        let master = unsafe { File::from_raw_fd(master.into_raw_fd()) };
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .context("making pty non-blocking")?;
+        Ok((
+            Self {
+                master: AsyncFd::new(master).context("registering pty")?,
+            },
+            slave,
+        ))
+    }
+
+    // Copy everything the job writes to the terminal into output. Returns once
+    // nothing has the job's end open anymore.
+    pub async fn copy_to(self, mut output: File) -> anyhow::Result<()> {
+        let mut buf = [0; 4096];
+        loop {
+            let mut guard = self.master.readable().await?;
+            match guard.try_io(|master| master.get_ref().read(&mut buf)) {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(n)) => output
+                    .write_all(&buf[..n])
+                    .context("writing terminal output")?,
+                // This is how Linux says the other end got closed.
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => return Ok(()),
+                Ok(Err(err)) => return Err(err).context("reading terminal output"),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn should_look_like_a_terminal() {
+        let (pty, slave) = Pty::open(TerminalSize {
+            cols: 123,
+            rows: 45,
+        })
+        .unwrap();
+        let mut cmd = process::Command::new("bash");
+        cmd.args([
+            "-c",
+            "[ -t 1 ] && [ -t 2 ] && stty size <&1 && echo err >&2",
+        ])
+        .stdin(process::Stdio::null())
+        .stdout(slave.try_clone().unwrap())
+        .stderr(slave);
+        let mut child = cmd.spawn().unwrap();
+        // Otherwise we'd still have the job's end open.
+        drop(cmd);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("output");
+        pty.copy_to(File::create(&path).unwrap()).await.unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "45 123\nerr\n");
+    }
+}
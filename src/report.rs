@@ -0,0 +1,658 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use lazy_static::lazy_static;
+use regex::{Regex, RegexSet};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Name of the variable that tells a job where to write its report.
+pub const RESULT_FILE_ENV_VAR: &str = "LIMMAT_RESULT_FILE";
+
+// What a test command can tell us about its result beyond the exit code, by
+// writing JSON to $LIMMAT_RESULT_FILE. Everything is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Report {
+    // One-liner to show in the UI instead of the exit code, like "3/120
+    // kselftests failed".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subtests: Vec<Subtest>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, f64>,
+    // Pointers to stuff outside of Limmat, like a CI dashboard.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<Link>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Subtest {
+    pub name: String,
+    pub status: SubtestStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtestStatus {
+    Pass,
+    Fail,
+    Skip,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    pub title: String,
+    pub url: String,
+}
+
+impl Report {
+    // Add subtests that were found some other way than the JSON report. If
+    // there's no summary, and some of them failed, make one up.
+    pub fn add_subtests(&mut self, subtests: Vec<Subtest>) {
+        self.subtests.extend(subtests);
+        let failed = self
+            .subtests
+            .iter()
+            .filter(|s| matches!(s.status, SubtestStatus::Fail | SubtestStatus::Error))
+            .count();
+        if self.summary.is_none() && failed != 0 {
+            self.summary = Some(format!("{failed}/{} subtests failed", self.subtests.len()));
+        }
+    }
+
+    // Read the report the job left at path. If it didn't leave one, that's
+    // fine, but if it left garbage that's an error.
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("reading {path:?}")),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("parsing ${RESULT_FILE_ENV_VAR}"))
+    }
+}
+
+// A report in a standard format that the test command leaves behind, from the
+// test config.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ReportFile {
+    pub format: ReportFormat,
+    /// Relative to the directory the job runs in.
+    pub path: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    /// JUnit XML. Each testcase element is a subtest.
+    Junit,
+    /// Test Anything Protocol. Each top-level test line is a subtest.
+    Tap,
+}
+
+impl ReportFile {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let path = Path::new(&self.path);
+        if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            bail!(
+                "report path {:?} must be relative and inside the job's directory",
+                self.path
+            );
+        }
+        Ok(())
+    }
+
+    // Parse the subtests out of the report, if the job left it in dir.
+    pub fn read_subtests(&self, dir: &Path) -> anyhow::Result<Option<Vec<Subtest>>> {
+        // The job could have made it a symlink to anywhere, so check where it
+        // really is.
+        let path = match dir.join(&self.path).canonicalize() {
+            Ok(path) => path,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("resolving {:?}", self.path)),
+        };
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("resolving {dir:?}"))?;
+        if !path.starts_with(&dir) {
+            bail!(
+                "report {:?} resolves to {path:?}, outside {dir:?}",
+                self.path
+            );
+        }
+        let content = fs::read_to_string(&path).with_context(|| format!("reading {path:?}"))?;
+        let subtests = match self.format {
+            ReportFormat::Junit => parse_junit(&content),
+            ReportFormat::Tap => Ok(parse_tap(&content)),
+        };
+        subtests
+            .map(Some)
+            .with_context(|| format!("parsing report {:?}", self.path))
+    }
+}
+
+fn parse_junit(content: &str) -> anyhow::Result<Vec<Subtest>> {
+    let doc = roxmltree::Document::parse(content)?;
+    let mut subtests = Vec::new();
+    // Don't care how the suites are nested, just find all the cases.
+    for case in doc.descendants().filter(|n| n.has_tag_name("testcase")) {
+        let name = case
+            .attribute("name")
+            .ok_or_else(|| anyhow!("testcase with no name"))?;
+        let name = match case.attribute("classname") {
+            Some(classname) if !classname.is_empty() => format!("{classname}.{name}"),
+            _ => name.to_owned(),
+        };
+        // A case with no outcome element passed.
+        let outcome = case.children().find_map(|child| {
+            let status = match child.tag_name().name() {
+                "failure" => SubtestStatus::Fail,
+                "error" => SubtestStatus::Error,
+                "skipped" => SubtestStatus::Skip,
+                _ => return None,
+            };
+            let message = child
+                .attribute("message")
+                .or(child.text())
+                .map(|m| m.trim().to_owned())
+                .filter(|m| !m.is_empty());
+            Some((status, message))
+        });
+        let (status, message) = outcome.unwrap_or((SubtestStatus::Pass, None));
+        subtests.push(Subtest {
+            name,
+            status,
+            message,
+        });
+    }
+    Ok(subtests)
+}
+
+lazy_static! {
+    // Like "not ok 3 - some description # SKIP some reason".
+    static ref TAP_TEST_REGEX: Regex =
+        Regex::new(r"^(not )?ok\b\s*(\d+)?\s*(?:-\s*)?([^#]*?)\s*(?:#\s*(.*))?$").unwrap();
+    static ref TAP_DIRECTIVE_REGEX: Regex = Regex::new(r"(?i)^(skip|todo)\S*\s*(.*)$").unwrap();
+}
+
+fn parse_tap(content: &str) -> Vec<Subtest> {
+    let mut subtests = Vec::new();
+    // Nested subtests are indented, those are just ignored.
+    for line in content.lines() {
+        if let Some(reason) = line.strip_prefix("Bail out!") {
+            subtests.push(Subtest {
+                name: "Bail out!".into(),
+                status: SubtestStatus::Error,
+                message: Some(reason.trim().to_owned()).filter(|r| !r.is_empty()),
+            });
+            continue;
+        }
+        let Some(captures) = TAP_TEST_REGEX.captures(line) else {
+            continue;
+        };
+        let ok = captures.get(1).is_none();
+        // Numbers are optional too, if there's nothing else just go by position.
+        let name = match captures.get(3).map_or("", |m| m.as_str()) {
+            "" => captures
+                .get(2)
+                .map_or((subtests.len() + 1).to_string(), |m| m.as_str().to_owned()),
+            description => description.to_owned(),
+        };
+        let comment = captures.get(4).map_or("", |m| m.as_str().trim());
+        let (status, message) = match TAP_DIRECTIVE_REGEX.captures(comment) {
+            // TODO tests are expected to fail, so they don't count either way.
+            Some(directive) => (SubtestStatus::Skip, directive.get(2).unwrap().as_str()),
+            None if ok => (SubtestStatus::Pass, comment),
+            None => (SubtestStatus::Fail, comment),
+        };
+        subtests.push(Subtest {
+            name,
+            status,
+            message: Some(message.to_owned()).filter(|m| !m.is_empty()),
+        });
+    }
+    subtests
+}
+
+// A metric that a test produces, from the test config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSpec {
+    pub name: String,
+    // If set, the metric is scraped from stdout with this. The first capture
+    // group of the last match is the value.
+    pub regex: Option<String>,
+    // Thresholds for flagging regressions, as fractions of the parent
+    // commit's value.
+    pub max_increase: Option<f64>,
+    pub max_decrease: Option<f64>,
+}
+
+// The thresholds are checked for NaN when parsing the config.
+impl Eq for MetricSpec {}
+
+impl MetricSpec {
+    fn parse(&self, value: &str) -> anyhow::Result<f64> {
+        value
+            .trim()
+            .parse()
+            .with_context(|| format!("metric {:?}: {value:?} isn't a number", self.name))
+    }
+}
+
+// Find the metrics that have a regex in the job's stdout. Each one's value
+// comes from its last match, metrics that don't match anywhere are left out.
+// One that can't be read doesn't stop the others, so there's a result for
+// each.
+pub fn scrape_metrics(
+    specs: &[MetricSpec],
+    stdout: &Path,
+) -> anyhow::Result<Vec<(String, anyhow::Result<f64>)>> {
+    let mut results = Vec::new();
+    let mut scrapers = Vec::new();
+    for spec in specs {
+        let Some(regex) = &spec.regex else {
+            continue;
+        };
+        match Regex::new(regex) {
+            Ok(regex) => scrapers.push((spec, regex, None)),
+            Err(err) => results.push((
+                spec.name.clone(),
+                Err(anyhow!(err).context(format!("bad regex for metric {:?}", spec.name))),
+            )),
+        }
+    }
+    if scrapers.is_empty() {
+        return Ok(results);
+    }
+    let file = File::open(stdout).with_context(|| format!("opening {stdout:?}"))?;
+    scan_lines(BufReader::new(file), stdout, |line| {
+        for (_, regex, last) in &mut scrapers {
+            if let Some(captures) = regex.captures_iter(line).last() {
+                let value = captures.get(1).unwrap_or(captures.get(0).unwrap());
+                *last = Some(value.as_str().to_owned());
+            }
+        }
+        false
+    })?;
+    for (spec, _, last) in scrapers {
+        if let Some(value) = last {
+            results.push((spec.name.clone(), spec.parse(&value)));
+        }
+    }
+    Ok(results)
+}
+
+// How a metric changed compared with the parent commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricDelta<'a> {
+    pub name: &'a str,
+    pub value: f64,
+    pub parent_value: f64,
+    pub regressed: bool,
+}
+
+impl MetricDelta<'_> {
+    // Relative change, None if the parent was zero so there's no sensible
+    // answer.
+    pub fn change(&self) -> Option<f64> {
+        (self.parent_value != 0.0)
+            .then(|| (self.value - self.parent_value) / self.parent_value.abs())
+    }
+}
+
+impl Display for MetricDelta<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.value)?;
+        match self.change() {
+            Some(change) => write!(f, " ({:+.1}%)", change * 100.0),
+            None => write!(f, " ({:+})", self.value - self.parent_value),
+        }
+    }
+}
+
+// Compare the metrics that both commits have. Regressions are judged
+// according to specs, metrics that aren't in there are never regressions.
+pub fn compare_metrics<'a>(
+    specs: &[MetricSpec],
+    metrics: &'a BTreeMap<String, f64>,
+    parent_metrics: &BTreeMap<String, f64>,
+) -> Vec<MetricDelta<'a>> {
+    metrics
+        .iter()
+        .filter_map(|(name, &value)| {
+            let parent_value = *parent_metrics.get(name)?;
+            let mut delta = MetricDelta {
+                name,
+                value,
+                parent_value,
+                regressed: false,
+            };
+            if let (Some(spec), Some(change)) =
+                (specs.iter().find(|s| &s.name == name), delta.change())
+            {
+                delta.regressed = spec.max_increase.is_some_and(|max| change > max)
+                    || spec.max_decrease.is_some_and(|max| -change > max);
+            }
+            Some(delta)
+        })
+        .collect()
+}
+
+// Find the first line in the outputs (checked in order) that matches any of
+// the patterns, for a one-line explanation of why the job failed.
+pub fn failure_excerpt(patterns: &[String], outputs: &[PathBuf]) -> anyhow::Result<Option<String>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let regexes = RegexSet::new(patterns).context("bad failure pattern")?;
+    for path in outputs {
+        let file = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).with_context(|| format!("opening {path:?}")),
+        };
+        let mut excerpt = None;
+        scan_lines(file, path, |line| {
+            if regexes.is_match(line) {
+                excerpt = Some(line.trim().to_owned());
+            }
+            excerpt.is_some()
+        })?;
+        if excerpt.is_some() {
+            return Ok(excerpt);
+        }
+    }
+    Ok(None)
+}
+
+// Call f with each line of a job's output until it returns true. The output
+// could be huge, so it's read a line at a time.
+fn scan_lines(
+    mut file: impl BufRead,
+    path: &Path,
+    mut f: impl FnMut(&str) -> bool,
+) -> anyhow::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if file
+            .read_until(b'\n', &mut line)
+            .with_context(|| format!("reading {path:?}"))?
+            == 0
+        {
+            return Ok(());
+        }
+        // Output isn't necessarily UTF-8, but what we want from it should be.
+        // Jobs run under a terminal will probably have coloured their output,
+        // get rid of that so the patterns don't need to care.
+        let line = strip_ansi_escapes::strip(&line);
+        let line = String::from_utf8_lossy(&line);
+        if f(line.trim_end_matches(['\n', '\r'])) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn should_read_report() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        assert_eq!(Report::read(&path).unwrap(), None);
+
+        fs::write(
+            &path,
+            r#"{
+                "summary": "1/2 failed",
+                "subtests": [
+                    {"name": "foo", "status": "pass"},
+                    {"name": "bar", "status": "fail", "message": "oh no"}
+                ],
+                "metrics": {"runtime_ms": 12.5},
+                "links": [{"title": "dashboard", "url": "http://example.com"}]
+            }"#,
+        )
+        .unwrap();
+        let report = Report::read(&path).unwrap().unwrap();
+        assert_eq!(report.summary.as_deref(), Some("1/2 failed"));
+        assert_eq!(report.subtests.len(), 2);
+        assert_eq!(report.subtests[1].status, SubtestStatus::Fail);
+        assert_eq!(report.subtests[1].message.as_deref(), Some("oh no"));
+        assert_eq!(report.metrics["runtime_ms"], 12.5);
+        assert_eq!(report.links[0].url, "http://example.com");
+
+        fs::write(&path, r#"{"summray": "typo"}"#).unwrap();
+        assert!(Report::read(&path).is_err());
+    }
+
+    #[test]
+    fn should_parse_junit() {
+        let subtests = parse_junit(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <testsuites>
+              <testsuite name="suite">
+                <testcase classname="mm" name="hugetlb"><failure message="timed out"/></testcase>
+                <testcase name="tcp_ao"/>
+                <testcase classname="net" name="udp"><skipped/></testcase>
+                <testsuite name="nested">
+                  <testcase name="boom"><error>segfault</error></testcase>
+                </testsuite>
+              </testsuite>
+            </testsuites>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            subtests,
+            vec![
+                Subtest {
+                    name: "mm.hugetlb".into(),
+                    status: SubtestStatus::Fail,
+                    message: Some("timed out".into())
+                },
+                Subtest {
+                    name: "tcp_ao".into(),
+                    status: SubtestStatus::Pass,
+                    message: None
+                },
+                Subtest {
+                    name: "net.udp".into(),
+                    status: SubtestStatus::Skip,
+                    message: None
+                },
+                Subtest {
+                    name: "boom".into(),
+                    status: SubtestStatus::Error,
+                    message: Some("segfault".into())
+                },
+            ]
+        );
+        assert!(parse_junit("<testsuite>").is_err());
+    }
+
+    #[test]
+    fn should_parse_tap() {
+        let subtests = parse_tap(indoc::indoc! {"
+            TAP version 13
+            1..5
+            ok 1 - foo
+            not ok 2 - bar # timed out
+            # Subtest: nested
+                not ok 1 - ignored
+            ok 3 baz # SKIP not supported
+            not ok 4 # TODO fix this
+            ok
+            Bail out! no more
+        "});
+        let got: Vec<_> = subtests
+            .iter()
+            .map(|s| (s.name.as_str(), s.status, s.message.as_deref()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("foo", SubtestStatus::Pass, None),
+                ("bar", SubtestStatus::Fail, Some("timed out")),
+                ("baz", SubtestStatus::Skip, Some("not supported")),
+                ("4", SubtestStatus::Skip, Some("fix this")),
+                ("5", SubtestStatus::Pass, None),
+                ("Bail out!", SubtestStatus::Error, Some("no more")),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_only_read_report_inside_dir() {
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret.tap"), "ok 1 - secret\n").unwrap();
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("report.tap"), "ok 1 - foo\n").unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out")).unwrap();
+        let report_file = |path: &str| ReportFile {
+            format: ReportFormat::Tap,
+            path: path.into(),
+        };
+
+        let subtests = report_file("report.tap")
+            .read_subtests(dir.path())
+            .unwrap()
+            .unwrap();
+        assert_eq!(subtests[0].name, "foo");
+        assert_eq!(
+            report_file("missing.tap")
+                .read_subtests(dir.path())
+                .unwrap(),
+            None
+        );
+        assert!(report_file("out/secret.tap")
+            .read_subtests(dir.path())
+            .is_err());
+        assert!(report_file("../secret.tap").validate().is_err());
+        assert!(report_file("/etc/passwd").validate().is_err());
+    }
+
+    fn metric(name: &str, regex: Option<&str>, max_increase: Option<f64>) -> MetricSpec {
+        MetricSpec {
+            name: name.into(),
+            regex: regex.map(|r| r.into()),
+            max_increase,
+            max_decrease: None,
+        }
+    }
+
+    #[test]
+    fn should_scrape_metrics() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("stdout.txt");
+        fs::write(
+            &path,
+            "size: 1\n\x1b[1msize: 2\x1b[0m\nspeed: fast\ncount: 3 count: 4\r\n",
+        )
+        .unwrap();
+        let specs = [
+            metric("size", Some(r"size: (\d+)$"), None),
+            metric("speed", Some(r"speed: (\w+)"), None),
+            metric("count", Some(r"count: (\d+)"), None),
+            metric("missing", Some(r"missing: (\d+)"), None),
+            metric("from_report", None, None),
+        ];
+        let scraped: Vec<_> = scrape_metrics(&specs, &path)
+            .unwrap()
+            .into_iter()
+            .map(|(name, value)| (name, value.ok()))
+            .collect();
+        assert_eq!(
+            scraped,
+            vec![
+                ("size".to_owned(), Some(2.0)),
+                ("speed".to_owned(), None),
+                ("count".to_owned(), Some(4.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_compare_metrics() {
+        let specs = [
+            metric("boot_time", None, Some(0.05)),
+            metric("zero", None, Some(0.05)),
+        ];
+        let metrics = BTreeMap::from([
+            ("boot_time".to_owned(), 110.0),
+            ("size".to_owned(), 200.0),
+            ("zero".to_owned(), 3.0),
+            ("new".to_owned(), 1.0),
+        ]);
+        let parent_metrics = BTreeMap::from([
+            ("boot_time".to_owned(), 100.0),
+            ("size".to_owned(), 400.0),
+            ("zero".to_owned(), 0.0),
+        ]);
+        let deltas = compare_metrics(&specs, &metrics, &parent_metrics);
+        let got: Vec<_> = deltas
+            .iter()
+            .map(|d| (d.to_string(), d.regressed))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("boot_time 110 (+10.0%)".to_owned(), true),
+                // No threshold.
+                ("size 200 (-50.0%)".to_owned(), false),
+                // Can't tell how big a change that is.
+                ("zero 3 (+3)".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_find_failure_excerpt() {
+        let dir = TempDir::new().unwrap();
+        let stderr = dir.path().join("stderr.txt");
+        let stdout = dir.path().join("stdout.txt");
+        fs::write(
+            &stderr,
+            "   Compiling foo\n  error: unused variable `x` at foo.rs:12\nerror: aborting\n",
+        )
+        .unwrap();
+        // Coloured, so ^FAIL only matches once the escapes are gone.
+        fs::write(&stdout, "\x1b[1;31mFAIL\x1b[0m: test_bar\n").unwrap();
+        let outputs = [stderr, stdout, dir.path().join("nonexistent")];
+        let patterns = |ps: &[&str]| ps.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(failure_excerpt(&[], &outputs).unwrap(), None);
+        assert_eq!(
+            failure_excerpt(&patterns(&["^FAIL", "error:"]), &outputs).unwrap(),
+            Some("error: unused variable `x` at foo.rs:12".into())
+        );
+        assert_eq!(
+            failure_excerpt(&patterns(&["^FAIL"]), &outputs).unwrap(),
+            Some("FAIL: test_bar".into())
+        );
+        assert_eq!(
+            failure_excerpt(&patterns(&["panicked"]), &outputs).unwrap(),
+            None
+        );
+        assert!(failure_excerpt(&patterns(&["("]), &outputs).is_err());
+    }
+}
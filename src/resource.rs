@@ -78,7 +78,10 @@ impl Pools {
     //
     // https://github.com/rust-lang/rust-clippy/issues/13075
     #[expect(clippy::await_holding_lock)]
-    pub async fn get(&self, wants: impl IntoIterator<Item = (ResourceKey, usize)>) -> Resources {
+    pub async fn get(
+        &self,
+        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+    ) -> Resources<'_> {
         let wants: Vec<(ResourceKey, usize)> = wants.into_iter().collect();
         let mut guard = self.resources.lock();
         loop {
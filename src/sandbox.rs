@@ -0,0 +1,561 @@
+// Optional isolation for jobs using Linux namespaces, so that a test that's
+// cached by_tree can actually be held to only depending on the tree. Jobs get
+// their own user and mount namespaces (and usually network namespace), set up
+// between fork and exec. This needs unprivileged user namespaces, which most
+// distros allow.
+
+use std::{
+    ffi::{CStr, CString},
+    io::{self, Write as _},
+    iter, mem,
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt as _, process::CommandExt as _},
+    },
+    path::{Path, PathBuf},
+    process, ptr,
+};
+
+use anyhow::{bail, Context as _};
+use log::debug;
+use nix::{
+    errno::Errno,
+    fcntl::{open, OFlag},
+    libc,
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::{
+        stat::Mode,
+        statvfs::{statvfs, FsFlags},
+    },
+    unistd::{chdir, getgid, getuid, mkdir, write},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const TMP: &CStr = c"/tmp";
+
+// From the test config.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Sandbox {
+    #[serde(default)]
+    /// Let the job use the network. Otherwise it gets its own network
+    /// namespace where only the loopback interface exists.
+    pub network: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Absolute paths the job can read but not write, e.g. "/", including
+    /// anything mounted under them. The directory the job runs in, its result
+    /// file and /dev stay writable regardless.
+    pub readonly_paths: Vec<PathBuf>,
+    #[serde(default = "default_private_tmp")]
+    /// Give the job its own empty /tmp, which is thrown away when it exits.
+    pub private_tmp: bool,
+}
+
+fn default_private_tmp() -> bool {
+    true
+}
+
+// Something the job still needs to see after the readonly paths and the
+// private /tmp have been mounted over the top of it.
+struct KeptPath {
+    path: CString,
+    // Directories to create in the private /tmp so there's somewhere to mount
+    // the path, outermost first.
+    mkdirs: Vec<CString>,
+    writable: bool,
+}
+
+impl Sandbox {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for path in &self.readonly_paths {
+            if !path.is_absolute() {
+                bail!("sandbox readonly path {path:?} must be absolute");
+            }
+        }
+        Ok(())
+    }
+
+    // Set up the command to run in the sandbox. The writable paths are ones the
+    // job must be able to write to whatever the config says, and the readable
+    // ones are things it gets told about in its environment, which it can read
+    // but not write.
+    pub fn add_command(
+        &self,
+        cmd: &mut process::Command,
+        writable: &[&Path],
+        readable: &[&Path],
+    ) -> anyhow::Result<()> {
+        // Everything the child needs is prepared here, since it mustn't
+        // allocate between fork and exec.
+        let readonly_paths = self
+            .readonly_paths
+            .iter()
+            .map(|path| {
+                path.canonicalize()
+                    .with_context(|| format!("sandbox readonly path {path:?}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !readonly_paths.is_empty() {
+            check_mount_setattr()?;
+        }
+        // Device nodes can't be opened for writing on a read-only mount, and
+        // everything expects to be able to write to /dev/null.
+        let dev = Path::new("/dev");
+        let keep_dev = readonly_paths
+            .iter()
+            .any(|path| dev.starts_with(path) && path != dev);
+        let readonly_paths: Vec<CString> = readonly_paths.iter().map(|p| c_path(p)).collect();
+        let mut kept = Vec::new();
+        for (path, writable) in writable
+            .iter()
+            .map(|p| (p, true))
+            .chain(readable.iter().map(|p| (p, false)))
+            .chain(iter::once((&dev, true)).filter(|_| keep_dev))
+        {
+            let path = match path.canonicalize() {
+                Ok(path) => path,
+                // The job will find out if something it expected isn't there.
+                Err(err) if !writable => {
+                    debug!("Not keeping {path:?} visible in sandbox: {err}");
+                    continue;
+                }
+                Err(err) => return Err(err).with_context(|| format!("canonicalizing {path:?}")),
+            };
+            let mkdirs = if self.private_tmp {
+                let mut dirs: Vec<CString> = path
+                    .ancestors()
+                    .filter(|a| a.starts_with("/tmp") && a != &Path::new("/tmp"))
+                    .map(c_path)
+                    .collect();
+                dirs.reverse();
+                dirs
+            } else {
+                Vec::new()
+            };
+            kept.push(KeptPath {
+                path: c_path(&path),
+                mkdirs,
+                writable,
+            });
+        }
+        let uid_map = format!("{uid} {uid} 1", uid = getuid());
+        let gid_map = format!("{gid} {gid} 1", gid = getgid());
+        let mut flags = CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS;
+        if !self.network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        let network = self.network;
+        let private_tmp = self.private_tmp;
+        let mut fds: Vec<RawFd> = vec![-1; kept.len()];
+        // SAFETY: This runs between fork and exec, where only async-signal-safe
+        // stuff is allowed. Everything here is a syscall, or formatting into a
+        // buffer on the stack.
+        unsafe {
+            cmd.pre_exec(move || {
+                unshare(flags)?;
+                // Map ourselves to ourselves, so that files the job creates
+                // belong to the user as normal. We still have all capabilities
+                // in the namespace until exec, which is all we need.
+                write_file(c"/proc/self/setgroups", b"deny")?;
+                write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+                write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+                // Don't let our mounts propagate back out.
+                mount(
+                    None::<&CStr>,
+                    c"/",
+                    None::<&CStr>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&CStr>,
+                )?;
+                // Grab hold of the stuff we need to keep before it gets
+                // covered up.
+                for (fd, kept) in fds.iter_mut().zip(&kept) {
+                    *fd = open(
+                        kept.path.as_c_str(),
+                        OFlag::O_PATH | OFlag::O_CLOEXEC,
+                        Mode::empty(),
+                    )?;
+                }
+                for path in &readonly_paths {
+                    make_readonly(path)?;
+                }
+                if private_tmp {
+                    mount(
+                        Some(c"tmpfs"),
+                        TMP,
+                        Some(c"tmpfs"),
+                        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                        None::<&CStr>,
+                    )?;
+                }
+                for (&fd, kept) in fds.iter().zip(&kept) {
+                    let fd = OwnedFd::from_raw_fd(fd);
+                    for dir in &kept.mkdirs {
+                        match mkdir(dir.as_c_str(), Mode::S_IRWXU) {
+                            Ok(()) | Err(Errno::EEXIST) => (),
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                    let mut buf = [0; 32];
+                    restore_mount(
+                        fd_path(fd.as_raw_fd(), &mut buf)?,
+                        &kept.path,
+                        kept.writable,
+                    )?;
+                }
+                // The working directory was set before we got here, so it's
+                // still on the old mounts. Look it up again so that the job
+                // can't get around the sandbox via "..".
+                let mut buf = [0u8; libc::PATH_MAX as usize];
+                chdir(getcwd_into(&mut buf)?)?;
+                if !network {
+                    loopback_up()?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+fn c_path(path: &Path) -> CString {
+    // Paths can't contain NUL bytes.
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn write_file(path: &CStr, content: &[u8]) -> io::Result<()> {
+    let fd = open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    // SAFETY: We just opened it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    write(&fd, content)?;
+    Ok(())
+}
+
+// Formats the magic /proc path for the fd, without allocating.
+fn fd_path(fd: RawFd, buf: &mut [u8; 32]) -> io::Result<&CStr> {
+    let mut cursor = &mut buf[..];
+    write!(cursor, "/proc/self/fd/{fd}\0")?;
+    CStr::from_bytes_until_nul(buf).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+// Like nix's getcwd but without allocating.
+fn getcwd_into(buf: &mut [u8]) -> io::Result<&CStr> {
+    // SAFETY: getcwd writes a NUL-terminated path into the buffer, up to its
+    // length.
+    if unsafe { libc::getcwd(buf.as_mut_ptr().cast(), buf.len()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    CStr::from_bytes_until_nul(buf).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+// Make the path read-only, along with everything mounted underneath it. Paths
+// are bind-mounted onto themselves first so that this doesn't affect the rest
+// of the filesystem they're on. Except for "/": lookups start from the root of
+// the old mount rather than whatever's mounted on top of it, so that one gets
+// changed directly. That's fine since it's our namespace's own copy of the
+// mounts.
+fn make_readonly(path: &CStr) -> nix::Result<()> {
+    if path != c"/" {
+        bind_mount(path, path)?;
+    }
+    set_readonly_recursive(path, true)
+}
+
+// Mount the source on the target, with the given writability regardless of
+// what the source had. This is for putting things back after they've been
+// covered up.
+fn restore_mount(source: &CStr, target: &CStr, writable: bool) -> nix::Result<()> {
+    bind_mount(source, target)?;
+    if !writable {
+        return set_readonly_recursive(target, true);
+    }
+    // The source might have been made read-only by make_readonly. If
+    // something under there was read-only to begin with, this fails for the
+    // lot and we can only fix up the top mount.
+    match set_readonly_recursive(target, false) {
+        Err(Errno::EPERM) => (),
+        result => return result,
+    }
+    if statvfs(target)?.flags().contains(FsFlags::ST_RDONLY) {
+        match remount(target, false) {
+            // It was read-only to begin with, so that's how it stays.
+            Ok(()) | Err(Errno::EPERM) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// The argument to mount_setattr, which nix and libc don't have yet.
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+fn mount_setattr(target: &CStr, flags: libc::c_int, attr: Option<&MountAttr>) -> nix::Result<()> {
+    let (attr, size) = match attr {
+        Some(attr) => (attr as *const MountAttr, mem::size_of::<MountAttr>()),
+        None => (ptr::null(), 0),
+    };
+    // SAFETY: The kernel reads at most size bytes of the attr.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            flags,
+            attr,
+            size,
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+// Unlike remounting, this covers the whole tree of mounts in one go.
+fn set_readonly_recursive(target: &CStr, readonly: bool) -> nix::Result<()> {
+    let (attr_set, attr_clr) = if readonly {
+        (MOUNT_ATTR_RDONLY, 0)
+    } else {
+        (0, MOUNT_ATTR_RDONLY)
+    };
+    mount_setattr(
+        target,
+        libc::AT_RECURSIVE,
+        Some(&MountAttr {
+            attr_set,
+            attr_clr,
+            propagation: 0,
+            userns_fd: 0,
+        }),
+    )
+}
+
+// mount_setattr only exists since Linux 5.12. Better to find that out here than
+// from a mysterious error when the job starts.
+fn check_mount_setattr() -> anyhow::Result<()> {
+    // This is invalid, so the kernel rejects it without doing anything, unless
+    // it doesn't know the syscall.
+    match mount_setattr(c"", 0, None) {
+        Err(Errno::ENOSYS) => bail!("sandbox readonly_paths need Linux 5.12 or later"),
+        _ => Ok(()),
+    }
+}
+
+fn bind_mount(source: &CStr, target: &CStr) -> nix::Result<()> {
+    mount(
+        Some(source),
+        target,
+        None::<&CStr>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&CStr>,
+    )
+}
+
+// Changing a bind mount's writability is a separate remount, which must keep
+// any flags that are locked because they came from a more privileged mount
+// namespace.
+fn remount(target: &CStr, readonly: bool) -> nix::Result<()> {
+    let flags = statvfs(target)?.flags();
+    let mut ms_flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND;
+    if readonly {
+        ms_flags |= MsFlags::MS_RDONLY;
+    }
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if flags.contains(fs_flag) {
+            ms_flags |= ms_flag;
+        }
+    }
+    mount(
+        None::<&CStr>,
+        target,
+        None::<&CStr>,
+        ms_flags,
+        None::<&CStr>,
+    )
+}
+
+// A new network namespace's loopback interface starts off down. Lots of tests
+// want to talk to themselves over localhost, so bring it up.
+fn loopback_up() -> io::Result<()> {
+    // SAFETY: Plain syscalls on a socket we own, with a zeroed ifreq which is
+    // valid.
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sock = OwnedFd::from_raw_fd(sock);
+        let mut ifreq: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut ifreq) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ifreq.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        if libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &ifreq) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use log::warn;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn run(sandbox: &Sandbox, dir: &Path, script: &str) -> process::Output {
+        let mut cmd = process::Command::new("bash");
+        cmd.args(["-c", script]).current_dir(dir);
+        sandbox.add_command(&mut cmd, &[dir], &[]).unwrap();
+        cmd.output().unwrap()
+    }
+
+    #[test_log::test]
+    fn should_isolate() {
+        let sandbox = Sandbox {
+            network: false,
+            readonly_paths: vec!["/".into()],
+            private_tmp: true,
+        };
+        let job_dir = TempDir::new().unwrap();
+        if !run(&sandbox, job_dir.path(), "true").status.success() {
+            warn!("Skipping test, can't create namespaces");
+            return;
+        }
+        // Not in /tmp, so that it's the readonly path that stops the job
+        // writing here, not the private /tmp.
+        let outside_dir = TempDir::new_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let outside_file = outside_dir.path().join("file");
+        let output = run(
+            &sandbox,
+            job_dir.path(),
+            &format!(
+                r#"
+                set -eux
+                echo hello > file
+                echo hello > /dev/null
+                ! echo hello > {outside_file:?}
+                [ "$(ls -A /tmp)" == {job_dir_name:?} ]
+                [ "$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ')" == lo ]
+                cd .. && [ "$(ls -A)" == {job_dir_name:?} ]
+                "#,
+                job_dir_name = job_dir.path().file_name().unwrap(),
+            ),
+        );
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(
+            fs::read_to_string(job_dir.path().join("file")).unwrap(),
+            "hello\n"
+        );
+        assert!(!outside_file.exists());
+    }
+
+    #[test_log::test]
+    fn should_make_nested_mounts_readonly() {
+        let sandbox = Sandbox {
+            network: true,
+            readonly_paths: vec![],
+            private_tmp: false,
+        };
+        let job_dir = TempDir::new().unwrap();
+        if !run(&sandbox, job_dir.path(), "true").status.success() {
+            warn!("Skipping test, can't create namespaces");
+            return;
+        }
+        let outside_dir = TempDir::new_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let nested_dir = outside_dir.path().join("nested");
+        fs::create_dir(&nested_dir).unwrap();
+        let sandbox = Sandbox {
+            readonly_paths: vec![outside_dir.path().into()],
+            ..sandbox
+        };
+        let mut cmd = process::Command::new("bash");
+        cmd.args([
+            "-c",
+            &format!(
+                r#"
+                set -eux
+                [ "$(stat -f -c %T {nested_dir:?})" == tmpfs ]
+                ! echo hello > {file:?}
+                "#,
+                file = nested_dir.join("file"),
+            ),
+        ])
+        .current_dir(job_dir.path());
+        // Before the sandbox gets set up, mount something inside the readonly
+        // path, in a namespace of our own so we don't need privileges.
+        let uid_map = format!("{uid} {uid} 1", uid = getuid());
+        let gid_map = format!("{gid} {gid} 1", gid = getgid());
+        let nested = c_path(&nested_dir);
+        // SAFETY: Same deal as in Sandbox::add_command.
+        unsafe {
+            cmd.pre_exec(move || {
+                unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)?;
+                write_file(c"/proc/self/setgroups", b"deny")?;
+                write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+                write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+                mount(
+                    None::<&CStr>,
+                    c"/",
+                    None::<&CStr>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&CStr>,
+                )?;
+                mount(
+                    Some(c"tmpfs"),
+                    nested.as_c_str(),
+                    Some(c"tmpfs"),
+                    MsFlags::empty(),
+                    None::<&CStr>,
+                )?;
+                Ok(())
+            });
+        }
+        sandbox
+            .add_command(&mut cmd, &[job_dir.path()], &[])
+            .unwrap();
+        let output = cmd.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test_log::test]
+    fn should_allow_network() {
+        let sandbox = Sandbox {
+            network: true,
+            readonly_paths: Vec::new(),
+            private_tmp: false,
+        };
+        let job_dir = TempDir::new().unwrap();
+        if !run(&sandbox, job_dir.path(), "true").status.success() {
+            warn!("Skipping test, can't create namespaces");
+            return;
+        }
+        let net_ns = fs::read_link("/proc/self/ns/net").unwrap();
+        let output = run(
+            &sandbox,
+            job_dir.path(),
+            &format!("[ \"$(readlink /proc/self/ns/net)\" == {net_ns:?} ]"),
+        );
+        assert!(output.status.success(), "{output:?}");
+    }
+}
@@ -1,14 +1,17 @@
 use core::{fmt, fmt::Display};
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::{OsStr, OsString},
     fmt::{Debug, Formatter},
-    path::Path,
+    fs::File,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
     pin::pin,
-    process::Stdio,
+    process::{ExitStatus, Stdio},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{anyhow, bail, Context};
@@ -21,45 +24,146 @@ use nix::unistd::Pid;
 use parking_lot::Mutex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use tokio::{
-    process::{Child, Command},
+    process::Command,
     select,
     sync::{broadcast, watch},
-    time::sleep,
+    task::spawn_blocking,
+    time::{sleep, timeout},
 };
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
 
 use crate::{
+    cgroup::{CgroupRoot, JobCgroup, Limits},
     dag::{Dag, GraphNode},
-    database::{Database, DatabaseOutput},
-    git::{Commit, CommitHash, Hash, Worktree},
-    process::ExitStatusExt as _,
+    database::{copy_recursive, Database, DatabaseOutput},
+    git::{Commit, CommitHash, CommitLookups, Hash, Worktree},
+    jobserver::Jobserver,
+    process::{self, ExitStatusExt as _, Rusage},
+    pty::{Pty, TerminalSize},
+    report::{
+        failure_excerpt, scrape_metrics, MetricSpec, Report, ReportFile, RESULT_FILE_ENV_VAR,
+    },
     resource::{Pools, ResourceKey, Resources},
-    util::ResultExt,
+    sandbox::Sandbox,
+    util::{hex_string, human_bytes, poll_lock, precise_duration, ResultExt},
 };
 
-#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+// Once the job is dead, how long to keep waiting for the last of its terminal
+// output, in case something else got hold of the terminal.
+const TERMINAL_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum CachePolicy {
     NoCaching,
     ByCommit,
     ByTree,
+    /// Like by_tree, but only the content at these paths (files or
+    /// directories, relative to the root of the repository) matters.
+    ByPaths(Vec<String>),
+    /// Only the diff matters (per `git patch-id --stable`), so the result can
+    /// be reused when the commit gets rebased or reworded.
+    ByPatchId,
+    /// Only the commit message matters.
+    ByMessage,
 }
 
 impl CachePolicy {
     // Figure out the hash that should be used to store a result in the
-    // database, if it should be stored at all
-    pub fn cache_hash(&self, commit: &Commit) -> Option<Hash> {
-        match self {
+    // database, if it should be stored at all. Some policies need the commit
+    // to have gone through Worktree::resolve_commits, see commit_lookups.
+    pub fn cache_hash(&self, commit: &Commit) -> anyhow::Result<Option<Hash>> {
+        Ok(match self {
             CachePolicy::NoCaching => None::<Hash>,
             CachePolicy::ByCommit => Some(commit.hash.clone().into()),
             CachePolicy::ByTree => Some(commit.tree.clone().into()),
+            CachePolicy::ByPaths(paths) => {
+                let mut hasher = Sha256::new();
+                for path in paths.iter().sorted() {
+                    let object = commit.path_objects.get(path).ok_or_else(|| {
+                        anyhow!("path {path:?} not resolved for commit {}", commit.hash)
+                    })?;
+                    hasher.update(format!(
+                        "{path}\0{}\n",
+                        object.as_ref().map_or("missing", |h| h.as_ref())
+                    ));
+                }
+                Some(Hash::new(hex_string(&hasher.finalize())))
+            }
+            CachePolicy::ByPatchId => Some(
+                commit
+                    .patch_id
+                    .clone()
+                    .ok_or_else(|| anyhow!("patch ID not resolved for commit {}", commit.hash))?,
+            ),
+            CachePolicy::ByMessage => {
+                Some(commit.message_hash.clone().ok_or_else(|| {
+                    anyhow!("message hash not resolved for commit {}", commit.hash)
+                })?)
+            }
+        })
+    }
+}
+
+// What needs to be looked up with Worktree::resolve_commits before creating
+// TestCases for these tests.
+pub fn commit_lookups<'a>(tests: impl IntoIterator<Item = &'a Arc<Test>>) -> CommitLookups {
+    let mut lookups = CommitLookups::default();
+    for test in tests {
+        match &test.cache_policy {
+            CachePolicy::ByPaths(paths) => {
+                for path in paths {
+                    if !lookups.paths.contains(path) {
+                        lookups.paths.push(path.clone());
+                    }
+                }
+            }
+            CachePolicy::ByPatchId => lookups.patch_id = true,
+            CachePolicy::ByMessage => lookups.message = true,
+            CachePolicy::NoCaching | CachePolicy::ByCommit | CachePolicy::ByTree => (),
         }
     }
+    lookups
+}
+
+// Hex SHA-256 of the canonical JSON of a test's effective configuration (see
+// config::Test::parse). Results are only reused if this matches.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(from = "StoredConfigHash")]
+pub struct ConfigHash(String);
+
+impl ConfigHash {
+    pub fn of(config_json: &serde_json::Value) -> Self {
+        let json = serde_json::to_vec(config_json).expect("failed to serialize config");
+        Self(hex_string(&Sha256::digest(json)))
+    }
+}
+
+impl Display for ConfigHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-// Some unspecified hash, don't care too much about stability across builds.
-pub type ConfigHash = u64;
+// Old versions stored a u64 from DefaultHasher. Those can't match a real hash
+// so entries that have one just look like the config changed.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredConfigHash {
+    Sha256(String),
+    Legacy(u64),
+}
+
+impl From<StoredConfigHash> for ConfigHash {
+    fn from(stored: StoredConfigHash) -> Self {
+        match stored {
+            StoredConfigHash::Sha256(hash) => Self(hash),
+            StoredConfigHash::Legacy(hash) => Self(format!("legacy-{hash:016x}")),
+        }
+    }
+}
 
 #[derive(Clone, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct TestName(String);
@@ -94,7 +198,10 @@ impl Debug for TestName {
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub struct Test {
     pub name: TestName,
-    // Hash of the configuration that created this Test.
+    // Canonical description of the configuration that created this Test,
+    // including the config hashes of its dependencies.
+    pub config_json: serde_json::Value,
+    // Hash of config_json.
     pub config_hash: ConfigHash,
     pub program: OsString,
     pub args: Vec<OsString>,
@@ -102,7 +209,26 @@ pub struct Test {
     // can begin.
     pub needs_resources: HashMap<ResourceKey, usize>,
     pub shutdown_grace_period: Duration,
+    // If the job runs for longer than this, it gets shut down and reported as
+    // timed out.
+    pub timeout: Option<Duration>,
+    pub limits: Limits,
+    pub sandbox: Option<Sandbox>,
+    // Run the job with a pseudo-terminal as its stdout and stderr.
+    pub tty: bool,
+    pub exit_codes: ExitCodeOutcomes,
+    // Glob patterns, relative to the directory the job runs in, for files to
+    // save once the job has finished.
+    pub artifacts: Vec<String>,
+    // Report in a standard format to read subtests from once the job has
+    // finished.
+    pub report_file: Option<ReportFile>,
+    pub metrics: Vec<MetricSpec>,
+    // Regexes for the line of output that explains a failure.
+    pub failure_patterns: Vec<String>,
     pub cache_policy: CachePolicy,
+    pub cache_ttl: CacheTtl,
+    pub retry: RetryPolicy,
     // This tests shoudln't start until these other tests have finished.
     // Manager setup will fail if there are cycles in this graph or named tests
     // do not exist.
@@ -142,6 +268,71 @@ impl Test {
     }
 }
 
+// How long cached results stay valid for, depending on whether the test
+// passed. None means forever.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTtl {
+    // This applies to everything that satisfies dependents, i.e. skips and
+    // warnings too.
+    pub success: Option<Duration>,
+    pub failure: Option<Duration>,
+}
+
+impl CacheTtl {
+    pub fn for_status(&self, status: &TestStatus) -> Option<Duration> {
+        if status.satisfies_dependents() {
+            self.success
+        } else {
+            self.failure
+        }
+    }
+}
+
+// When to run a job again if it doesn't succeed. The default is never.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Number of runs allowed on top of the first one.
+    pub max_retries: u32,
+    pub on_failure: bool,
+    // Timeouts count as errors here.
+    pub on_error: bool,
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, status: &TestStatus) -> bool {
+        match status {
+            TestStatus::Completed(result) => self.on_failure && result.exit_code != 0,
+            TestStatus::Error(_) | TestStatus::TimedOut => self.on_error,
+            _ => false,
+        }
+    }
+}
+
+// Special meanings that the user has given to exit codes, on top of the default
+// that zero means success and anything else means failure.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExitCodeOutcomes {
+    pub error: Vec<ExitCode>,
+    pub skip: Option<ExitCode>,
+    pub warning: Vec<ExitCode>,
+}
+
+impl ExitCodeOutcomes {
+    // Figure out the final status of a job whose test command exited with this code.
+    pub fn status(&self, exit_code: ExitCode) -> TestStatus {
+        let result = TestResult { exit_code };
+        if self.error.contains(&exit_code) {
+            TestStatus::Error(format!("exit code {exit_code} indicates an error"))
+        } else if self.skip == Some(exit_code) {
+            TestStatus::Skipped(result)
+        } else if self.warning.contains(&exit_code) {
+            TestStatus::Warning(result)
+        } else {
+            TestStatus::Completed(result)
+        }
+    }
+}
+
 impl Display for Test {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "<test: {:?}>", self.name)
@@ -188,13 +379,34 @@ pub struct Manager<W: Worktree> {
     job_cts: Mutex<HashMap<TestCaseId, CancellationToken>>,
     job_counter: JobCounter,
     notif_tx: broadcast::Sender<Arc<Notification>>,
-    tests: TestDag,
+    tests: Mutex<TestDag>,
+    // The commits from the last set_revisions call.
+    commits: Mutex<Vec<Commit>>,
     // Pools contains sets of intangible arbitrary "resources" that can be used to throttle test
     // jobs, and also tracks access to reused worktrees. The indices of the token-type resources
     // will be referenced by Test::needs_resource_idx values.
     resource_pools: Arc<Pools>,
     result_db: Arc<Database>,
     job_env: Arc<Vec<(String, String)>>,
+    // None if cgroups aren't available.
+    cgroups: Option<Arc<CgroupRoot>>,
+    jobserver: Option<Arc<Jobserver>>,
+}
+
+// Most limits fall back to rlimits without cgroups, but there's nothing like
+// that for cpu_max. Better to say so once than for every job.
+fn warn_unenforced_limits(cgroups: Option<&CgroupRoot>, tests: &TestDag) {
+    if cgroups.is_some_and(|c| c.has_controller("cpu")) {
+        return;
+    }
+    for test in tests.nodes() {
+        if test.limits.cpu_max.is_some() {
+            warn!(
+                "{}: cpu_max can't be enforced without the cgroup cpu controller, ignoring it",
+                test.name
+            );
+        }
+    }
 }
 
 // We need to specify 'static here. Just because we have an Arc over the
@@ -217,18 +429,31 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         // TODO: If this capacity gets exhausted, data gets lost and we get an error which this code
         // probably doesn't handle very gracefully. We should instead just block the sender.
         let (result_tx, _) = broadcast::channel(4096);
+        let cgroups = CgroupRoot::try_create();
+        warn_unenforced_limits(cgroups.as_deref(), &tests);
         Self {
             job_env: Arc::new(base_job_env(repo.path())),
             repo,
             notif_tx: result_tx,
             job_cts: Mutex::new(HashMap::new()),
             job_counter: JobCounter::new(),
-            tests,
+            tests: Mutex::new(tests),
+            commits: Mutex::new(Vec::new()),
             resource_pools,
             result_db,
+            cgroups,
+            jobserver: None,
         }
     }
 
+    // Give all the jobs this jobserver. Its slots get reset whenever the
+    // manager is settled.
+    pub fn with_jobserver(mut self, jobserver: Option<Arc<Jobserver>>) -> Self {
+        self.job_counter.jobserver = jobserver.clone();
+        self.jobserver = jobserver;
+        self
+    }
+
     fn spawn_job(&self, mut job: TestJob<DatabaseOutput>) {
         job.notifier.notify(&TestStatus::Enqueued);
 
@@ -245,7 +470,8 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                 );
                 let status =
                     TestStatus::Error(format!("Dependency {failed_test_name:?} unsuccessful"));
-                job.notifier.notify_completion(status);
+                job.notifier
+                    .notify_completion(status, JobDetails::default(), None);
                 return;
             }
             job.run(db, &pools, origin_worktree.path()).await;
@@ -273,6 +499,8 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                 .map(move |result| result?.ok_or(anyhow!("no such revision {commit_hash:?}")))
         }))
         .await?;
+        let lookups = commit_lookups(self.tests.lock().nodes());
+        let commits = self.repo.resolve_commits(commits, &lookups).await?;
 
         self.set_commits(commits)
     }
@@ -280,15 +508,19 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
     // Inner non-async helper for set_revisions.
     pub fn set_commits(&self, commits: impl IntoIterator<Item = Commit>) -> anyhow::Result<()> {
         let mut job_cts = self.job_cts.lock();
+        let tests = self.tests.lock();
+        let mut last_commits = self.commits.lock();
+        *last_commits = commits.into_iter().collect();
 
-        let test_cases: HashMap<TestCaseId, TestCase> = commits
-            .into_iter()
-            .cartesian_product(self.tests.nodes())
+        let test_cases: HashMap<TestCaseId, TestCase> = last_commits
+            .iter()
+            .cloned()
+            .cartesian_product(tests.nodes())
             .map(|(commit, test)| {
-                let tc = TestCase::new(commit, test.clone());
-                (tc.id(), tc)
+                let tc = TestCase::new(commit, test.clone())?;
+                Ok((tc.id(), tc))
             })
-            .collect();
+            .collect::<anyhow::Result<_>>()?;
 
         // Cancel jobs for test cases that we don't care about any more.
         // https://github.com/rust-lang/rust/issues/59618 would make this more convenient.
@@ -319,16 +551,17 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         // Note we don't actually need the Dag structure for the jobs, and since
         // we don't have a GraphNode implementation for TestJob, we just collect
         // them into a HashMap instead.
-        let jobs = test_cases.bottom_up().try_fold(
+        let mut jobs = test_cases.bottom_up().try_fold(
             HashMap::new(),
             |mut jobs, test_case| -> anyhow::Result<HashMap<TestCaseId, TestJob<DatabaseOutput>>> {
                 let wait_for = test_case
                     .child_ids() // This gives the TestCaseIds of dependency jobs.
                     .iter()
                     .map(|tc_id| {
+                        let dep_job: &TestJob<_> = &jobs[tc_id.borrow()];
                         (
-                            test_case.test.name.clone(),
-                            jobs[tc_id.borrow()].subscribe_completion(),
+                            dep_job.test_case.test.name.clone(),
+                            dep_job.subscribe_completion(),
                         )
                     })
                     .collect();
@@ -343,12 +576,45 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                 )
                 .with_token(self.job_counter.get())
                 .with_global_notif(self.notif_tx.clone())
+                .with_cgroups(self.cgroups.clone())
+                .with_jobserver(self.jobserver.clone())
                 .build();
                 jobs.insert(test_case.id(), job);
                 Ok(jobs)
             },
         )?;
 
+        // Metrics get compared with the same test at the parent commit. If
+        // that's being run now too, wait for it, so there's something to
+        // compare with.
+        let commits_by_hash: HashMap<&CommitHash, &Commit> =
+            last_commits.iter().map(|c| (&c.hash, c)).collect();
+        let mut parents = Vec::new();
+        for (tc_id, job) in &jobs {
+            let test = &job.test_case.test;
+            if test.metrics.is_empty() {
+                continue;
+            }
+            let Some(parent) = commits_by_hash[&job.test_case.commit_hash]
+                .parent
+                .as_ref()
+                .and_then(|p| commits_by_hash.get(p))
+            else {
+                continue;
+            };
+            parents.push((
+                tc_id.clone(),
+                TestCase::new((*parent).clone(), test.clone())?,
+            ));
+        }
+        for (tc_id, test_case) in parents {
+            let completion = jobs.get(&test_case.id()).map(TestJob::subscribe_completion);
+            jobs.get_mut(&tc_id).unwrap().parent = Some(ParentCase {
+                test_case,
+                completion,
+            });
+        }
+
         for (tc_id, job) in jobs.into_iter() {
             job_cts.insert(tc_id.clone(), job.ct.clone());
             self.spawn_job(job);
@@ -356,6 +622,42 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         Ok(())
     }
 
+    // Switch to a new configuration for the tests, e.g. because their
+    // fingerprints changed. The set of tests must be the same. If any test's
+    // config changed, all the jobs get restarted (unchanged tests will mostly
+    // just get their results from the database). Returns whether that happened.
+    pub fn set_tests(&self, tests: TestDag) -> anyhow::Result<bool> {
+        {
+            let mut job_cts = self.job_cts.lock();
+            let mut old_tests = self.tests.lock();
+            let names = |tests: &TestDag| -> HashSet<TestName> {
+                tests.nodes().map(|test| test.name.clone()).collect()
+            };
+            if names(&tests) != names(&old_tests) {
+                bail!("the set of tests can't change, only their configuration");
+            }
+            let changed = tests.nodes().any(|test| {
+                !old_tests
+                    .node(&test.name)
+                    .is_some_and(|old| old.config_hash == test.config_hash)
+            });
+            if !changed {
+                return Ok(false);
+            }
+            warn_unenforced_limits(self.cgroups.as_deref(), &tests);
+            // We can't just restart the changed ones, the dependency jobs of
+            // the restarted jobs need to be restarted too so that they can be
+            // waited for.
+            for (_, ct) in job_cts.drain() {
+                ct.cancel();
+            }
+            *old_tests = tests;
+        }
+        let commits = self.commits.lock().clone();
+        self.set_commits(commits)?;
+        Ok(true)
+    }
+
     pub async fn cancel_running(&self) -> anyhow::Result<()> {
         self.set_revisions::<_, CommitHash>([]).await
     }
@@ -391,7 +693,9 @@ struct TestStatusNotifier {
     // online as the best way to broadcast a single value. But that's not at all
     // what it's actually designed for and using it that way makes for
     // extremely weird code.
-    completion_tx: broadcast::Sender<TestStatus>,
+    completion_tx: broadcast::Sender<JobCompletion>,
+    // Which attempt the output is in, see Notification::attempt.
+    attempt: Option<u64>,
 }
 
 impl TestStatusNotifier {
@@ -401,20 +705,27 @@ impl TestStatusNotifier {
             test_case,
             global_tx,
             completion_tx,
+            attempt: None,
         }
     }
 
     // Get notified when the job on the other end of this notifier is complete.
-    fn subscribe_completion(&self) -> broadcast::Receiver<TestStatus> {
+    fn subscribe_completion(&self) -> broadcast::Receiver<JobCompletion> {
         self.completion_tx.subscribe()
     }
 
     // Report a general update to the status of the test job.
     pub fn notify(&self, status: &TestStatus) {
+        self.send(status, JobDetails::default())
+    }
+
+    fn send(&self, status: &TestStatus, details: JobDetails) {
         // Inner failure means nobody is listening. This is expected when running unit tests.
         let notif = Arc::new(Notification {
             test_case: self.test_case.clone(),
             status: status.clone(),
+            details,
+            attempt: self.attempt,
         });
         if let Some(tx) = &self.global_tx {
             let _ = tx.send(notif);
@@ -426,23 +737,53 @@ impl TestStatusNotifier {
     // using here means that we can only ever reliably send one message. If we
     // got that wrong the results would be confusing to debug, so that's why
     // sending the message consumes the JobDebNotifier.
-    fn notify_completion(self, status: TestStatus) {
-        self.notify(&status);
+    fn notify_completion(
+        self,
+        status: TestStatus,
+        details: JobDetails,
+        output_dir: Option<PathBuf>,
+    ) {
+        self.send(&status, details);
         // Inner failure means nobody is listening. This is fine and normal.
-        let _ = self.completion_tx.send(status);
+        let _ = self
+            .completion_tx
+            .send(JobCompletion { status, output_dir });
     }
 }
 
+// What a job tells the jobs that depend on it, once it's done.
+#[derive(Debug, Clone)]
+pub struct JobCompletion {
+    pub status: TestStatus,
+    // Where the job's stdout, stderr and artifacts can be found, if anywhere.
+    pub output_dir: Option<PathBuf>,
+}
+
+// Name of the variable that tells a job where to find a dependency's output.
+// Test names can contain characters that shells won't accept in variable
+// names, so those get replaced.
+pub fn dep_dir_env_var(dep_name: &TestName) -> String {
+    let name: String = dep_name
+        .to_string()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("LIMMAT_DEP_{name}_DIR")
+}
+
 // This is a horrible attempt to implement Manager::settled. There is no Condvar in tokio or
 // futures-rs, so we have this weird condvar-like construction using a Tokio watch channel.
 struct JobCounter {
     w: watch::Sender<usize>,
+    // Reset whenever the counter hits zero.
+    jobserver: Option<Arc<Jobserver>>,
 }
 
 impl JobCounter {
     pub fn new() -> Self {
         Self {
             w: watch::Sender::new(0),
+            jobserver: None,
         }
     }
 
@@ -455,7 +796,10 @@ impl JobCounter {
             *count += 1;
             was_zero
         });
-        JobToken { w: self.w.clone() }
+        JobToken {
+            w: self.w.clone(),
+            jobserver: self.jobserver.clone(),
+        }
     }
 
     // Block until the counter is zero. If it's already zero, return immediately. This might miss
@@ -479,33 +823,70 @@ impl JobCounter {
 
 struct JobToken {
     w: watch::Sender<usize>,
+    jobserver: Option<Arc<Jobserver>>,
 }
 
 impl Drop for JobToken {
     fn drop(&mut self) {
         self.w.send_if_modified(|count| {
             *count -= 1;
+            if *count == 0 {
+                // Nothing's running, so if any jobserver slots are missing it's
+                // because a job leaked them. This happens under the channel's
+                // lock, so no new job can sneak in and take a token meanwhile.
+                if let Some(jobserver) = &self.jobserver {
+                    jobserver.reset().or_log_error("resetting jobserver");
+                }
+            }
             *count == 0
         });
     }
 }
 
 // A drop guard which attempts to ensure child processes can be cleaned up quite
-// brutally, by killing the entire process group with the PID of the child.
+// brutally. If the job has its own cgroup, that's easy: everything in there
+// gets killed when the cgroup is dropped. Otherwise we kill the entire process
+// group with the PID of the child.
 // This is a bad idea if you haven't made some effort to ensure that the child's
 // process group ID is its PID. I initially tried to ensure that with Rust
 // jiggery pokery but it produced just godawful nonsense verbosity so... just be
 // careful yeah?
 #[derive(Debug)]
-struct ChildDropGuard(Child);
+struct ChildDropGuard {
+    pid: Pid,
+    // Once the child has been reaped its PID could belong to someone else.
+    reaped: bool,
+    cgroup: Option<JobCgroup>,
+}
+
+impl ChildDropGuard {
+    // Send a signal to everything the job started, as far as we can tell.
+    fn signal(&self, signal: Signal) -> anyhow::Result<()> {
+        match &self.cgroup {
+            Some(cgroup) => cgroup.signal(signal),
+            None => Ok(killpg(self.pid, signal)?),
+        }
+    }
+
+    // Like dropping it, but waiting for the cgroup to empty out happens off
+    // the async threads.
+    async fn release(mut self) {
+        if let Some(cgroup) = self.cgroup.take() {
+            // Nothing left to killpg once the cgroup is gone.
+            self.reaped = true;
+            spawn_blocking(move || drop(cgroup))
+                .await
+                .or_log_error("removing job cgroup");
+        }
+    }
+}
 
 impl Drop for ChildDropGuard {
     fn drop(&mut self) {
-        let pid = match self.0.id() {
-            None => return, // Must already have shut down.
-            Some(p) => Pid::from_raw(p.try_into().unwrap()),
-        };
-        killpg(pid, Signal::SIGKILL).or_log_error("SIGKILLing child process group");
+        if self.reaped || self.cgroup.is_some() {
+            return;
+        }
+        killpg(self.pid, Signal::SIGKILL).or_log_error("SIGKILLing child process group");
     }
 }
 
@@ -515,8 +896,11 @@ pub struct TestJobBuilder<O> {
     token: Option<JobToken>,
     output: O,
     env: Arc<Vec<(String, String)>>,
-    wait_for: Vec<(TestName, broadcast::Receiver<TestStatus>)>,
+    wait_for: Vec<(TestName, broadcast::Receiver<JobCompletion>)>,
+    dep_dirs: Vec<(TestName, PathBuf)>,
     global_tx: Option<broadcast::Sender<Arc<Notification>>>,
+    cgroups: Option<Arc<CgroupRoot>>,
+    jobserver: Option<Arc<Jobserver>>,
 }
 
 impl<O: TestJobOutput> TestJobBuilder<O> {
@@ -527,7 +911,7 @@ impl<O: TestJobOutput> TestJobBuilder<O> {
         env: Arc<JobEnv>,
         // Job shouldn't start until all of these channels produce a result. If any
         // is unsuccessful it should abort.
-        wait_for: Vec<(TestName, broadcast::Receiver<TestStatus>)>,
+        wait_for: Vec<(TestName, broadcast::Receiver<JobCompletion>)>,
     ) -> Self {
         Self {
             ct,
@@ -535,11 +919,21 @@ impl<O: TestJobOutput> TestJobBuilder<O> {
             output,
             env,
             wait_for,
+            dep_dirs: Vec::new(),
             token: None,
             global_tx: None,
+            cgroups: None,
+            jobserver: None,
         }
     }
 
+    // Tell the job where its dependencies' outputs are. This is only needed
+    // if they were run separately, otherwise the job finds out via wait_for.
+    pub fn with_dep_dirs(mut self, dep_dirs: Vec<(TestName, PathBuf)>) -> Self {
+        self.dep_dirs = dep_dirs;
+        self
+    }
+
     // Have this job also report when it's done back to this weird token counter
     // mechanism that probably shouldn't exist.
     fn with_token(mut self, token: JobToken) -> Self {
@@ -553,6 +947,19 @@ impl<O: TestJobOutput> TestJobBuilder<O> {
         self
     }
 
+    // Run each attempt of the job in its own cgroup under this root. Otherwise
+    // the job is only tracked by its process group.
+    pub fn with_cgroups(mut self, cgroups: Option<Arc<CgroupRoot>>) -> Self {
+        self.cgroups = cgroups;
+        self
+    }
+
+    // Make the job a client of this jobserver.
+    pub fn with_jobserver(mut self, jobserver: Option<Arc<Jobserver>>) -> Self {
+        self.jobserver = jobserver;
+        self
+    }
+
     pub fn build(self) -> TestJob<O> {
         TestJob {
             ct: self.ct,
@@ -561,18 +968,62 @@ impl<O: TestJobOutput> TestJobBuilder<O> {
             output: self.output,
             base_env: self.env,
             wait_for: self.wait_for,
+            dep_dirs: self.dep_dirs,
+            parent: None,
+            parent_metrics: None,
             notifier: TestStatusNotifier::new(self.test_case, self.global_tx),
+            cgroups: self.cgroups,
+            jobserver: self.jobserver,
         }
     }
 }
 
 pub trait TestJobOutput {
+    // Try to get exclusive ownership of wherever the output goes, so that
+    // nobody else (e.g. another Limmat process) writes there at the same time.
+    // Returns false if someone else has it. Implementations may panic if
+    // written to before this has succeeded.
+    fn claim(&mut self) -> anyhow::Result<bool>;
     // Panics if called more than once.
     fn stderr(&mut self) -> anyhow::Result<Stdio>;
     // Panics if called more than once.
     fn stdout(&mut self) -> anyhow::Result<Stdio>;
-    // Panics if called more than once.
-    fn set_result(&mut self, result: &TestResult) -> anyhow::Result<()>;
+    // Used instead of stdout and stderr when the job runs under a terminal.
+    // Returns where everything it writes should go, and records the terminal
+    // size. Panics if called more than once, or along with stdout or stderr.
+    fn terminal(&mut self, size: TerminalSize) -> anyhow::Result<File>;
+    // Record the final status of the job. Panics if called more than once
+    // (per attempt).
+    fn set_status(&mut self, status: &TestStatus) -> anyhow::Result<()>;
+    // Record how long the job took and what it used. If this is called, it's
+    // before set_status.
+    fn set_usage(&mut self, usage: &JobUsage);
+    // Record the report the job wrote to $LIMMAT_RESULT_FILE. If this is
+    // called, it's before set_status.
+    fn set_result(&mut self, report: &Report);
+    // Record the line of output that explains why the job failed. If this is
+    // called, it's before set_status.
+    fn set_failure_excerpt(&mut self, excerpt: &str);
+    // Record the parent commit's metrics that the job's get compared with. If
+    // this is called, it's before set_status.
+    fn set_parent_metrics(&mut self, metrics: &BTreeMap<String, f64>);
+    // Called when the job is going to be run again after set_status. The
+    // next run's output should be stored separately, ideally without
+    // throwing away the previous one.
+    fn new_attempt(&mut self);
+    // Number of the attempt the output is going into, if there's such a
+    // thing. Might have to create it.
+    fn attempt(&mut self) -> anyhow::Result<Option<u64>>;
+    // Where to save a copy of the file or directory that the job produced at
+    // relpath (relative to the directory it ran in), None if it doesn't need
+    // saving. The parent directory must exist once this returns.
+    fn artifact_dest(&mut self, relpath: &Path) -> anyhow::Result<Option<PathBuf>>;
+    // Directory where the outputs end up, if they get stored anywhere.
+    fn path(&self) -> Option<&Path>;
+    // Where the stdout ended up, if it can be read back.
+    fn stdout_path(&self) -> Option<PathBuf>;
+    // Same for stderr.
+    fn stderr_path(&self) -> Option<PathBuf>;
 }
 
 // This is not really a proper type, it doesn't really mean anything except as an implementation
@@ -589,33 +1040,98 @@ pub struct TestJob<O: TestJobOutput> {
     base_env: Arc<Vec<(String, String)>>,
     // Job shouldn't start until all of these channels produce a result. If any
     // is unsuccessful it should abort.
-    wait_for: Vec<(TestName, broadcast::Receiver<TestStatus>)>,
+    wait_for: Vec<(TestName, broadcast::Receiver<JobCompletion>)>,
+    // Output directories of dependency jobs, passed to the job via the
+    // environment.
+    dep_dirs: Vec<(TestName, PathBuf)>,
+    // The same test at the parent commit, if the metrics should be compared
+    // with that.
+    parent: Option<ParentCase>,
+    // Read from the parent's result before the job runs.
+    parent_metrics: Option<BTreeMap<String, f64>>,
     notifier: TestStatusNotifier,
+    cgroups: Option<Arc<CgroupRoot>>,
+    jobserver: Option<Arc<Jobserver>>,
+}
+
+struct ParentCase {
+    test_case: TestCase,
+    // Set if it's being run alongside this job, which then waits for it.
+    completion: Option<broadcast::Receiver<JobCompletion>>,
 }
 
 impl<'a, O: TestJobOutput> TestJob<O> {
-    pub fn subscribe_completion(&self) -> broadcast::Receiver<TestStatus> {
+    pub fn subscribe_completion(&self) -> broadcast::Receiver<JobCompletion> {
         self.notifier.subscribe_completion()
     }
 
+    pub fn test_case(&self) -> &TestCase {
+        &self.test_case
+    }
+
     // This is the normal entry point to run a job. It gets the necessary
     // resources from the pools and runs the job. It takes care of notifying
     // anyone who needs to know about the result, and also of checking for
     // pre-existing results in the database.
     pub async fn run(
-        self,
+        mut self,
         database: Arc<Database>,
         pools: &Pools,
         origin_worktree_path: &Path,
     ) -> TestStatus {
-        if let Some(db_entry) = database
-            .lookup_result(&self.test_case)
-            .inspect_err(|e| error!("Failed to read cached test result, will overwrite: {e:?}"))
-            .unwrap_or(None)
-        {
-            let result = TestStatus::Completed(db_entry.result().clone());
-            self.notifier.notify_completion(result.clone());
-            return result;
+        loop {
+            if let Some(db_entry) = database
+                .lookup_result(&self.test_case)
+                .inspect_err(|e| error!("Failed to read cached test result, will overwrite: {e:?}"))
+                .unwrap_or(None)
+            {
+                let status = db_entry.status().clone();
+                self.notifier.attempt = db_entry.attempt();
+                self.notifier.notify_completion(
+                    status.clone(),
+                    db_entry.details(),
+                    Some(db_entry.path().to_owned()),
+                );
+                return status;
+            }
+            match self.output.claim() {
+                Ok(true) => break,
+                Ok(false) => {
+                    // Someone else, probably another Limmat process, is
+                    // already producing this result. Rather than clobber it,
+                    // wait for them to finish and then check again. There's no
+                    // way to asynchronously wait for a flock so just poll.
+                    info!("{:?} is being run elsewhere, waiting", self.test_case);
+                    select! {
+                        _ = self.ct.cancelled() => return TestStatus::Canceled,
+                        _ = sleep(Duration::from_millis(500)) => (),
+                    }
+                }
+                Err(err) => return self.fail(format!("claiming job output: {err:#}")),
+            }
+        }
+
+        // The parent's metrics aren't known until it's done. We're not holding
+        // any resources yet, so this can't stop it getting them.
+        self.await_parent(&database).await;
+
+        // Don't let anyone delete the dependency outputs while we're running.
+        // If someone's busy with one (e.g. another Limmat process is re-running
+        // the dependency) wait for them.
+        let mut dep_locks = Vec::new();
+        let mut lock_err = None;
+        for (dep_name, dir) in &self.dep_dirs {
+            match poll_lock(&self.ct, || database.read_lock(dir)).await {
+                Ok(Some(lock)) => dep_locks.push(lock),
+                Ok(None) => return TestStatus::Canceled,
+                Err(err) => {
+                    lock_err = Some(format!("locking output of dependency {dep_name}: {err:#}"));
+                    break;
+                }
+            }
+        }
+        if let Some(msg) = lock_err {
+            return self.fail(msg);
         }
 
         select! {
@@ -629,7 +1145,7 @@ impl<'a, O: TestJobOutput> TestJob<O> {
 
             _ = self.ct.cancelled() => TestStatus::Canceled,
             resources = pools.get(self.test_case.test.needs_resources.clone()) =>  {
-                self.notifier.notify(&TestStatus::Started);
+                self.notify_started();
                 if let Some(worktrees) = resources.resources(&ResourceKey::Worktree) {
                     // We "own" this worktree.
                     let worktree = worktrees[0].as_worktree();
@@ -645,6 +1161,25 @@ impl<'a, O: TestJobOutput> TestJob<O> {
         }
     }
 
+    // Tell everyone the job is running, and where its output is going.
+    fn notify_started(&mut self) {
+        match self.output.attempt() {
+            Ok(attempt) => self.notifier.attempt = attempt,
+            // Writing the output will probably fail too, that'll get reported
+            // properly.
+            Err(err) => warn!("{:?}: couldn't create output: {err:#}", self.test_case),
+        }
+        self.notifier.notify(&TestStatus::Started);
+    }
+
+    // Give up on the job before starting it.
+    fn fail(self, msg: String) -> TestStatus {
+        let status = TestStatus::Error(msg);
+        self.notifier
+            .notify_completion(status.clone(), JobDetails::default(), None);
+        status
+    }
+
     // Blocks until all dependency jobs have succeeded, or returns an error
     // reporting the name of the job that terminated without success.
     pub async fn await_dep_success(&mut self) -> Result<(), TestName> {
@@ -658,24 +1193,27 @@ impl<'a, O: TestJobOutput> TestJob<O> {
             .collect();
         let mut wait_for: Vec<_> = wait_for.into_iter().map(Box::pin).collect();
         while !wait_for.is_empty() {
-            let ((test_name, test_status), _idx, remaining) = select_all(wait_for).await;
+            let ((test_name, completion), _idx, remaining) = select_all(wait_for).await;
             wait_for = remaining;
             // We are squashing lots of different types of failures and aborts
             // (including the "impossible" case that the sender has been dropped
             // and the rx.wait_for call failed) here, we trust that the other
             // side of the notifier has reported any issues appropriately.
-            if let Ok(TestStatus::Completed(result)) = &test_status {
-                if result.exit_code == 0 {
+            if let Ok(ref completion) = completion {
+                if completion.status.satisfies_dependents() {
                     debug!(
                         "{:?}: Dependency {:?} succeeded",
                         self.test_case.test.name, test_name
                     );
+                    if let Some(dir) = &completion.output_dir {
+                        self.dep_dirs.push((test_name.clone(), dir.clone()));
+                    }
                     continue;
                 }
             }
             info!(
                 "Dependency {:?} of {:?} failed: {:?}",
-                test_name, self.test_case.test.name, test_status
+                test_name, self.test_case.test.name, completion
             );
             return Err(test_name.clone());
         }
@@ -683,11 +1221,45 @@ impl<'a, O: TestJobOutput> TestJob<O> {
         Ok(())
     }
 
+    // If the job's metrics get compared with the parent commit's, wait for
+    // the parent to finish if it's being run too, then read them from its
+    // result. It's not a problem if there's nothing to compare with.
+    async fn await_parent(&mut self, database: &Database) {
+        let Some(parent) = &mut self.parent else {
+            return;
+        };
+        if let Some(completion) = &mut parent.completion {
+            // However it finished, there's nothing more to wait for.
+            select! {
+                _ = self.ct.cancelled() => return,
+                _ = completion.recv() => (),
+            }
+        }
+        self.parent_metrics = match database.latest_entry(&parent.test_case) {
+            Ok(Some(entry)) => entry
+                .details()
+                .report
+                .map(|r| r.metrics)
+                .filter(|m| !m.is_empty()),
+            Ok(None) => {
+                info!("{:?} is busy, not comparing with it", parent.test_case);
+                None
+            }
+            Err(err) => {
+                debug!("{:?}: nothing to compare with: {err:#}", parent.test_case);
+                None
+            }
+        };
+    }
+
     fn set_env(&self, cmd: &mut Command, resources: &Resources<'a>) {
         cmd.env("LIMMAT_COMMIT", &self.test_case.commit_hash);
         for (k, v) in self.base_env.iter() {
             cmd.env(k, v);
         }
+        for (dep_name, dir) in &self.dep_dirs {
+            cmd.env(dep_dir_env_var(dep_name), dir);
+        }
         // Set up env vars to communicate token values.
         for (resource_name, tokens) in resources.tokens() {
             if tokens.len() == 1 {
@@ -704,7 +1276,7 @@ impl<'a, O: TestJobOutput> TestJob<O> {
         }
     }
 
-    // Returns Ok(None) when canceled. Does not return until the child process has shut down.
+    // Does not return until the child process has shut down.
     // This _inner variant is just here so we can use ? in it instead of having
     // to construct a TestStatus to report errors. I'm not sure if this
     // awkwardness is my fault for designing the types wrong, or Rust's fault
@@ -714,68 +1286,389 @@ impl<'a, O: TestJobOutput> TestJob<O> {
         &mut self,
         current_dir: &Path,
         resources: &Resources<'a>,
-    ) -> anyhow::Result<Option<ExitCode>> {
+        result_file: &Path,
+    ) -> anyhow::Result<(ChildOutcome, Option<JobUsage>)> {
         info!("Starting {:?}", self.test_case);
 
         let mut cmd = self.test_case.test.command();
-        cmd.current_dir(current_dir)
-            .stdout(self.output.stdout().context("no stdout handle available")?)
-            .stderr(self.output.stderr().context("no stdout handle available")?);
+        cmd.current_dir(current_dir);
+        let mut terminal = None;
+        if self.test_case.test.tty {
+            let size = TerminalSize::current();
+            let output = self
+                .output
+                .terminal(size)
+                .context("no terminal output available")?;
+            let (pty, tty) = Pty::open(size)?;
+            cmd.stdout(tty.try_clone().context("duplicating pty")?)
+                .stderr(tty);
+            terminal = Some((pty, output));
+        } else {
+            cmd.stdout(self.output.stdout().context("no stdout handle available")?)
+                .stderr(self.output.stderr().context("no stdout handle available")?);
+        }
         self.set_env(&mut cmd, resources);
+        cmd.env(RESULT_FILE_ENV_VAR, result_file);
+        let cgroup = match &self.cgroups {
+            Some(cgroups) => {
+                let cgroup = cgroups.new_job().context("creating cgroup for job")?;
+                cgroup.add_command(cmd.as_std_mut());
+                Some(cgroup)
+            }
+            None => None,
+        };
+        let limits = &self.test_case.test.limits;
+        let unenforced = match &cgroup {
+            Some(cgroup) if !limits.is_empty() => cgroup
+                .set_limits(limits)
+                .context("setting job resource limits")?,
+            Some(_) => Limits::default(),
+            None => limits.clone(),
+        };
+        if !unenforced.is_empty() {
+            unenforced.add_rlimits(cmd.as_std_mut());
+        }
+        if let Some(jobserver) = &self.jobserver {
+            jobserver.add_command(cmd.as_std_mut());
+        }
+        if let Some(sandbox) = &self.test_case.test.sandbox {
+            // The job can still see the stuff its environment points it to.
+            let readable: Vec<&Path> = self
+                .base_env
+                .iter()
+                .filter(|(k, _)| k == "LIMMAT_ORIGIN")
+                .map(|(_, v)| Path::new(v))
+                .chain(self.dep_dirs.iter().map(|(_, dir)| dir.as_path()))
+                .collect();
+            sandbox
+                .add_command(
+                    cmd.as_std_mut(),
+                    &[current_dir, result_file.parent().unwrap()],
+                    &readable,
+                )
+                .context("setting up sandbox")?;
+        }
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        // We want the child's rusage, which tokio won't tell us about, so
+        // spawn it via std and reap it ourselves with wait4 on a blocking
+        // thread.
+        let pid = Pid::from_raw(
+            cmd.as_std_mut()
+                .spawn()
+                .context("spawning test command")?
+                .id()
+                .try_into()
+                .unwrap(),
+        );
+        // Otherwise we'd keep the job's end of the terminal open and never find
+        // out when it's done with it.
+        drop(cmd);
+        let terminal_copier =
+            terminal.map(|(pty, output)| AbortOnDropHandle::new(tokio::spawn(pty.copy_to(output))));
         // It would be really confusing and annoying if we exited this function
         // without ensuring the child is dead. So we wrap it in this sketchy
         // drop guard thing.
-        let mut child = ChildDropGuard(cmd.spawn().context("spawning test command")?);
-        // Grab the PID now if we can, since it's a pain to look it up later for
-        // silly Rust reasons. If no PID is found we just carry on assuming the
-        // process has already shut down.
-        let pid = child
-            .0
-            .id()
-            .map(|raw| Pid::from_raw(raw.try_into().unwrap()));
-        // Await the child, or cancellation. Because the "right" branch still needs to do work on
-        // the "left" future, tokio::select doesn't grant us any clarity or concision here so we
-        // drop down to the raw function call.
-        let child_fut = pin!(child.0.wait());
+        let mut guard = ChildDropGuard {
+            pid,
+            reaped: false,
+            cgroup,
+        };
+        let usage = |rusage| JobUsage::new(started_at, start, rusage);
+        // Await the child, or cancellation, or the timeout. Because the "right"
+        // branch still needs to do work on the "left" future, tokio::select
+        // doesn't grant us any clarity or concision here so we drop down to the
+        // raw function call.
+        let child_fut = spawn_blocking(move || process::wait4(pid))
+            .map(|result| result.map_err(io::Error::other).and_then(|r| r));
+        let child_fut = pin!(child_fut);
         let cancel_fut = pin!(self.ct.cancelled());
-        match future::select(child_fut, cancel_fut).await {
-            Either::Left((wait_result, _)) =>
-            // Test completed, figure out the result. I think maybe a true Rustacean would
-            // write this block as a single chain of methods? But it seems ridiculous to me.
-            {
-                let exit_code = wait_result
-                    .map_err(anyhow::Error::from)?
-                    .code_not_killed()?;
-                Ok(Some(exit_code))
+        let timeout_fut = pin!(match self.test_case.test.timeout {
+            Some(timeout) => Either::Left(sleep(timeout)),
+            None => Either::Right(future::pending()),
+        });
+        let result = match future::select(child_fut, future::select(cancel_fut, timeout_fut)).await
+        {
+            Either::Left((wait_result, _)) => (|| {
+                // Test completed, the caller figures out the result.
+                let (exit_status, rusage) = wait_result?;
+                guard.reaped = true;
+                let oom_killed = match &guard.cgroup {
+                    Some(cgroup) => cgroup.oom_killed()?,
+                    None => false,
+                };
+                let outcome = if oom_killed {
+                    info!("{:?} ran out of memory", self.test_case);
+                    ChildOutcome::OomKilled
+                } else {
+                    ChildOutcome::Exited(exit_status)
+                };
+                Ok::<_, anyhow::Error>((outcome, Some(usage(rusage))))
+            })(),
+            Either::Right((interruption, child_fut)) => {
+                let outcome = match interruption {
+                    Either::Left(_) => ChildOutcome::Canceled,
+                    Either::Right(_) => {
+                        info!("{:?} timed out", self.test_case);
+                        ChildOutcome::TimedOut
+                    }
+                };
+                let rusage = self.shut_down_child(&guard, child_fut).await;
+                guard.reaped = rusage.is_some();
+                Ok((outcome, rusage.map(usage)))
             }
-            Either::Right((_, child_fut)) => {
-                // Canceled. Shut down the process if necessary.
-                if let Some(pid) = pid {
-                    killpg(pid, Signal::SIGTERM).or_log_error("SIGTERMing child process");
+        };
+        // This kills anything left in the cgroup, which might still be writing
+        // to the terminal.
+        guard.release().await;
+        let result = result?;
+        if let Some(copier) = terminal_copier {
+            match timeout(TERMINAL_DRAIN_TIMEOUT, copier).await {
+                Ok(result) => result
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r)
+                    .or_log_error("copying terminal output"),
+                Err(_) => warn!(
+                    "{:?}: something outside the job still has its terminal open, dropping the rest of its output",
+                    self.test_case
+                ),
+            }
+        }
+        Ok(result)
+    }
+
+    // Copy out anything the test config said should be kept. Artifacts can be
+    // big, so the globbing and copying happens off the async threads.
+    async fn save_artifacts(&mut self, current_dir: &Path) -> anyhow::Result<()> {
+        let escaped_dir = glob::Pattern::escape(
+            current_dir
+                .to_str()
+                .ok_or_else(|| anyhow!("non-UTF-8 job directory {current_dir:?}"))?,
+        );
+        let patterns: Vec<String> = self
+            .test_case
+            .test
+            .artifacts
+            .iter()
+            .map(|pattern| format!("{escaped_dir}/{pattern}"))
+            .collect();
+        let paths = spawn_blocking(move || -> anyhow::Result<Vec<PathBuf>> {
+            let mut paths = Vec::new();
+            for pattern in patterns {
+                for path in glob::glob(&pattern)
+                    .with_context(|| format!("bad artifact pattern {pattern:?}"))?
+                {
+                    paths.push(path.context("expanding artifact pattern")?);
+                }
+            }
+            // Overlapping patterns (say, a directory and something inside
+            // it) would have us copy the same thing twice.
+            paths.sort();
+            let mut deduped: Vec<PathBuf> = Vec::new();
+            for path in paths {
+                if !deduped.last().is_some_and(|prev| path.starts_with(prev)) {
+                    deduped.push(path);
                 }
-                // We don't care about its result but we
-                // need to wait for it to shut down so that we can safely give back the
-                // worktree.
-                let timeout = pin!(sleep(self.test_case.test.shutdown_grace_period));
-                match future::select(child_fut, timeout).await {
-                    Either::Left(_) => (), // Done, child terminated
-                    Either::Right((_timeout, child_fut)) => {
-                        // Canceled. Shut down the process harder.
-                        warn!(
-                            "timeout for {:?}, SIGKILLing whole process group",
-                            self.test_case.test.name
-                        );
-                        killpg(pid.expect("timed out, but no child PID"), Signal::SIGKILL)
-                            .or_log_error("SIGKILLing child process group");
-                        // To be sure to be sure, we'll also wait and make sure
-                        // the child is really dead.
-                        child_fut.await.expect("failed to wait on SIGKILLed child");
+            }
+            Ok(deduped)
+        })
+        .await
+        .context("joining artifact glob task")??;
+        let mut copies = Vec::new();
+        for path in paths {
+            let relpath = path
+                .strip_prefix(current_dir)
+                .expect("artifact glob matched outside job directory")
+                .to_owned();
+            if let Some(dest) = self
+                .output
+                .artifact_dest(&relpath)
+                .with_context(|| format!("saving artifact {relpath:?}"))?
+            {
+                debug!("{:?}: saving artifact {relpath:?}", self.test_case);
+                copies.push((relpath, path, dest));
+            }
+        }
+        let job_dir = current_dir.to_owned();
+        spawn_blocking(move || {
+            for (relpath, src, dest) in copies {
+                copy_recursive(&src, &dest, &job_dir)
+                    .with_context(|| format!("saving artifact {relpath:?}"))?;
+            }
+            Ok(())
+        })
+        .await
+        .context("joining artifact copy task")?
+    }
+
+    // Gather up whatever the job told us about its result, if anything.
+    async fn read_report(
+        &self,
+        current_dir: &Path,
+        result_file: &Path,
+    ) -> anyhow::Result<Option<Report>> {
+        let mut report = Report::read(result_file)?;
+        if let Some(report_file) = &self.test_case.test.report_file {
+            // If the job failed it might not have got as far as writing the
+            // report, so that's not an error. Nor is a report we can't make
+            // sense of, that's down to whatever tool wrote it rather than the
+            // job, so the exit code still decides the status.
+            match report_file.read_subtests(current_dir) {
+                Ok(None) => warn!(
+                    "{:?}: no report found at {:?}",
+                    self.test_case, report_file.path
+                ),
+                Ok(Some(subtests)) => report
+                    .get_or_insert_with(Report::default)
+                    .add_subtests(subtests),
+                Err(err) => warn!("{:?}: ignoring report: {err:#}", self.test_case),
+            }
+        }
+        let metrics = self.test_case.test.metrics.clone();
+        if let Some(stdout) = self
+            .output
+            .stdout_path()
+            .filter(|_| metrics.iter().any(|m| m.regex.is_some()))
+        {
+            // Metrics are just extra information, so if we can't get them
+            // that's not the job's problem.
+            match spawn_blocking(move || scrape_metrics(&metrics, &stdout))
+                .await
+                .context("scraping metrics panicked")
+                .and_then(|r| r)
+            {
+                Err(err) => warn!("{:?}: reading stdout for metrics: {err:#}", self.test_case),
+                Ok(scraped) => {
+                    for (name, value) in scraped {
+                        match value {
+                            Ok(value) => {
+                                report
+                                    .get_or_insert_with(Report::default)
+                                    .metrics
+                                    .insert(name, value);
+                            }
+                            Err(err) => warn!("{:?}: {err:#}", self.test_case),
+                        }
                     }
                 }
+            }
+        }
+        Ok(report)
+    }
+
+    // Shut down the process if necessary. We don't care about its result but
+    // we need to wait for it to shut down so that we can safely give back the
+    // worktree. Returns its resource usage if it was reaped successfully.
+    async fn shut_down_child<F>(&self, guard: &ChildDropGuard, child_fut: F) -> Option<Rusage>
+    where
+        F: Future<Output = io::Result<(ExitStatus, Rusage)>> + Unpin,
+    {
+        guard
+            .signal(Signal::SIGTERM)
+            .or_log_error("SIGTERMing child process");
+        let timeout = pin!(sleep(self.test_case.test.shutdown_grace_period));
+        match future::select(child_fut, timeout).await {
+            // Done, child terminated
+            Either::Left((wait_result, _)) => wait_result
+                .inspect_err(|e| error!("Failed to wait for child process: {e}"))
+                .ok()
+                .map(|(_, rusage)| rusage),
+            Either::Right((_timeout, child_fut)) => {
+                // Shut down the process harder.
+                warn!(
+                    "timeout for {:?}, SIGKILLing all its processes",
+                    self.test_case.test.name
+                );
+                guard
+                    .signal(Signal::SIGKILL)
+                    .or_log_error("SIGKILLing child processes");
+                // To be sure to be sure, we'll also wait and make sure
+                // the child is really dead.
+                let (_, rusage) = child_fut.await.expect("failed to wait on SIGKILLed child");
+                Some(rusage)
+            }
+        }
+    }
 
-                Ok(None)
+    // Run the test command once and figure out what happened.
+    async fn run_attempt(
+        &mut self,
+        current_dir: &Path,
+        resources: &Resources<'a>,
+    ) -> (TestStatus, JobDetails) {
+        // Somewhere for the job to leave its report. This doesn't go straight
+        // into the output, since that would let the job clobber other stuff
+        // there.
+        let result_dir = match tempfile::Builder::new().prefix("limmat-result-").tempdir() {
+            Ok(dir) => dir,
+            Err(err) => {
+                return (
+                    TestStatus::Error(format!("creating result file dir: {err}")),
+                    JobDetails::default(),
+                )
+            }
+        };
+        let result_file = result_dir.path().join("result.json");
+        let (outcome, usage) = match self.run_inner(current_dir, resources, &result_file).await {
+            Err(err) => return (TestStatus::Error(err.to_string()), JobDetails::default()),
+            Ok(result) => result,
+        };
+        let mut details = JobDetails {
+            usage,
+            ..Default::default()
+        };
+        let status = match outcome {
+            ChildOutcome::Canceled => TestStatus::Canceled,
+            ChildOutcome::TimedOut => TestStatus::TimedOut,
+            ChildOutcome::OomKilled => {
+                TestStatus::Error(match self.test_case.test.limits.memory_max {
+                    Some(max) => format!("OOM: exceeded memory_max of {}", human_bytes(max)),
+                    None => "OOM: killed for running out of memory".into(),
+                })
             }
+            ChildOutcome::Exited(exit_status) => match exit_status.code_not_killed() {
+                Err(err) => TestStatus::Error(err.to_string()),
+                Ok(exit_code) => {
+                    let report = match self.save_artifacts(current_dir).await {
+                        Ok(()) => self.read_report(current_dir, &result_file).await,
+                        Err(err) => Err(err),
+                    };
+                    match report {
+                        Ok(report) => {
+                            details.report = report;
+                            self.test_case.test.exit_codes.status(exit_code)
+                        }
+                        // Don't hide how the command itself went.
+                        Err(err) => TestStatus::Error(format!("{err:#} (exit code {exit_code})")),
+                    }
+                }
+            },
+        };
+        if matches!(status, TestStatus::Completed(TestResult { exit_code }) if exit_code != 0)
+            || matches!(status, TestStatus::Error(_) | TestStatus::TimedOut)
+        {
+            let outputs: Vec<_> = [self.output.stderr_path(), self.output.stdout_path()]
+                .into_iter()
+                .flatten()
+                .collect();
+            // The output could be huge.
+            let patterns = self.test_case.test.failure_patterns.clone();
+            details.failure_excerpt = spawn_blocking(move || failure_excerpt(&patterns, &outputs))
+                .await
+                .context("scanning output panicked")
+                .and_then(|r| r)
+                .inspect_err(|e| warn!("{:?}: couldn't scan output: {e:#}", self.test_case))
+                .ok()
+                .flatten();
+        }
+        if details
+            .report
+            .as_ref()
+            .is_some_and(|r| !r.metrics.is_empty())
+        {
+            details.parent_metrics = self.parent_metrics.clone();
         }
+        (status, details)
     }
 
     // This is a specialised entry point for when you already have the necessary
@@ -786,22 +1679,70 @@ impl<'a, O: TestJobOutput> TestJob<O> {
         current_dir: &Path,
         resources: &Resources<'a>,
     ) -> TestStatus {
-        let status = match self.run_inner(current_dir, resources).await {
-            Err(ref err) => TestStatus::Error(err.to_string()),
-            Ok(None) => TestStatus::Canceled,
-            Ok(Some(exit_code)) => {
-                let test_result = TestResult { exit_code };
-                self.output
-                    .set_result(&test_result)
-                    .or_log_error("couldn't save job status");
-                TestStatus::Completed(test_result)
+        // Retries happen right here, hanging on to the resources.
+        let test = self.test_case.test.clone();
+        let retry = &test.retry;
+        let mut attempts = 1;
+        let (status, details) = loop {
+            let (status, details) = self.run_attempt(current_dir, resources).await;
+            if let Some(usage) = &details.usage {
+                self.output.set_usage(usage);
+            }
+            if let Some(report) = &details.report {
+                self.output.set_result(report);
+            }
+            if let Some(excerpt) = &details.failure_excerpt {
+                self.output.set_failure_excerpt(excerpt);
+            }
+            if let Some(metrics) = &details.parent_metrics {
+                self.output.set_parent_metrics(metrics);
+            }
+            if attempts > retry.max_retries || !retry.should_retry(&status) {
+                break (status, details);
+            }
+            info!(
+                "{:?} attempt {attempts}: {status}, retrying",
+                self.test_case
+            );
+            self.output
+                .set_status(&status)
+                .or_log_error("couldn't save job status");
+            self.output.new_attempt();
+            self.notify_started();
+            attempts += 1;
+        };
+        // Only a plain pass is relabelled, a skip or warning says more about
+        // the final attempt than that it was retried.
+        let status = match status {
+            TestStatus::Completed(TestResult { exit_code: 0 }) if attempts > 1 => {
+                TestStatus::Flaky { attempts }
             }
+            status => status,
         };
-        self.notifier.notify_completion(status.clone());
+        // Cancellations get recorded too, the output is kept around for
+        // post-mortems even though it's not a result.
+        self.output
+            .set_status(&status)
+            .or_log_error("couldn't save job status");
+        let output_dir = self.output.path().map(|p| p.to_owned());
+        // Release the output before announcing it, so dependents can use it.
+        drop(self.output);
+        self.notifier
+            .notify_completion(status.clone(), details, output_dir);
         status
     }
 }
 
+// How the child process of a job came to an end, when that didn't involve an error.
+enum ChildOutcome {
+    Exited(ExitStatus),
+    Canceled,
+    TimedOut,
+    // The kernel killed something in the job's cgroup for using too much
+    // memory.
+    OomKilled,
+}
+
 // An identifier that uniquely identifies a TestCase among all that can exist for a given Manager.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TestCaseId(String);
@@ -835,12 +1776,14 @@ impl Debug for TestCase {
 }
 
 impl TestCase {
-    pub fn new(commit: Commit, test: Arc<Test>) -> Self {
-        Self {
-            cache_hash: test.cache_policy.cache_hash(&commit),
+    // Fails if the commit hasn't been resolved for the test's cache policy, see
+    // commit_lookups.
+    pub fn new(commit: Commit, test: Arc<Test>) -> anyhow::Result<Self> {
+        Ok(Self {
+            cache_hash: test.cache_policy.cache_hash(&commit)?,
             test,
             commit_hash: commit.hash,
-        }
+        })
     }
 
     // Returns the hash that should be used to store the result in the result
@@ -874,16 +1817,26 @@ impl GraphNode<TestCaseId> for TestCase {
 
 pub type ExitCode = i32;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum TestStatus {
     Enqueued,
     Started,
     Canceled,
+    // The job was shut down because it ran for longer than Test::timeout.
+    TimedOut,
+    // These two are like Completed, except that the user configured the exit
+    // code to have a special meaning via Test::exit_codes.
+    Skipped(TestResult),
+    Warning(TestResult),
     // anyhow::Error doesn't implement Clone. We don't really need the overall
     // error handling fanciness since this is just part of the normal flow of
     // the program, so we just define this as a normal case among this enum.
     Error(String), // This includes the test getting terminated by a signal.
     Completed(TestResult),
+    // Succeeded, but only after being retried because the earlier attempts
+    // didn't. Counts as a success for everything else.
+    Flaky { attempts: u32 },
 }
 
 impl Display for TestStatus {
@@ -892,8 +1845,32 @@ impl Display for TestStatus {
             Self::Enqueued => write!(f, "Enqueued"),
             Self::Started => write!(f, "Started"),
             Self::Canceled => write!(f, "Cancelled"),
+            Self::TimedOut => write!(f, "Timed out"),
+            Self::Skipped(result) => write!(f, "Skipped - {}", result),
+            Self::Warning(result) => write!(f, "Completed with warnings - {}", result),
             Self::Error(msg) => write!(f, "Error while testing - {:?}", msg),
             Self::Completed(result) => write!(f, "Completed - exit code {}", result),
+            Self::Flaky { attempts } => write!(f, "Flaky - succeeded on attempt {attempts}"),
+        }
+    }
+}
+
+impl TestStatus {
+    // Whether a job that finished with this status produced a result that can
+    // be reused instead of running the job again.
+    pub fn is_cacheable(&self) -> bool {
+        matches!(
+            self,
+            Self::Completed(_) | Self::Skipped(_) | Self::Warning(_) | Self::Flaky { .. }
+        )
+    }
+
+    // Whether jobs that depend on a job with this status are allowed to run.
+    pub fn satisfies_dependents(&self) -> bool {
+        match self {
+            Self::Completed(result) => result.exit_code == 0,
+            Self::Skipped(_) | Self::Warning(_) | Self::Flaky { .. } => true,
+            _ => false,
         }
     }
 }
@@ -905,6 +1882,7 @@ impl From<TestStatus> for anyhow::Result<()> {
             TestStatus::Completed(TestResult { exit_code: code }) => {
                 bail!("Test failed with exit code {}", code)
             }
+            TestStatus::Skipped(_) | TestStatus::Warning(_) | TestStatus::Flaky { .. } => Ok(()),
             _ => bail!("{}", s),
         }
     }
@@ -923,10 +1901,68 @@ impl Display for TestResult {
     }
 }
 
+// Timing and resource usage of the test command.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobUsage {
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub system_time: Duration,
+    // Peak resident set size in bytes. This is of the biggest single process,
+    // not the total.
+    pub max_rss: u64,
+}
+
+impl JobUsage {
+    fn new(started_at: SystemTime, start: Instant, rusage: Rusage) -> Self {
+        Self {
+            started_at,
+            finished_at: SystemTime::now(),
+            wall_time: start.elapsed(),
+            user_time: rusage.user_time,
+            system_time: rusage.system_time,
+            max_rss: rusage.max_rss,
+        }
+    }
+}
+
+impl Display for JobUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "took {}, CPU {} user {} sys, peak RSS {}",
+            precise_duration(self.wall_time),
+            precise_duration(self.user_time),
+            precise_duration(self.system_time),
+            human_bytes(self.max_rss)
+        )
+    }
+}
+
+// What we found out about a job, apart from its status. Only final statuses
+// have these.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobDetails {
+    // None if the test command never ran.
+    pub usage: Option<JobUsage>,
+    // None if the test command didn't write one.
+    pub report: Option<Report>,
+    // The first line of output that matched Test::failure_patterns, if the
+    // job failed.
+    pub failure_excerpt: Option<String>,
+    // The metrics of the same test at the parent commit, that the ones in the
+    // report get compared with. None if there was nothing to compare.
+    pub parent_metrics: Option<BTreeMap<String, f64>>,
+}
+
 #[derive(Debug)]
 pub struct Notification {
     pub test_case: TestCase,
     pub status: TestStatus,
+    pub details: JobDetails,
+    // Where in the database the output is, see Database::attempt_relpath.
+    pub attempt: Option<u64>,
 }
 
 #[cfg(test)]
@@ -937,10 +1973,11 @@ mod tests {
         env,
         fs::{self, remove_file, File},
         io::{self, BufRead as _},
+        iter,
         mem::ManuallyDrop,
         path::PathBuf,
         thread::panicking,
-        time::Duration,
+        time::{Duration, SystemTime},
     };
 
     use anyhow::bail;
@@ -951,14 +1988,17 @@ mod tests {
     use test_case::test_case;
     use tokio::{
         select,
-        time::{sleep, sleep_until, Instant},
+        time::{sleep, sleep_until, timeout, Instant},
     };
 
     use crate::{
+        database::{LookupExplanation, RepoId},
         git::{
             test_utils::{TempRepo, WorktreeExt},
-            CommitHash, TempWorktree,
+            CommitHash, TempWorktree, Worktree as _,
         },
+        process::CommandExt as _,
+        report::{ReportFormat, SubtestStatus},
         resource::Resource,
         test_utils::{path_exists, timeout_5s},
     };
@@ -1110,7 +2150,7 @@ mod tests {
         }
 
         // Blocks until the script is started for the given commit hash.
-        pub async fn started(&self, hash: &CommitHash) -> StartedTestScript {
+        pub async fn started(&self, hash: &CommitHash) -> StartedTestScript<'_> {
             let pid_path = self.signalling_path(Self::PID_FILENAME_PREFIX, hash);
             path_exists(&pid_path).await;
             let content = fs::read_to_string(pid_path).expect("couldn't read PID file");
@@ -1153,8 +2193,20 @@ mod tests {
                     [].into()
                 },
                 shutdown_grace_period: Duration::from_secs(5),
+                timeout: None,
+                exit_codes: ExitCodeOutcomes::default(),
+                artifacts: vec![],
                 cache_policy,
-                config_hash: 0,
+                cache_ttl: CacheTtl::default(),
+                retry: RetryPolicy::default(),
+                report_file: None,
+                metrics: vec![],
+                failure_patterns: vec![],
+                limits: Limits::default(),
+                sandbox: None,
+                tty: false,
+                config_json: serde_json::Value::Null,
+                config_hash: ConfigHash::default(),
                 depends_on: depends_on.into_iter().collect(),
             }
         }
@@ -1222,6 +2274,70 @@ mod tests {
         }
     }
 
+    struct OneRun {
+        _db_dir: TempDir,
+        db: Arc<Database>,
+        test_case: TestCase,
+        // Everything up to and including the first final status.
+        notifs: Vec<Arc<Notification>>,
+    }
+
+    impl OneRun {
+        fn statuses(&self) -> Vec<TestStatus> {
+            self.notifs.iter().map(|n| n.status.clone()).collect()
+        }
+
+        fn last(&self) -> &Notification {
+            self.notifs.last().unwrap()
+        }
+    }
+
+    // Run a single test on a single commit with a fresh database, giving it a
+    // worktree if it needs one, and wait up to 20s for it to finish.
+    async fn run_one(repo: &Arc<TempRepo>, commit: &Commit, test: Test) -> OneRun {
+        let test = Arc::new(test);
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                .expect("couldn't setup result DB"),
+        );
+        let num_worktrees = test
+            .needs_resources
+            .get(&ResourceKey::Worktree)
+            .copied()
+            .unwrap_or(0);
+        let m = Manager::new(
+            repo.clone(),
+            db.clone(),
+            Arc::new(Pools::new([(
+                ResourceKey::Worktree,
+                worktree_resources(repo, num_worktrees).await,
+            )])),
+            Dag::new([test.clone()]).expect("couldn't build test DAG"),
+        );
+        let mut results = m.results();
+        m.set_revisions([commit.clone()]).await.unwrap();
+        let mut notifs = Vec::new();
+        timeout(Duration::from_secs(20), async {
+            loop {
+                let notif = results.recv().await.unwrap();
+                let done = !matches!(notif.status, TestStatus::Enqueued | TestStatus::Started);
+                notifs.push(notif);
+                if done {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("job didn't finish after 20s, got {notifs:?}"));
+        OneRun {
+            _db_dir: db_dir,
+            db,
+            test_case: TestCase::new(commit.clone(), test).unwrap(),
+            notifs,
+        }
+    }
+
     fn dump_want_statuses(want: &HashMap<TestCaseId, (TestCase, VecDeque<TestStatus>)>) -> String {
         let mut ret = String::from("");
         for (test_case, statuses) in want.values() {
@@ -1398,18 +2514,19 @@ mod tests {
                 &self.cache_policies,
                 &self.needs_worktree
             )
-            .map(|(i, script, &cache_policy, &needs_worktree)| {
+            .map(|(i, script, cache_policy, &needs_worktree)| {
                 let dep_names = self
                     .dependencies
                     .iter()
                     .filter(|(from_idx, _)| *from_idx == i)
                     .map(|(_, to_idx)| TestName::new(format!("test_{to_idx}")));
-                script.as_test(cache_policy, needs_worktree, dep_names)
+                script.as_test(cache_policy.clone(), needs_worktree, dep_names)
             });
             let manager = Manager::new(
                 repo.clone(),
                 Arc::new(
-                    Database::create_or_open(db_dir.path()).expect("couldn't setup result DB"),
+                    Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                        .expect("couldn't setup result DB"),
                 ),
                 Arc::new(Pools::new([(
                     ResourceKey::Worktree,
@@ -1444,11 +2561,13 @@ mod tests {
                 commit.borrow().to_owned(),
                 self.manager
                     .tests
+                    .lock()
                     .nodes()
                     .nth(test_idx)
                     .expect("bad test idx")
                     .clone(),
             )
+            .unwrap()
         }
     }
 
@@ -1670,6 +2789,147 @@ mod tests {
         assert_eq!(f.scripts[2].num_runs(&orig_commit.hash), 1);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn should_cache_by_paths() {
+        let repo = TempRepo::new().await.unwrap();
+        let paths = vec!["src".to_owned(), "Makefile".to_owned()];
+        let policy = CachePolicy::ByPaths(paths.clone());
+        let lookups = CommitLookups {
+            paths: paths.clone(),
+            ..Default::default()
+        };
+        let cache_hash = |commit: Commit| {
+            let repo = &repo;
+            let lookups = &lookups;
+            let policy = &policy;
+            async move {
+                let commit = repo
+                    .resolve_commits(vec![commit], lookups)
+                    .await
+                    .unwrap()
+                    .pop()
+                    .unwrap();
+                policy.cache_hash(&commit).unwrap().unwrap()
+            }
+        };
+
+        // Makefile doesn't exist yet, that's fine.
+        let base = cache_hash(
+            repo.commit_file("src/main.c", "int main;", "base")
+                .await
+                .unwrap(),
+        )
+        .await;
+        let docs = cache_hash(repo.commit_file("README", "hello", "docs").await.unwrap()).await;
+        assert_eq!(base, docs);
+        let makefile =
+            cache_hash(repo.commit_file("Makefile", "all:", "make").await.unwrap()).await;
+        assert_ne!(makefile, docs);
+        let src = cache_hash(repo.commit_file("src/lib.c", "", "src").await.unwrap()).await;
+        assert_ne!(src, makefile);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_cache_by_patch_id_and_message() {
+        let repo = TempRepo::new().await.unwrap();
+        let base = repo.commit_file("a", "a", "base").await.unwrap();
+        let orig = repo.commit_file("b", "b", "orig").await.unwrap();
+        // Rebase orig onto an unrelated upstream change.
+        repo.checkout(&base.hash).await.unwrap();
+        repo.commit_file("c", "c", "upstream").await.unwrap();
+        repo.git(["cherry-pick"])
+            .arg(&orig.hash)
+            .execute()
+            .await
+            .unwrap();
+        let rebased = repo.rev_parse("HEAD").await.unwrap().unwrap();
+        repo.git(["commit", "--amend", "-m", "reworded"])
+            .execute()
+            .await
+            .unwrap();
+        let reworded = repo.rev_parse("HEAD").await.unwrap().unwrap();
+        let empty = repo.commit("orig").await.unwrap();
+
+        let commits = repo
+            .resolve_commits(
+                vec![orig, rebased, reworded, empty],
+                &CommitLookups {
+                    patch_id: true,
+                    message: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let hashes = |policy: CachePolicy| {
+            commits
+                .iter()
+                .map(|c| policy.cache_hash(c).unwrap().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let patch_ids = hashes(CachePolicy::ByPatchId);
+        assert_eq!(patch_ids[0], patch_ids[1]);
+        assert_eq!(patch_ids[0], patch_ids[2]);
+        assert_ne!(patch_ids[0], patch_ids[3]);
+
+        let messages = hashes(CachePolicy::ByMessage);
+        assert_eq!(messages[0], messages[1]);
+        assert_ne!(messages[0], messages[2]);
+        assert_eq!(messages[0], messages[3]);
+
+        // Forgetting to resolve the commit is a bug, but not a crash.
+        let unresolved = repo.rev_parse("HEAD").await.unwrap().unwrap();
+        assert!(CachePolicy::ByPatchId.cache_hash(&unresolved).is_err());
+        assert!(CachePolicy::ByMessage.cache_hash(&unresolved).is_err());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_restart_on_config_change() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let commit = f
+            .repo
+            .commit("hello,")
+            .await
+            .expect("couldn't create test commit");
+        f.manager.set_revisions(vec![commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commit.hash), 1);
+
+        // Same config, nothing should happen.
+        let same_tests = Dag::new([Arc::new(f.scripts[0].as_test(
+            CachePolicy::ByCommit,
+            true,
+            [],
+        ))])
+        .unwrap();
+        assert!(!f.manager.set_tests(same_tests).unwrap());
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commit.hash), 1);
+
+        let mut test = f.scripts[0].as_test(CachePolicy::ByCommit, true, []);
+        test.config_hash = ConfigHash::of(&serde_json::json!("new config"));
+        assert!(f
+            .manager
+            .set_tests(Dag::new([Arc::new(test)]).unwrap())
+            .unwrap());
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commit.hash), 2);
+
+        // Adding a test isn't allowed.
+        let other = TestScript::new(TestName::new("other"), true);
+        assert!(f
+            .manager
+            .set_tests(
+                Dag::new([
+                    Arc::new(f.scripts[0].as_test(CachePolicy::ByCommit, true, [])),
+                    Arc::new(other.as_test(CachePolicy::ByCommit, true, [])),
+                ])
+                .unwrap()
+            )
+            .is_err());
+    }
+
     #[test_case(1, 1 ; "single worktree, one test")]
     #[test_case(4, 1 ; "multiple worktrees, one test")]
     #[test_case(4, 4 ; "multiple worktrees, multiple tests")]
@@ -1738,14 +2998,29 @@ mod tests {
                 (ResourceKey::UserToken("foo".into()), 1),
             ]),
             shutdown_grace_period: Duration::from_secs(5),
+            timeout: None,
+            exit_codes: ExitCodeOutcomes::default(),
+            artifacts: vec![],
             cache_policy: CachePolicy::ByCommit,
-            config_hash: 0,
+            cache_ttl: CacheTtl::default(),
+            retry: RetryPolicy::default(),
+            report_file: None,
+            metrics: vec![],
+            failure_patterns: vec![],
+            limits: Limits::default(),
+            sandbox: None,
+            tty: false,
+            config_json: serde_json::Value::Null,
+            config_hash: ConfigHash::default(),
             depends_on: vec![],
         }];
         let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
         let m = Manager::new(
             repo.clone(),
-            Arc::new(Database::create_or_open(db_dir.path()).expect("couldn't setup result DB")),
+            Arc::new(
+                Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                    .expect("couldn't setup result DB"),
+            ),
             Arc::new(Pools::new(
                 [(ResourceKey::Worktree, worktree_resources(&repo, 4).await)]
                     .into_iter()
@@ -1795,8 +3070,20 @@ mod tests {
             ]
             .into(),
             shutdown_grace_period: Duration::from_secs(5),
+            timeout: None,
+            exit_codes: ExitCodeOutcomes::default(),
+            artifacts: vec![],
             cache_policy: CachePolicy::ByCommit,
-            config_hash: 0,
+            cache_ttl: CacheTtl::default(),
+            retry: RetryPolicy::default(),
+            report_file: None,
+            metrics: vec![],
+            failure_patterns: vec![],
+            limits: Limits::default(),
+            sandbox: None,
+            tty: false,
+            config_json: serde_json::Value::Null,
+            config_hash: ConfigHash::default(),
             depends_on: vec![],
         })])
         .expect("couldn't build test DAG");
@@ -1827,7 +3114,10 @@ mod tests {
         );
         let m = Manager::new(
             repo.clone(),
-            Arc::new(Database::create_or_open(db_dir.path()).expect("couldn't setup result DB")),
+            Arc::new(
+                Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                    .expect("couldn't setup result DB"),
+            ),
             Arc::new(resource_pools),
             tests,
         );
@@ -1878,6 +3168,141 @@ mod tests {
         assert_eq!(env.get("LIMMAT_RESOURCE_other_resource_0"), None);
     }
 
+    // Dependent jobs should be able to find the output of their dependencies,
+    // whether they were run just now or found in the database.
+    #[test_log::test(tokio::test)]
+    async fn should_pass_dep_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = nonempty_temp_repo().await;
+        let commit = repo
+            .commit("hello,")
+            .await
+            .expect("couldn't create test commit");
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                .expect("couldn't setup result DB"),
+        );
+        let bash_test =
+            |name: &str, script: String, cache_policy: CachePolicy, depends_on: Vec<TestName>| {
+                Arc::new(Test {
+                    name: TestName::new(name),
+                    program: OsString::from("bash"),
+                    args: vec!["-c".into(), script.into()],
+                    needs_resources: [].into(),
+                    shutdown_grace_period: Duration::from_secs(5),
+                    timeout: None,
+                    exit_codes: ExitCodeOutcomes::default(),
+                    artifacts: vec![],
+                    cache_policy,
+                    cache_ttl: CacheTtl::default(),
+                    retry: RetryPolicy::default(),
+                    report_file: None,
+                    metrics: vec![],
+                    failure_patterns: vec![],
+                    limits: Limits::default(),
+                    sandbox: None,
+                    tty: false,
+                    config_json: serde_json::Value::Null,
+                    config_hash: ConfigHash::default(),
+                    depends_on,
+                })
+            };
+        let dep_test = bash_test(
+            "my-dep",
+            "echo dep output".into(),
+            CachePolicy::ByCommit,
+            vec![],
+        );
+        // The dependent is not cached, so it runs every time.
+        let test = bash_test(
+            "my_test",
+            format!(
+                "cat $LIMMAT_DEP_my_dep_DIR/stdout.txt >> {:?}",
+                temp_dir.path().join("out.txt")
+            ),
+            CachePolicy::NoCaching,
+            vec![dep_test.name.clone()],
+        );
+        for _ in 0..2 {
+            let m = Manager::new(
+                repo.clone(),
+                db.clone(),
+                Arc::new(Pools::new([])),
+                Dag::new([dep_test.clone(), test.clone()]).expect("couldn't build test DAG"),
+            );
+            m.set_revisions([commit.clone()])
+                .await
+                .expect("set_revisions failed");
+            m.settled().await;
+        }
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("out.txt")).unwrap(),
+            "dep output\ndep output\n"
+        );
+    }
+
+    // If something else is busy with a dependency's output, the dependent
+    // should wait for it instead of failing.
+    #[test_log::test(tokio::test)]
+    async fn should_wait_for_busy_dep_output() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let db_dir = TempDir::new().unwrap();
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into())).unwrap(),
+        );
+        let dep_script = TestScript::new(TestName::new("dep"), false);
+        let dep_case = TestCase::new(
+            commit.clone(),
+            Arc::new(dep_script.as_test(CachePolicy::ByCommit, false, [])),
+        )
+        .unwrap();
+        let mut dep_output = db.create_output(&dep_case).unwrap();
+        assert!(dep_output.claim().unwrap());
+        dep_output
+            .set_status(&TestStatus::Completed(TestResult { exit_code: 0 }))
+            .unwrap();
+        drop(dep_output);
+        let dep_dir = db
+            .attempts(dep_case.storage_hash(), &dep_case.test.name)
+            .unwrap()[0]
+            .dir
+            .clone();
+        // Pretend someone else is re-running the dependency.
+        let mut rerun_output = db.create_output(&dep_case).unwrap();
+        assert!(rerun_output.claim().unwrap());
+
+        let script = TestScript::new(TestName::new("test"), false);
+        let test_case = TestCase::new(
+            commit.clone(),
+            Arc::new(script.as_test(CachePolicy::NoCaching, false, [dep_case.test.name.clone()])),
+        )
+        .unwrap();
+        let job = TestJobBuilder::new(
+            CancellationToken::new(),
+            test_case.clone(),
+            db.create_output(&test_case).unwrap(),
+            Arc::new(base_job_env(repo.path())),
+            Vec::new(), // wait_for
+        )
+        .with_dep_dirs(vec![(dep_case.test.name.clone(), dep_dir)])
+        .build();
+        let pools = Pools::new([]);
+        let mut run = pin!(job.run(db.clone(), &pools, repo.path()));
+        assert!(
+            timeout(Duration::from_secs(1), &mut run).await.is_err(),
+            "job didn't wait for dependency output"
+        );
+        assert!(!script.was_started(&commit.hash));
+
+        drop(rerun_output);
+        assert_eq!(
+            timeout_5s(run).await.expect("job didn't finish"),
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+    }
+
     #[test_log::test(tokio::test)]
     async fn should_not_start_canceled() {
         let f = TestScriptFixture::builder()
@@ -2068,6 +3493,908 @@ mod tests {
         };
     }
 
+    #[test_log::test(tokio::test)]
+    async fn should_wait_for_other_writer() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let commit = f
+            .repo
+            .commit("hello,")
+            .await
+            .expect("couldn't create test commit");
+        let test_case = f.test_case(&commit, 0);
+        // Pretend another process is already running this test case.
+        let mut other_output = f
+            .manager
+            .result_db
+            .create_output(&test_case)
+            .expect("couldn't create output");
+        assert!(other_output.claim().expect("couldn't claim output"));
+
+        let mut results = f.manager.results();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        expect_notifs_20s(
+            &mut results,
+            [(test_case.clone(), vec![TestStatus::Enqueued].into())],
+        )
+        .await
+        .expect("bad test result");
+        // Same problem as should_wait_for_dependencies, we just have to wait
+        // some arbitrary time.
+        sleep(Duration::from_secs(1)).await;
+        assert!(!f.scripts[0].was_started(&commit.hash));
+
+        // Once the "other process" is done, we should pick up its result
+        // instead of running the test ourselves.
+        other_output
+            .set_status(&TestStatus::Completed(TestResult { exit_code: 0 }))
+            .unwrap();
+        drop(other_output);
+        expect_notifs_20s(
+            &mut results,
+            [(
+                test_case.clone(),
+                vec![TestStatus::Completed(TestResult { exit_code: 0 })].into(),
+            )],
+        )
+        .await
+        .expect("bad test result");
+        expect_no_more_results(&mut results, &f.manager)
+            .await
+            .unwrap();
+        assert!(!f.scripts[0].was_started(&commit.hash));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_time_out() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo
+            .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+            .await
+            .expect("couldn't create test commit");
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            timeout: Some(Duration::from_secs(1)),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::TimedOut,
+            ]
+        );
+        // It should have been shut down like a canceled job.
+        timeout_5s(script.started(&commit.hash).await.sigtermed())
+            .await
+            .expect("timed out script wasn't SIGTERMed");
+        // Timeouts don't count as results.
+        assert!(run.db.lookup_result(&run.test_case).unwrap().is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_save_overlapping_artifacts() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "mkdir -p out/sub; echo hello > out/sub/file; ln -s file out/sub/link".into(),
+            ],
+            artifacts: vec!["out".into(), "out/*".into()],
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.last().status,
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+        let artifacts_dir = run
+            .db
+            .lookup_result(&run.test_case)
+            .unwrap()
+            .unwrap()
+            .artifacts_dir();
+        assert_eq!(
+            fs::read_to_string(artifacts_dir.join("out/sub/link")).unwrap(),
+            "hello\n"
+        );
+    }
+
+    // Stuff that escaped the process group should get killed too, if cgroups
+    // are available.
+    #[test_log::test(tokio::test)]
+    async fn should_kill_escaped_processes() {
+        if let Err(err) = CgroupRoot::create() {
+            warn!("Skipping test, can't create cgroups: {err:#}");
+            return;
+        }
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let pid_dir = TempDir::new().unwrap();
+        let pid_file = pid_dir.path().join("pid");
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                format!("setsid sleep 1000 & echo $! > {pid_file:?}; sleep 1000").into(),
+            ],
+            timeout: Some(Duration::from_secs(1)),
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::TimedOut,
+            ]
+        );
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        // It might not have been reaped yet, but it shouldn't be running.
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        assert!(
+            stat.is_empty() || stat.contains(") Z "),
+            "setsid'd process still alive: {stat}"
+        );
+    }
+
+    // The job should still be able to get at the stuff it needs, even though
+    // it's all under the /tmp that the sandbox hides.
+    #[test_log::test(tokio::test)]
+    async fn should_run_in_sandbox() {
+        let sandbox = Sandbox {
+            network: false,
+            readonly_paths: vec!["/".into()],
+            private_tmp: true,
+        };
+        let mut cmd = std::process::Command::new("true");
+        sandbox.add_command(&mut cmd, &[], &[]).unwrap();
+        if !cmd.status().unwrap().success() {
+            warn!("Skipping test, can't create namespaces");
+            return;
+        }
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                r#"
+                    set -eux
+                    echo hello > file
+                    [ -w "$(dirname "$LIMMAT_RESULT_FILE")" ]
+                    [ "$(git rev-parse HEAD)" == "$LIMMAT_COMMIT" ]
+                    ! touch "$LIMMAT_ORIGIN/file"
+                "#
+                .into(),
+            ],
+            sandbox: Some(sandbox),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::Completed(TestResult { exit_code: 0 }),
+            ]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_enforce_memory_limit() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            // Try to hold 256MiB in a shell variable.
+            args: vec![
+                "-c".into(),
+                "x=$(head -c 268435456 /dev/zero | tr '\\0' a); echo ${#x}".into(),
+            ],
+            limits: Limits {
+                memory_max: Some(64 << 20),
+                ..Default::default()
+            },
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        // This is what the manager is gonna get too.
+        let memory_cgroup = CgroupRoot::create().is_ok_and(|c| c.has_controller("memory"));
+        let run = run_one(&repo, &commit, test).await;
+        let want_status = if memory_cgroup {
+            TestStatus::Error("OOM: exceeded memory_max of 64.0 MiB".into())
+        } else {
+            // RLIMIT_AS makes bash's allocation fail, which it treats as fatal.
+            TestStatus::Completed(TestResult { exit_code: 2 })
+        };
+        assert_eq!(run.last().status, want_status);
+    }
+
+    // Slots that jobs leaked should come back once everything's finished.
+    #[test_log::test(tokio::test)]
+    async fn should_reset_jobserver() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Arc::new(Test {
+            program: "bash".into(),
+            // Take a token and never give it back.
+            args: vec![
+                "-c".into(),
+                r#"fds=${MAKEFLAGS#*--jobserver-auth=}; dd bs=1 count=1 status=none <&${fds%,*}"#
+                    .into(),
+            ],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        });
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                .expect("couldn't setup result DB"),
+        );
+        let jobserver = Arc::new(Jobserver::new(2).unwrap());
+        let m = Manager::new(
+            repo.clone(),
+            db.clone(),
+            Arc::new(Pools::new([])),
+            Dag::new([test.clone()]).expect("couldn't build test DAG"),
+        )
+        .with_jobserver(Some(jobserver.clone()));
+        let mut results = m.results();
+        m.set_revisions([commit.clone()]).await.unwrap();
+        expect_notifs_20s(
+            &mut results,
+            [(
+                TestCase::new(commit.clone(), test).unwrap(),
+                vec![
+                    TestStatus::Enqueued,
+                    TestStatus::Started,
+                    TestStatus::Completed(TestResult { exit_code: 0 }),
+                ]
+                .into(),
+            )],
+        )
+        .await
+        .expect("bad test result");
+        timeout_5s(m.settled())
+            .await
+            .expect("manager didn't settle");
+        assert_eq!(jobserver.available().unwrap(), 2);
+    }
+
+    #[test_case(TestStatus::Completed(TestResult { exit_code: 0 }), true ; "success")]
+    #[test_case(TestStatus::Warning(TestResult { exit_code: 3 }), true ; "warning")]
+    #[test_case(TestStatus::Completed(TestResult { exit_code: 1 }), false ; "failure")]
+    #[test_log::test(tokio::test)]
+    async fn should_expire_results(status: TestStatus, is_success: bool) {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+            .expect("couldn't setup result DB");
+        let test_case = |success_s: Option<u64>, failure_s: Option<u64>| {
+            TestCase::new(
+                commit.clone(),
+                Arc::new(Test {
+                    cache_ttl: CacheTtl {
+                        success: success_s.map(Duration::from_secs),
+                        failure: failure_s.map(Duration::from_secs),
+                    },
+                    ..script.as_test(CachePolicy::ByCommit, true, [])
+                }),
+            )
+            .unwrap()
+        };
+        let mut output = db.create_output(&test_case(None, None)).unwrap();
+        assert!(output.claim().unwrap());
+        output.set_status(&status).unwrap();
+        drop(output);
+        assert!(db
+            .lookup_result(&test_case(Some(3600), Some(3600)))
+            .unwrap()
+            .is_some());
+
+        // Pretend it was stored 2 hours ago.
+        let result_path = db
+            .latest_entry(&test_case(None, None))
+            .unwrap()
+            .unwrap()
+            .path()
+            .join("result.json");
+        let mut result: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&result_path).unwrap()).unwrap();
+        result["finished_at"] =
+            serde_json::to_value(SystemTime::now() - Duration::from_secs(2 * 60 * 60)).unwrap();
+        fs::write(&result_path, result.to_string()).unwrap();
+
+        // Only the TTL for the entry's outcome applies.
+        let success_ttl = test_case(Some(3600), None);
+        let failure_ttl = test_case(None, Some(3600));
+        let (expired, fresh) = if is_success {
+            (success_ttl, failure_ttl)
+        } else {
+            (failure_ttl, success_ttl)
+        };
+        assert!(db.lookup_result(&expired).unwrap().is_none());
+        assert!(db.lookup_result(&fresh).unwrap().is_some());
+        assert!(matches!(
+            db.explain_lookup(&expired).unwrap(),
+            LookupExplanation::Expired { ttl, .. } if ttl == Duration::from_secs(3600)
+        ));
+        // Looking up an expired result leaves it alone, it only gets replaced
+        // once the test runs again.
+        assert!(result_path.exists());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn latest_entry_should_not_create_entry() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+            .expect("couldn't setup result DB");
+        let test_case = TestCase::new(
+            commit,
+            Arc::new(script.as_test(CachePolicy::ByCommit, true, [])),
+        )
+        .unwrap();
+        assert!(db.latest_entry(&test_case).is_err());
+        let pattern = format!(
+            "{}/**/{}",
+            db_dir.path().display(),
+            Database::result_relpath(&test_case).display()
+        );
+        assert_eq!(glob::glob(&pattern).unwrap().count(), 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_explain_uncacheable_result() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+            .expect("couldn't setup result DB");
+        let test_case = TestCase::new(
+            commit,
+            Arc::new(script.as_test(CachePolicy::ByCommit, true, [])),
+        )
+        .unwrap();
+        assert_eq!(
+            db.explain_lookup(&test_case).unwrap(),
+            LookupExplanation::NoEntry
+        );
+        let mut output = db.create_output(&test_case).unwrap();
+        assert!(output.claim().unwrap());
+        output.set_status(&TestStatus::TimedOut).unwrap();
+        drop(output);
+        assert_eq!(
+            db.explain_lookup(&test_case).unwrap(),
+            LookupExplanation::NotCacheable(TestStatus::TimedOut)
+        );
+    }
+
+    #[test_case(
+        RetryPolicy { max_retries: 2, on_failure: true, on_error: false }, 1,
+        ExitCodeOutcomes::default(),
+        TestStatus::Flaky { attempts: 2 }, 2
+        ; "flaky")]
+    #[test_case(
+        RetryPolicy { max_retries: 2, on_failure: true, on_error: true }, 5,
+        ExitCodeOutcomes::default(),
+        TestStatus::Completed(TestResult { exit_code: 1 }), 3
+        ; "out of retries")]
+    #[test_case(
+        RetryPolicy { max_retries: 2, on_failure: false, on_error: true }, 1,
+        ExitCodeOutcomes::default(),
+        TestStatus::Completed(TestResult { exit_code: 1 }), 1
+        ; "failure not retried")]
+    #[test_case(
+        RetryPolicy { max_retries: 2, on_failure: true, on_error: false }, 1,
+        ExitCodeOutcomes { warning: vec![0], ..Default::default() },
+        TestStatus::Warning(TestResult { exit_code: 0 }), 2
+        ; "warning after retry")]
+    #[test_log::test(tokio::test)]
+    async fn should_retry(
+        retry: RetryPolicy,
+        num_failures: usize,
+        exit_codes: ExitCodeOutcomes,
+        want_status: TestStatus,
+        want_runs: usize,
+    ) {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let runs_path = temp_dir.path().join("runs");
+        // Fails the first num_failures times.
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                format!(
+                    "echo run >> {runs_path:?}; [ $(wc -l < {runs_path:?}) -gt {num_failures} ]"
+                )
+                .into(),
+            ],
+            needs_resources: [].into(),
+            retry,
+            exit_codes,
+            ..script.as_test(CachePolicy::ByCommit, false, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        // Each attempt gets announced separately, so observers can find its
+        // output.
+        let mut want_notifs = vec![TestStatus::Enqueued];
+        want_notifs.extend(iter::repeat(TestStatus::Started).take(want_runs));
+        want_notifs.push(want_status);
+        assert_eq!(run.statuses(), want_notifs);
+
+        assert_eq!(
+            fs::read_to_string(&runs_path).unwrap().lines().count(),
+            want_runs
+        );
+        // Every run is recorded.
+        let attempts = run
+            .db
+            .attempts(run.test_case.storage_hash(), &run.test_case.test.name)
+            .unwrap();
+        assert_eq!(attempts.len(), want_runs);
+        assert!(attempts[..want_runs - 1]
+            .iter()
+            .all(|a| a.status == Some(TestStatus::Completed(TestResult { exit_code: 1 }))));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_record_usage() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "sleep 0.2; for i in $(seq 20000); do :; done".into(),
+            ],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, false, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.last().status,
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+        let usage = run
+            .last()
+            .details
+            .usage
+            .clone()
+            .expect("no usage in notification");
+
+        assert!(usage.wall_time >= Duration::from_millis(200), "{usage:?}");
+        assert!(usage.finished_at >= usage.started_at, "{usage:?}");
+        assert!(
+            usage.user_time + usage.system_time > Duration::ZERO,
+            "{usage:?}"
+        );
+        assert!(usage.max_rss > 0, "{usage:?}");
+        // It's stored with the result too.
+        let attempts = run
+            .db
+            .attempts(run.test_case.storage_hash(), &run.test_case.test.name)
+            .unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].usage.as_ref(), Some(&usage));
+        assert_eq!(
+            run.db
+                .lookup_result(&run.test_case)
+                .unwrap()
+                .unwrap()
+                .details()
+                .usage,
+            Some(usage)
+        );
+    }
+
+    #[test_case(
+        r#"{"summary": "1/2 failed", "metrics": {"foo": 1.5}}"#, 1,
+        TestStatus::Completed(TestResult { exit_code: 1 }), Some("1/2 failed")
+        ; "valid")]
+    #[test_case(
+        r#"{"summary": "#, 3,
+        TestStatus::Error("parsing $LIMMAT_RESULT_FILE: EOF while parsing a value at line 2 column 0 (exit code 3)".into()),
+        None
+        ; "invalid")]
+    #[test_log::test(tokio::test)]
+    async fn should_read_result_file(
+        report_json: &str,
+        exit_code: ExitCode,
+        want_status: TestStatus,
+        want_summary: Option<&str>,
+    ) {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                format!("echo '{report_json}' > $LIMMAT_RESULT_FILE; exit {exit_code}").into(),
+            ],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, false, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        let notif = run.last();
+
+        assert_eq!(notif.status, want_status);
+        let summary =
+            |details: &JobDetails| details.report.as_ref().and_then(|r| r.summary.clone());
+        assert_eq!(summary(&notif.details).as_deref(), want_summary);
+        if want_status.is_cacheable() {
+            // It gets stored in the database too.
+            let entry = run.db.lookup_result(&run.test_case).unwrap().unwrap();
+            assert_eq!(summary(&entry.details()).as_deref(), want_summary);
+            assert_eq!(entry.details().report.unwrap().metrics["foo"], 1.5);
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_read_report_file() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "mkdir -p out; printf 'ok 1 - foo\\nnot ok 2 - bar\\n' > out/report.tap; exit 1"
+                    .into(),
+            ],
+            report_file: Some(ReportFile {
+                format: ReportFormat::Tap,
+                path: "out/report.tap".into(),
+            }),
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::Completed(TestResult { exit_code: 1 }),
+            ]
+        );
+
+        let attempts = run
+            .db
+            .attempts(run.test_case.storage_hash(), &run.test_case.test.name)
+            .unwrap();
+        let report = attempts[0].report.as_ref().expect("no report stored");
+        assert_eq!(report.summary.as_deref(), Some("1/2 subtests failed"));
+        assert_eq!(
+            report
+                .subtests
+                .iter()
+                .map(|s| (s.name.as_str(), s.status))
+                .collect::<Vec<_>>(),
+            vec![("foo", SubtestStatus::Pass), ("bar", SubtestStatus::Fail)]
+        );
+    }
+
+    // A broken report shouldn't turn a pass into an error.
+    #[test_log::test(tokio::test)]
+    async fn should_ignore_malformed_report() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec!["-c".into(), "echo '<testsuite>' > report.xml".into()],
+            report_file: Some(ReportFile {
+                format: ReportFormat::Junit,
+                path: "report.xml".into(),
+            }),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.last().status,
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+        assert_eq!(run.last().details.report, None);
+    }
+
+    // Scraping metrics is best-effort, it shouldn't affect the result.
+    #[test_log::test(tokio::test)]
+    async fn should_scrape_metrics() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let metric = |name: &str, regex: &str| MetricSpec {
+            name: name.into(),
+            regex: Some(regex.into()),
+            max_increase: None,
+            max_decrease: None,
+        };
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                r"printf 'size: 12\n\xff\xfe\nspeed: fast\n'".into(),
+            ],
+            metrics: vec![
+                metric("size", r"size: (\d+)"),
+                metric("speed", r"speed: (.*)"),
+            ],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, false, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.last().status,
+            TestStatus::Completed(TestResult { exit_code: 0 })
+        );
+        let report = run.last().details.report.clone().expect("no report");
+        assert_eq!(report.metrics, [("size".to_owned(), 12.0)].into());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_compare_metrics_with_parent() {
+        let repo = nonempty_temp_repo().await;
+        let parent = repo.commit("10").await.unwrap();
+        let child = repo.commit("20").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        // The parent is slower, so the child only has something to compare
+        // with if it waits.
+        let test = Arc::new(Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                r#"size=$(git log -n1 --format=%s $LIMMAT_COMMIT)
+                   if [ $size = 10 ]; then sleep 1; fi
+                   echo "size: $size""#
+                    .into(),
+            ],
+            metrics: vec![MetricSpec {
+                name: "size".into(),
+                regex: Some(r"size: (\d+)".into()),
+                max_increase: Some(0.5),
+                max_decrease: None,
+            }],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, false, [])
+        });
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                .expect("couldn't setup result DB"),
+        );
+        let m = Manager::new(
+            repo.clone(),
+            db.clone(),
+            Arc::new(Pools::new([])),
+            Dag::new([test.clone()]).expect("couldn't build test DAG"),
+        );
+        let mut results = m.results();
+        m.set_revisions([parent.clone(), child.clone()])
+            .await
+            .unwrap();
+        let mut finished = HashMap::new();
+        timeout_5s(async {
+            while finished.len() < 2 {
+                let notif = results.recv().await.unwrap();
+                if !matches!(notif.status, TestStatus::Enqueued | TestStatus::Started) {
+                    finished.insert(notif.test_case.commit_hash.clone(), notif);
+                }
+            }
+        })
+        .await
+        .expect("jobs didn't finish");
+        // The parent's parent isn't being tested, so it has nothing to
+        // compare with.
+        assert_eq!(finished[&parent.hash].details.parent_metrics, None);
+        let want = Some([("size".to_owned(), 10.0)].into());
+        assert_eq!(finished[&child.hash].details.parent_metrics, want);
+        // It's stored with the result.
+        let test_case = TestCase::new(child.clone(), test).unwrap();
+        let attempts = db
+            .attempts(test_case.storage_hash(), &test_case.test.name)
+            .unwrap();
+        assert_eq!(attempts[0].parent_metrics, want);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_find_failure_excerpt() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                "echo 'error: from stdout'; echo 'warning: meh' >&2; echo '  error: from stderr' >&2; exit 1"
+                    .into(),
+            ],
+            failure_patterns: vec!["^error:".into(), "^\\s+error:".into()],
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::Completed(TestResult { exit_code: 1 }),
+            ]
+        );
+
+        // stderr is checked first.
+        let attempts = run
+            .db
+            .attempts(run.test_case.storage_hash(), &run.test_case.test.name)
+            .unwrap();
+        assert_eq!(
+            attempts[0].failure_excerpt.as_deref(),
+            Some("error: from stderr")
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn should_run_under_terminal() {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo.commit("hello").await.unwrap();
+        let script = TestScript::new(TestName::new("my_test"), true);
+        let test = Test {
+            program: "bash".into(),
+            args: vec![
+                "-c".into(),
+                // Coloured, like compilers do when they see a terminal.
+                r"[ -t 1 ] && [ -t 2 ] || exit 2; echo out; printf '\e[31merror:\e[0m err\n' >&2; exit 1"
+                    .into(),
+            ],
+            failure_patterns: vec!["^error:".into()],
+            tty: true,
+            needs_resources: [].into(),
+            ..script.as_test(CachePolicy::ByCommit, true, [])
+        };
+        let run = run_one(&repo, &commit, test).await;
+        assert_eq!(
+            run.statuses(),
+            vec![
+                TestStatus::Enqueued,
+                TestStatus::Started,
+                TestStatus::Completed(TestResult { exit_code: 1 }),
+            ]
+        );
+
+        // Both streams go into stdout.
+        let attempts = run
+            .db
+            .attempts(run.test_case.storage_hash(), &run.test_case.test.name)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(attempts[0].dir.join("stdout.txt")).unwrap(),
+            "out\n\x1b[31merror:\x1b[0m err\n"
+        );
+        assert!(!attempts[0].dir.join("stderr.txt").exists());
+        // The colours don't get in the way of the pattern, or end up in the
+        // excerpt.
+        assert_eq!(attempts[0].failure_excerpt.as_deref(), Some("error: err"));
+        assert_eq!(attempts[0].terminal_size, Some(TerminalSize::current()));
+    }
+
+    #[test_case(
+        ExitCodeOutcomes { error: vec![3], ..Default::default() },
+        TestStatus::Error("exit code 3 indicates an error".into()), false
+        ; "error")]
+    #[test_case(
+        ExitCodeOutcomes { skip: Some(3), ..Default::default() },
+        TestStatus::Skipped(TestResult { exit_code: 3 }), true
+        ; "skip")]
+    #[test_case(
+        ExitCodeOutcomes { warning: vec![4, 3], ..Default::default() },
+        TestStatus::Warning(TestResult { exit_code: 3 }), true
+        ; "warning")]
+    #[test_case(
+        ExitCodeOutcomes { error: vec![4], skip: Some(5), warning: vec![6] },
+        TestStatus::Completed(TestResult { exit_code: 3 }), false
+        ; "unmapped")]
+    #[test_log::test(tokio::test)]
+    async fn should_map_exit_codes(
+        exit_codes: ExitCodeOutcomes,
+        want_status: TestStatus,
+        dependent_should_run: bool,
+    ) {
+        let repo = nonempty_temp_repo().await;
+        let commit = repo
+            .commit(TestScript::exit_code_tag(3))
+            .await
+            .expect("couldn't create test commit");
+        let dep_script = TestScript::new(TestName::new("test_0"), true);
+        let dep_test = Arc::new(Test {
+            exit_codes,
+            ..dep_script.as_test(CachePolicy::ByCommit, true, [])
+        });
+        // This one doesn't have the special exit codes, so if it runs it will
+        // just fail.
+        let script = TestScript::new(TestName::new("test_1"), true);
+        let test = Arc::new(script.as_test(CachePolicy::ByCommit, true, [dep_test.name.clone()]));
+        let db_dir = TempDir::new().expect("couldn't make temp dir for result DB");
+        let db = Arc::new(
+            Database::create_or_open(db_dir.path(), &RepoId::Project("test".into()))
+                .expect("couldn't setup result DB"),
+        );
+        let m = Manager::new(
+            repo.clone(),
+            db.clone(),
+            Arc::new(Pools::new([(
+                ResourceKey::Worktree,
+                worktree_resources(&repo, 2).await,
+            )])),
+            Dag::new([dep_test.clone(), test.clone()]).expect("couldn't build test DAG"),
+        );
+        let mut results = m.results();
+        m.set_revisions([commit.clone()]).await.unwrap();
+        let dep_test_case = TestCase::new(commit.clone(), dep_test).unwrap();
+        expect_notifs_20s(
+            &mut results,
+            [
+                (
+                    dep_test_case.clone(),
+                    vec![
+                        TestStatus::Enqueued,
+                        TestStatus::Started,
+                        want_status.clone(),
+                    ]
+                    .into(),
+                ),
+                (
+                    TestCase::new(commit.clone(), test).unwrap(),
+                    if dependent_should_run {
+                        vec![
+                            TestStatus::Enqueued,
+                            TestStatus::Started,
+                            TestStatus::Completed(TestResult { exit_code: 3 }),
+                        ]
+                    } else {
+                        vec![
+                            TestStatus::Enqueued,
+                            TestStatus::Error("Dependency \"test_0\" unsuccessful".into()),
+                        ]
+                    }
+                    .into(),
+                ),
+            ],
+        )
+        .await
+        .expect("bad test result");
+        // Everything except errors should get cached.
+        let cached = db.lookup_result(&dep_test_case).unwrap();
+        if want_status.is_cacheable() {
+            assert_eq!(cached.map(|e| e.status().clone()), Some(want_status));
+        } else {
+            assert!(cached.is_none());
+        }
+    }
+
     #[test_log::test(tokio::test)]
     async fn should_not_require_worktree() {
         let f = TestScriptFixture::builder()
@@ -2135,4 +4462,34 @@ mod tests {
         // TODO: Test that changes to dependency config hashes invalidates
         // result cache.
     }
+
+    #[test_log::test(tokio::test)]
+    async fn should_name_failed_dependency() {
+        let f = TestScriptFixture::builder()
+            .dependencies([(1, 0)])
+            .num_worktrees(2)
+            .build()
+            .await;
+        let mut results = f.manager.results();
+        let commit = f.repo.commit(TestScript::exit_code_tag(1)).await.unwrap();
+        f.manager.set_revisions(vec![commit.clone()]).await.unwrap();
+        let status = timeout_5s(async {
+            loop {
+                let notif = results.recv().await.unwrap();
+                if notif.test_case.test.name == f.scripts[1].test_name
+                    && matches!(notif.status, TestStatus::Error(_))
+                {
+                    return notif.status.clone();
+                }
+            }
+        })
+        .await
+        .expect("depending test never completed");
+        // The error should name the dependency, not the test that depended on
+        // it.
+        assert_eq!(
+            status,
+            TestStatus::Error("Dependency \"test_0\" unsuccessful".into())
+        );
+    }
 }
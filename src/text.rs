@@ -48,12 +48,12 @@ impl<'a, T: Into<Line<'a>>> From<T> for Text<'a> {
 impl<'a> Text<'a> {
     // Render the text with style applied using ANSI commands. Use Display on the returned value
     // to write it out.
-    pub fn ansi(&self) -> RenderAnsi {
+    pub fn ansi(&self) -> RenderAnsi<'_> {
         RenderAnsi { text: self }
     }
 
     // Render to an HTML <pre> element.
-    pub fn html_pre(&self) -> RenderHtmlPre {
+    pub fn html_pre(&self) -> RenderHtmlPre<'_> {
         RenderHtmlPre { text: self }
     }
 
@@ -93,6 +93,10 @@ impl RenderHtmlPre<'_> {
             background: rgba(0, 255, 0, 0.3);
         }
 
+        .warning {
+            background: rgba(255, 215, 0, 0.3);
+        }
+
         .test-name {
             font-weight: bold;
         }
@@ -250,6 +254,7 @@ impl Display for RenderAnsiSpan<'_> {
             None => ColoredString::from(output),
             Some(Class::Failure) => output.on_red(),
             Some(Class::Success) => output.on_green(),
+            Some(Class::Warning) => output.on_yellow(),
             Some(Class::Error) => output.on_bright_red(),
             Some(Class::TestName) => output.bold(),
         };
@@ -280,6 +285,7 @@ impl Display for RenderHtmlSpan<'_> {
                 Some(Class::Error) => "error",
                 Some(Class::Success) => "success",
                 Some(Class::Failure) => "failure",
+                Some(Class::Warning) => "warning",
                 Some(Class::TestName) => "test-name",
             },
             self.span.content.as_ref()
@@ -298,5 +304,6 @@ pub enum Class {
     Error,
     Success,
     Failure,
+    Warning,
     TestName,
 }
@@ -1,4 +1,12 @@
-use std::{collections::HashMap, ffi::OsStr, io::Write, mem, sync::Arc};
+use std::{
+    cmp::max,
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    io::Write,
+    iter, mem,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use ansi_control_codes::control_sequences::{CUP, ED};
 use anyhow::{self, bail, Context as _};
@@ -9,15 +17,20 @@ use regex::Regex;
 use crate::{
     database::Database,
     git::{CommitHash, Worktree},
-    http::UiState,
-    test::{Notification, TestCase, TestName, TestStatus},
+    http::{url_path, UiState},
+    report::compare_metrics,
+    test::{JobDetails, JobUsage, Notification, TestCase, TestName, TestStatus},
     text::{Class, Line, Span, Text},
-    util::{Rect, ResultExt as _},
+    util::{precise_duration, Rect, ResultExt as _},
 };
 
 struct TrackedTestCase {
     test_case: TestCase,
     status: TestStatus,
+    details: JobDetails,
+    attempt: Option<u64>,
+    // When we heard that it started, if it's running.
+    started: Option<Instant>,
 }
 
 // Inner string key is test name. Here we awkwardly store this as a
@@ -37,10 +50,85 @@ fn update_tracked_cases(tracked_cases: &mut TrackedCases, notif: Arc<Notificatio
         TrackedTestCase {
             test_case: notif.test_case.clone(),
             status: notif.status.clone(),
+            details: notif.details.clone(),
+            attempt: notif.attempt,
+            started: (notif.status == TestStatus::Started).then(Instant::now),
         },
     );
 }
 
+// Remembers how long recent runs of each test took, to guess how long the
+// next one will take.
+#[derive(Default)]
+pub struct DurationStats {
+    // Keyed by start time, so that seeing the same run twice (e.g. once when
+    // it runs and again when it's found in the cache) doesn't count it twice.
+    runs: HashMap<TestName, BTreeMap<SystemTime, Duration>>,
+}
+
+impl DurationStats {
+    const MAX_RUNS: usize = 10;
+
+    pub fn record(&mut self, test_name: &TestName, usage: &JobUsage) {
+        let runs = self.runs.entry(test_name.clone()).or_default();
+        runs.insert(usage.started_at, usage.wall_time);
+        while runs.len() > Self::MAX_RUNS {
+            runs.pop_first();
+        }
+    }
+
+    // Median duration of the recent runs.
+    fn estimate(&self, test_name: &TestName) -> Option<Duration> {
+        let mut durations: Vec<Duration> = self.runs.get(test_name)?.values().copied().collect();
+        durations.sort();
+        durations.get(durations.len() / 2).copied()
+    }
+
+    // How much longer we expect the test case to take, None if we've got no
+    // idea.
+    fn remaining(&self, tracked_case: &TrackedTestCase, now: Instant) -> Option<Duration> {
+        let estimate = self.estimate(&tracked_case.test_case.test.name)?;
+        match tracked_case.status {
+            TestStatus::Enqueued => Some(estimate),
+            TestStatus::Started => {
+                let elapsed = tracked_case
+                    .started
+                    .map(|started| now.duration_since(started))
+                    .unwrap_or_default();
+                Some(estimate.saturating_sub(elapsed))
+            }
+            _ => Some(Duration::ZERO),
+        }
+    }
+}
+
+// One-line description of the work that's left, like "3 queued / 2 running /
+// ETA 4m10s". The ETA is pretty crude, it just assumes that things will keep
+// running with the current amount of parallelism.
+fn summarize(tracked_cases: &TrackedCases, stats: &DurationStats, now: Instant) -> String {
+    let pending: Vec<&TrackedTestCase> = tracked_cases
+        .values()
+        .flat_map(|cases| cases.values())
+        .filter(|c| matches!(c.status, TestStatus::Enqueued | TestStatus::Started))
+        .collect();
+    let running = pending
+        .iter()
+        .filter(|c| c.status == TestStatus::Started)
+        .count();
+    let summary = format!("{} queued / {running} running", pending.len() - running);
+    if pending.is_empty() {
+        return summary;
+    }
+    let work: Option<Duration> = pending.iter().map(|c| stats.remaining(c, now)).sum();
+    match work {
+        Some(work) => format!(
+            "{summary} / ETA {}",
+            precise_duration(work / running.max(1) as u32)
+        ),
+        None => format!("{summary} / ETA unknown"),
+    }
+}
+
 // Tracks the status of the tests being run by observing the notification
 // stream.
 pub struct StatusTracker<W: Worktree, O: Write> {
@@ -50,7 +138,9 @@ pub struct StatusTracker<W: Worktree, O: Write> {
     output: O,
     web_ui: Arc<UiState>,
     result_url_base: String,
+    history_url_base: String,
     home_url: String,
+    durations: DurationStats,
 }
 
 // This ought to be private to Tracker::reset, rust just doesn't seem to let you do that.
@@ -61,13 +151,15 @@ lazy_static! {
 
 impl<W: Worktree, O: Write> StatusTracker<W, O> {
     // Construct a tracker that will write the UI to the given outut. The URL
-    // base is used to generate hyperlinks to test results.
+    // bases are used to generate hyperlinks to test results and histories.
     pub fn new(
         repo: Arc<W>,
         output: O,
         web_ui: Arc<UiState>,
         result_url_base: impl Into<String>,
+        history_url_base: impl Into<String>,
         home_url: impl Into<String>,
+        durations: DurationStats,
     ) -> Self {
         Self {
             repo,
@@ -76,7 +168,9 @@ impl<W: Worktree, O: Write> StatusTracker<W, O> {
             output,
             web_ui,
             result_url_base: result_url_base.into(),
+            history_url_base: history_url_base.into(),
             home_url: home_url.into(),
+            durations,
         }
     }
 
@@ -92,15 +186,43 @@ impl<W: Worktree, O: Write> StatusTracker<W, O> {
 
     // Absorb a notification.
     pub fn update(&mut self, notif: Arc<Notification>) {
+        if let Some(usage) = &notif.details.usage {
+            if notif.status.is_cacheable() {
+                self.durations.record(&notif.test_case.test.name, usage);
+            }
+        }
         update_tracked_cases(&mut self.tracked_cases, notif);
     }
 
+    // Whether any jobs are running, i.e. whether the display goes stale if we
+    // don't repaint it.
+    pub fn is_busy(&self) -> bool {
+        self.tracked_cases
+            .values()
+            .flat_map(|cases| cases.values())
+            .any(|c| c.status == TestStatus::Started)
+    }
+
     // Update the UI by writing it to the output with fancy terminal escape
     // codes to overwrite what was previously written.
     pub fn repaint(&mut self, term_size: &Rect) -> anyhow::Result<()> {
-        let render = self
-            .output_buf
-            .render(&self.tracked_cases, &self.result_url_base)?;
+        let now = Instant::now();
+        let render = self.output_buf.render(
+            &self.tracked_cases,
+            &self.durations,
+            now,
+            &self.result_url_base,
+            &self.history_url_base,
+            term_size.cols,
+        )?;
+        let render = Text::from_iter(
+            iter::once(Line::from(summarize(
+                &self.tracked_cases,
+                &self.durations,
+                now,
+            )))
+            .chain(render.into_lines()),
+        );
 
         self.web_ui.set_log_buf(render.html_pre());
 
@@ -293,7 +415,11 @@ impl OutputBuffer {
     fn render<'a>(
         &'a self,
         statuses: &'a HashMap<CommitHash, HashMap<TestName, TrackedTestCase>>,
+        durations: &DurationStats,
+        now: Instant,
         result_url_base: &str,
+        history_url_base: &str,
+        term_cols: usize,
     ) -> anyhow::Result<Text<'a>> {
         if self.lines.is_empty() {
             return Ok("[range empty]".into());
@@ -305,7 +431,14 @@ impl OutputBuffer {
                 let mut spans = vec![Span::from(log_line)];
                 if let Some(hash) = self.status_commits.get(&i) {
                     if let Some(tracked_cases) = statuses.get(hash) {
-                        spans.extend(self.render_cases(tracked_cases, result_url_base)?);
+                        spans.extend(Self::render_cases(
+                            tracked_cases,
+                            durations,
+                            now,
+                            result_url_base,
+                            history_url_base,
+                            term_cols,
+                        )?);
                     }
                 }
                 Ok(Line::from_iter(spans))
@@ -314,18 +447,32 @@ impl OutputBuffer {
     }
 
     fn render_cases<'a>(
-        &self,
         tracked_cases: &'a HashMap<TestName, TrackedTestCase>,
+        durations: &DurationStats,
+        now: Instant,
         result_url_base: &str,
+        history_url_base: &str,
+        term_cols: usize,
     ) -> anyhow::Result<Vec<Span<'a>>> {
+        // The whole line gets truncated to the terminal width when it's
+        // painted, but one long compiler error shouldn't push all the other
+        // tests off the end.
+        let max_excerpt_chars = max(term_cols / 2, 20);
         let mut tracked_cases: Vec<(&TestName, &TrackedTestCase)> = tracked_cases.iter().collect();
-        // Sort by test case name. Would like sort_by_key here but
-        // there's lifetime pain.
-        tracked_cases.sort_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        // Sort by test case name.
+        tracked_cases.sort_by_key(|(name, _)| *name);
         let mut spans = Vec::new();
         for (name, tracked_case) in tracked_cases {
-            let status_part = match &tracked_case.status {
+            let relpath = Database::result_relpath(&tracked_case.test_case);
+            let mut status_part = match &tracked_case.status {
                 TestStatus::Error(msg) => Span::new(msg).with_class(Class::Error),
+                TestStatus::TimedOut => Span::new("timed out").with_class(Class::Error),
+                TestStatus::Skipped(_) => Span::new("skipped"),
+                TestStatus::Flaky { .. } => Span::new("flaky").with_class(Class::Warning),
+                TestStatus::Warning(result) => {
+                    Span::new(format!("warning (status {})", result.exit_code))
+                        .with_class(Class::Warning)
+                }
                 TestStatus::Completed(result) => {
                     if result.exit_code == 0 {
                         Span::new("success").with_class(Class::Success)
@@ -334,19 +481,81 @@ impl OutputBuffer {
                             .with_class(Class::Failure)
                     }
                 }
+                TestStatus::Started => match durations.remaining(tracked_case, now) {
+                    Some(remaining) => Span::new(format!(
+                        "{} (~{} left)",
+                        tracked_case.status,
+                        precise_duration(remaining)
+                    )),
+                    None => Span::new(tracked_case.status.to_string()),
+                },
                 _ => Span::new(tracked_case.status.to_string()),
             }
             .with_url(format!(
                 "{}/{}/stdout.txt",
                 result_url_base,
-                Database::result_relpath(&tracked_case.test_case).to_string_lossy()
+                url_path(&Database::attempt_relpath(
+                    &tracked_case.test_case,
+                    tracked_case.attempt
+                ))
             ));
+            // If the job told us what happened, that's more interesting than
+            // the exit code.
+            if let Some(summary) = tracked_case
+                .details
+                .report
+                .as_ref()
+                .and_then(|r| r.summary.as_ref())
+            {
+                status_part.content = summary.into();
+            }
+            // The comparison is stored with the result, but the thresholds
+            // come from the current config.
+            let details = &tracked_case.details;
+            let deltas = match (&details.report, &details.parent_metrics) {
+                (Some(report), Some(parent_metrics)) => compare_metrics(
+                    &tracked_case.test_case.test.metrics,
+                    &report.metrics,
+                    parent_metrics,
+                ),
+                _ => vec![],
+            };
+            // The job itself passed, but it's still a problem. Dependents
+            // don't care about that though, so the status stays a pass.
+            if tracked_case.status.satisfies_dependents() && deltas.iter().any(|d| d.regressed) {
+                status_part.content = "regressed".into();
+                status_part.class = Some(Class::Failure);
+            }
             spans.extend([
-                Span::new(name.to_string()).with_class(Class::TestName),
+                Span::new(name.to_string())
+                    .with_class(Class::TestName)
+                    .with_url(format!("{}/{}", history_url_base, url_path(&relpath))),
                 Span::new(": "),
                 status_part,
-                Span::new(" "),
-            ])
+            ]);
+            if let Some(excerpt) = &tracked_case.details.failure_excerpt {
+                let mut excerpt = excerpt.clone();
+                if let Some((i, _)) = excerpt.char_indices().nth(max_excerpt_chars) {
+                    excerpt.truncate(i);
+                    excerpt.push('…');
+                }
+                spans.push(Span::new(format!(": {excerpt}")));
+            }
+            if let Some(usage) = &tracked_case.details.usage {
+                spans.push(Span::new(format!(
+                    " ({})",
+                    precise_duration(usage.wall_time)
+                )));
+            }
+            for delta in deltas {
+                let span = Span::new(format!(" {delta}"));
+                spans.push(if delta.regressed {
+                    span.with_class(Class::Failure)
+                } else {
+                    span
+                });
+            }
+            spans.push(Span::new(" "));
         }
         Ok(spans)
     }
@@ -355,16 +564,24 @@ impl OutputBuffer {
 #[cfg(test)]
 mod tests {
     use core::str;
-    use std::{sync::Arc, time::Duration};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    };
 
     use googletest::{expect_that, prelude::eq};
 
     use crate::{
+        cgroup::Limits,
         git::{
             test_utils::{TempRepo, WorktreeExt},
             Commit,
         },
-        test::{CachePolicy, Test, TestName, TestResult},
+        report::{MetricSpec, Report},
+        test::{
+            CachePolicy, CacheTtl, ConfigHash, ExitCodeOutcomes, RetryPolicy, Test, TestName,
+            TestResult,
+        },
     };
 
     use super::*;
@@ -373,12 +590,24 @@ mod tests {
         Arc::new(Test {
             name: TestName::new(name),
             cache_policy,
+            cache_ttl: CacheTtl::default(),
+            retry: RetryPolicy::default(),
+            report_file: None,
+            metrics: vec![],
+            failure_patterns: vec![],
+            limits: Limits::default(),
+            sandbox: None,
+            tty: false,
             // Don't care abou any of the other fields in these tests
-            config_hash: 0,
+            config_json: serde_json::Value::Null,
+            config_hash: ConfigHash::default(),
             program: "".into(),
             args: vec![],
             needs_resources: [].into(),
             shutdown_grace_period: Duration::from_secs(1),
+            timeout: None,
+            exit_codes: ExitCodeOutcomes::default(),
+            artifacts: vec![],
             depends_on: vec![],
         })
     }
@@ -391,6 +620,8 @@ mod tests {
                 test: test.clone(),
             },
             status,
+            details: JobDetails::default(),
+            attempt: None,
         }
     }
 
@@ -428,7 +659,19 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(
+                &tracked_cases,
+                &DurationStats::default(),
+                Instant::now(),
+                "myhost",
+                "myhost",
+                160,
+            )
+            .unwrap()
+            .ansi()
+        );
         expect_that!(
             // The colored crate does not have any useful way to disable it from
             // this test code, only globally. This clashes with parallel testing.
@@ -488,7 +731,19 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(
+                &tracked_cases,
+                &DurationStats::default(),
+                Instant::now(),
+                "myhost",
+                "myhost",
+                160,
+            )
+            .unwrap()
+            .ansi()
+        );
 
         // Note this is a kinda weird log. We excluded the common ancestor of all the commits.
         // Also note it's a kinda weird input because we haven't provided any
@@ -516,6 +771,118 @@ mod tests {
         );
     }
 
+    #[googletest::test]
+    #[test_log::test(tokio::test)]
+    async fn output_buffer_metrics() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let commit3 = repo.commit("3").await.unwrap();
+        let mut test = Arc::into_inner(fake_test("bench", CachePolicy::ByCommit)).unwrap();
+        test.metrics = vec![MetricSpec {
+            name: "boot_time".into(),
+            regex: None,
+            max_increase: Some(0.05),
+            max_decrease: None,
+        }];
+        let test = Arc::new(test);
+
+        let ob = OutputBuffer::new(&repo, format!("{}..HEAD", commit1.hash), "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        let mut parent_metrics = None;
+        for (commit, boot_time, size) in [
+            (&commit1, 100.0, 1000.0),
+            (&commit2, 101.0, 2000.0),
+            (&commit3, 120.0, 2000.0),
+        ] {
+            let mut notif = fake_notif(
+                &commit.hash,
+                &test,
+                TestStatus::Completed(TestResult { exit_code: 0 }),
+            );
+            let metrics: BTreeMap<_, _> =
+                [("boot_time".into(), boot_time), ("size".into(), size)].into();
+            notif.details.report = Some(Report {
+                metrics: metrics.clone(),
+                ..Default::default()
+            });
+            notif.details.parent_metrics = parent_metrics.replace(metrics);
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+        }
+
+        let buf = format!(
+            "{}",
+            ob.render(
+                &tracked_cases,
+                &DurationStats::default(),
+                Instant::now(),
+                "myhost",
+                "myhost",
+                160,
+            )
+            .unwrap()
+            .ansi()
+        );
+        // commit1 is outside the range so it's not shown, but commit2 was
+        // still compared with it.
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit3} 3\n\
+                | bench: regressed boot_time 120 (+18.8%) size 2000 (+0.0%) \n\
+                * {commit2} 2\n\
+                | bench: success boot_time 101 (+1.0%) size 2000 (+100.0%) \n",
+                commit3 = abbrev(&commit3),
+                commit2 = abbrev(&commit2)
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[test_log::test(tokio::test)]
+    async fn output_buffer_failure_excerpt() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let base_commit = repo.commit("base").await.unwrap();
+        let commit = repo.commit("1").await.unwrap();
+        let test = fake_test("build", CachePolicy::ByCommit);
+
+        let ob = OutputBuffer::new(&repo, format!("{}..HEAD", base_commit.hash), "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut notif = fake_notif(
+            &commit.hash,
+            &test,
+            TestStatus::Completed(TestResult { exit_code: 1 }),
+        );
+        notif.details.failure_excerpt = Some(format!("error: {}", "x".repeat(100)));
+        let mut tracked_cases = HashMap::new();
+        update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+
+        let buf = format!(
+            "{}",
+            ob.render(
+                &tracked_cases,
+                &DurationStats::default(),
+                Instant::now(),
+                "myhost",
+                "myhost",
+                160,
+            )
+            .unwrap()
+            .ansi()
+        );
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit} 1\n| build: failed (status 1): error: {}… \n",
+                "x".repeat(73),
+                commit = abbrev(&commit),
+            ))
+        );
+    }
+
     #[googletest::test]
     #[test_log::test(tokio::test)]
     async fn output_buffer_empty() {
@@ -550,10 +917,94 @@ mod tests {
             update_tracked_cases(&mut tracked_cases, Arc::new(notif));
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").unwrap().ansi());
+        let buf = format!(
+            "{}",
+            ob.render(
+                &tracked_cases,
+                &DurationStats::default(),
+                Instant::now(),
+                "myhost",
+                "myhost",
+                160,
+            )
+            .unwrap()
+            .ansi()
+        );
         expect_that!(
             *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
             eq("[range empty]\n".to_owned())
         );
     }
+
+    fn fake_usage(started_at: SystemTime, wall_time: Duration) -> JobUsage {
+        JobUsage {
+            started_at,
+            finished_at: started_at + wall_time,
+            wall_time,
+            user_time: Duration::ZERO,
+            system_time: Duration::ZERO,
+            max_rss: 0,
+        }
+    }
+
+    #[test]
+    fn should_estimate_remaining_time() {
+        let test1 = fake_test("my_test1", CachePolicy::ByCommit);
+        let test2 = fake_test("my_test2", CachePolicy::ByCommit);
+        let t0 = SystemTime::now();
+        let mut stats = DurationStats::default();
+        for (i, secs) in [10, 20, 1000].into_iter().enumerate() {
+            stats.record(
+                &test1.name,
+                &fake_usage(
+                    t0 + Duration::from_secs(i as u64),
+                    Duration::from_secs(secs),
+                ),
+            );
+        }
+        // Seeing the same run again doesn't skew the stats.
+        stats.record(
+            &test1.name,
+            &fake_usage(t0 + Duration::from_secs(2), Duration::from_secs(1000)),
+        );
+        assert_eq!(stats.estimate(&test1.name), Some(Duration::from_secs(20)));
+        assert_eq!(stats.estimate(&test2.name), None);
+
+        let commit1 = CommitHash::new("1".repeat(40));
+        let commit2 = CommitHash::new("2".repeat(40));
+        let now = Instant::now();
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit1, &test1, TestStatus::Started),
+            fake_notif(&commit2, &test1, TestStatus::Enqueued),
+            fake_notif(
+                &commit2,
+                &test2,
+                TestStatus::Completed(TestResult { exit_code: 0 }),
+            ),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+        }
+        let started = &mut tracked_cases
+            .get_mut(&commit1)
+            .unwrap()
+            .get_mut(&test1.name)
+            .unwrap();
+        started.started = Some(now - Duration::from_secs(5));
+        // 15s left on the running one, 20s for the queued one.
+        assert_eq!(
+            summarize(&tracked_cases, &stats, now),
+            "1 queued / 1 running / ETA 35s"
+        );
+
+        // No idea how long test2 takes.
+        update_tracked_cases(
+            &mut tracked_cases,
+            Arc::new(fake_notif(&commit1, &test2, TestStatus::Enqueued)),
+        );
+        assert_eq!(
+            summarize(&tracked_cases, &stats, now),
+            "2 queued / 1 running / ETA unknown"
+        );
+    }
 }
@@ -5,11 +5,12 @@ use std::{
     ops::Deref,
     path::PathBuf,
     str::FromStr,
+    time::Duration,
 };
 
 #[allow(unused_imports)]
 use log::{debug, error};
-use tokio::task::JoinSet;
+use tokio::{select, task::JoinSet, time::sleep};
 use tokio_util::sync::CancellationToken;
 
 #[derive(Clone, Debug)]
@@ -59,6 +60,27 @@ where
     }
 }
 
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// There's no way to asynchronously wait for a flock, so this just keeps calling
+// try_lock until it gets something. None if ct gets cancelled first.
+pub async fn poll_lock<T>(
+    ct: &CancellationToken,
+    mut try_lock: impl FnMut() -> anyhow::Result<Option<T>>,
+) -> anyhow::Result<Option<T>> {
+    loop {
+        if let Some(lock) = try_lock()? {
+            return Ok(Some(lock));
+        }
+        select! {
+            _ = ct.cancelled() => return Ok(None),
+            _ = sleep(Duration::from_millis(500)) => (),
+        }
+    }
+}
+
 // It's an ErrGroup like from Go lol.
 // https://stackoverflow.com/questions/79172707/concise-tokio-equivalent-of-gos-errgroup
 pub struct ErrGroup {
@@ -105,8 +127,67 @@ impl ErrGroup {
     }
 }
 
+// Rough description of a duration, like "3h".
+pub fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=119 => format!("{secs}s"),
+        120..=7199 => format!("{}m", secs / 60),
+        7200..=172799 => format!("{}h", secs / (60 * 60)),
+        _ => format!("{}d", secs / (24 * 60 * 60)),
+    }
+}
+
+// Duration in whole seconds (rounding down), like "3m12s".
+pub fn precise_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
 #[derive(Clone)]
 pub struct Rect {
     pub cols: usize,
     pub rows: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(Duration::from_millis(900), "0s")]
+    #[test_case(Duration::from_secs(59), "59s")]
+    #[test_case(Duration::from_secs(192), "3m12s")]
+    #[test_case(Duration::from_secs(3600 + 5), "1h00m05s")]
+    fn should_format_precise_duration(duration: Duration, want: &str) {
+        assert_eq!(precise_duration(duration), want);
+    }
+
+    #[test_case(Duration::from_secs(119), "119s")]
+    #[test_case(Duration::from_secs(120), "2m")]
+    #[test_case(Duration::from_secs(3 * 3600 + 59 * 60), "3h")]
+    #[test_case(Duration::from_secs(5 * 24 * 3600), "5d")]
+    fn should_format_human_duration(duration: Duration, want: &str) {
+        assert_eq!(human_duration(duration), want);
+    }
+}
@@ -610,3 +610,246 @@ async fn should_find_output(want_stdout: &str, want_stderr: &str) {
         ok(eq(want_stderr))
     );
 }
+
+#[googletest::test]
+#[tokio::test]
+async fn should_find_artifacts() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+
+    let config = r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = """
+            mkdir -p out/logs
+            echo kernel > out/bzImage
+            echo boot > out/logs/boot.log
+            echo run > out/logs/run.log
+            echo nope > out/logs/ignored.txt
+            """
+            artifacts = ["out/bzImage", "out/logs/*.log"]
+            shutdown_grace_period_s = 1
+        "##;
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(
+            &config,
+            [
+                "get",
+                "--run",
+                "my_test",
+                "HEAD^",
+                "artifact",
+                "out/bzImage",
+            ],
+        )
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_success())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        fs::read_to_string(child.stdout().unwrap().trim()),
+        ok(eq("kernel\n"))
+    );
+
+    // Without a name we should get the directory with all the artifacts.
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dir.path().to_owned())
+        .start(config, ["get", "my_test", "HEAD^", "artifact"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_success())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let artifacts_dir = PathBuf::from(child.stdout().unwrap().trim());
+    expect_that!(
+        fs::read_to_string(artifacts_dir.join("out/logs/boot.log")),
+        ok(eq("boot\n"))
+    );
+    expect_that!(
+        fs::read_to_string(artifacts_dir.join("out/logs/run.log")),
+        ok(eq("run\n"))
+    );
+    expect_that!(
+        artifacts_dir.join("out/logs/ignored.txt").exists(),
+        eq(false)
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_list_repos() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = r##"
+            [[tests]]
+            name = "my_test"
+            command = "true"
+        "##;
+    let mut repo_dirs = Vec::new();
+    for _ in 0..2 {
+        let repo_dir = TempDir::with_prefix("repo").unwrap();
+        LimmatChildBuilder::init_test_repo(repo_dir.path())
+            .await
+            .unwrap();
+        let mut child = LimmatChildBuilder::new()
+            .await
+            .unwrap()
+            .db_dir(db_dir.path().to_owned())
+            .existing_repo_dir(repo_dir.path().to_owned())
+            .start(config, ["get", "--run", "my_test", "HEAD"])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_success())
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+        repo_dirs.push(repo_dir);
+    }
+
+    let mut child = LimmatChildBuilder::new()
+        .await
+        .unwrap()
+        .db_dir(db_dir.path().to_owned())
+        .existing_repo_dir(repo_dirs[0].path().to_owned())
+        .start(config, ["db", "list-repos"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_success())
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    expect_that!(lines, len(eq(2)));
+    // The current repo should be marked.
+    let git_dir = fs::canonicalize(repo_dirs[0].path().join(".git")).unwrap();
+    let current: Vec<&str> = lines
+        .iter()
+        .filter(|l| l.starts_with('*'))
+        .copied()
+        .collect();
+    expect_that!(
+        current,
+        elements_are![contains_substring(git_dir.to_str().unwrap())]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_explain_cache() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = |command: &str| {
+        format!(
+            r##"
+                [[tests]]
+                name = "my_test"
+                command = "{command}"
+            "##
+        )
+    };
+
+    let run = |config: String, args: Vec<&'static str>| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args)
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    let stdout = run(config("true"), vec!["explain-cache", "my_test", "HEAD"]).await;
+    expect_that!(stdout, contains_substring("No result stored"));
+
+    run(config("true"), vec!["get", "--run", "my_test", "HEAD"]).await;
+    let stdout = run(config("true"), vec!["explain-cache", "my_test", "HEAD"]).await;
+    expect_that!(stdout, contains_substring("will be reused"));
+
+    let stdout = run(config("false"), vec!["explain-cache", "my_test", "HEAD"]).await;
+    expect_that!(
+        stdout,
+        contains_substring(r#"test.command: "true" -> "false""#)
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_keep_history() {
+    let repo_dir = TempDir::with_prefix("repo").unwrap();
+    LimmatChildBuilder::init_test_repo(repo_dir.path())
+        .await
+        .unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let config = |command: &str| {
+        format!(
+            r##"
+                [[tests]]
+                name = "my_test"
+                command = "{command}"
+            "##
+        )
+    };
+
+    let run = |config: String, args: Vec<&'static str>| {
+        let db_dir = db_dir.path().to_owned();
+        let repo_dir = repo_dir.path().to_owned();
+        async move {
+            let mut child = LimmatChildBuilder::new()
+                .await
+                .unwrap()
+                .db_dir(db_dir)
+                .existing_repo_dir(repo_dir)
+                .start(config, args)
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), child.expect_success())
+                .await
+                .expect("child didn't shut down")
+                .unwrap();
+            child.stdout().unwrap()
+        }
+    };
+
+    let stdout = run(config("true"), vec!["history", "my_test", "HEAD"]).await;
+    expect_that!(stdout, contains_substring("No runs stored"));
+
+    // Changing the config makes it run again, the first run should be kept.
+    run(config("true"), vec!["get", "--run", "my_test", "HEAD"]).await;
+    run(config("echo hi"), vec!["get", "--run", "my_test", "HEAD"]).await;
+    let stdout = run(config("true"), vec!["history", "my_test", "HEAD"]).await;
+    let lines: Vec<&str> = stdout.lines().collect();
+    expect_that!(
+        lines,
+        elements_are![
+            starts_with("  #1: Completed"),
+            starts_with("* #2: Completed"),
+        ]
+    );
+}